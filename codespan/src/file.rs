@@ -1,6 +1,5 @@
-use crate::position::Position;
+use crate::position::{Position, PositionEncoding};
 use crate::{Range, Span};
-use core::panic;
 use std::fmt::{Display, Formatter};
 
 #[allow(dead_code)]
@@ -8,6 +7,7 @@ use std::fmt::{Display, Formatter};
 pub struct File {
     pub name: String,
     pub source: String,
+    pub encoding: PositionEncoding,
     line_starts: Vec<usize>,
 }
 
@@ -37,6 +37,7 @@ impl File {
         Self {
             name: name.into(),
             source,
+            encoding: PositionEncoding::default(),
             line_starts,
         }
     }
@@ -50,18 +51,19 @@ impl File {
     fn line_start(&self, line_index: usize) -> Result<usize> {
         use std::cmp::Ordering;
 
-        match line_index.cmp(&self.last_line_index()) {
+        // One past the last line is the EOF sentinel, so `line_span` works on the last line
+        match line_index.cmp(&(self.last_line_index() + 1)) {
             Ordering::Less => Ok(self.line_starts[line_index]),
             Ordering::Equal => Ok(self.source.len()),
-            Ordering::Greater => {
-                // tracing::error!("Line index out of bounds");
-                panic!("Line index out of bounds")
-            }
+            Ordering::Greater => Err(FileError::OutOfBounds {
+                given: line_index,
+                span: self.source_span(),
+            }),
         }
     }
 
     fn last_line_index(&self) -> usize {
-        self.line_starts.len()
+        self.line_starts.len() - 1
     }
 
     fn line_span(&self, line_index: usize) -> Result<Span> {
@@ -96,7 +98,12 @@ impl File {
                         }
                     })?;
 
-                Ok(Position::new(line_index, line_src.len()))
+                let character = match self.encoding {
+                    PositionEncoding::Utf8 => line_src.len(),
+                    PositionEncoding::Utf16 => line_src.encode_utf16().count(),
+                };
+
+                Ok(Position::new(line_index, character))
             }
         }
     }
@@ -106,8 +113,14 @@ impl File {
         let end = span.end;
 
         self.source.get(start..end).ok_or_else(|| {
-            // tracing::error!("Failed to create source span");
-            panic!()
+            if end > self.source.len() {
+                FileError::OutOfBounds {
+                    given: end,
+                    span: self.source_span(),
+                }
+            } else {
+                FileError::InvalidCharBoundary { given: start }
+            }
         })
     }
 
@@ -116,8 +129,12 @@ impl File {
     }
 
     pub fn position_to_byte_index(&self, position: Position) -> Result<usize> {
-        let line_span = &self.line_span(position.line)?;
-        let byte_offset = position.character;
+        let line_span = self.line_span(position.line)?;
+        let line = self.source_slice(line_span)?;
+        let byte_offset = match self.encoding {
+            PositionEncoding::Utf8 => position.character,
+            PositionEncoding::Utf16 => utf16_to_byte_offset(line, position.character),
+        };
 
         Ok(line_span.start() + byte_offset)
     }
@@ -149,50 +166,222 @@ impl File {
     }
 
     pub fn get_word_at_position(&self, position: Position) -> Result<&str> {
-        let line = self.source_slice(self.line_span(position.line)?)?;
-        let range = find_word_at_pos(line, position.character);
+        let span = self.line_span(position.line)?;
+        let line = self.source_slice(span)?;
+        let range = find_word_at_pos(line, self.position_to_byte_index(position)? - span.start);
 
-        Ok(line.get(range.0..range.1).unwrap())
+        Ok(line.get(range.0..range.1).unwrap_or_default())
     }
 
     pub fn get_word_span_at_position(&self, position: Position) -> Result<(&str, Span)> {
         let span = self.line_span(position.line)?;
         let line = self.source_slice(span)?;
-        let range = find_word_at_pos(line, position.character);
+        let range = find_word_at_pos(line, self.position_to_byte_index(position)? - span.start);
 
-        let word = line.get(range.0..range.1).unwrap();
+        let word = line.get(range.0..range.1).unwrap_or_default();
         let span = Span::new(span.start + range.0, span.start + range.1);
 
         Ok((word, span))
     }
 }
 
+// LSP columns count UTF-16 code units rather than bytes
+fn utf16_to_byte_offset(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= character {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+
+    line.len() + character.saturating_sub(units)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '@' || c == '.'
+}
+
+/// Finds the byte range of the word around byte offset `col`, keeping `::` scope separators so
+/// a qualified name like `Player::state::current` is returned whole.
 pub fn find_word_at_pos(line: &str, col: usize) -> (usize, usize) {
-    let line_ = format!("{} ", line);
-    let is_ident_char =
-        |c: char| c.is_alphanumeric() || c == '_' || c == '@' || c == ':' || c == '.';
-
-    let start = line_
-        .chars()
-        .enumerate()
-        .take(col)
-        .filter(|&(_, c)| !is_ident_char(c))
-        .last()
-        .map(|(i, _)| i + 1)
-        .unwrap_or(0);
-
-    let end = line_
-        .chars()
-        .enumerate()
-        .skip(col)
-        .find(|&(_, c)| !is_ident_char(c))
-        .map(|(i, _)| i)
-        .unwrap_or(col);
-
-    // Quick hack to handle addressing modes
-    if line[start..end].starts_with("f:") {
-        (start + 2, end)
-    } else {
-        (start, end)
+    find_word_bounds(line, col, true)
+}
+
+/// Finds the byte range of the single name segment around byte offset `col`, stopping at `::`.
+pub fn find_segment_at_pos(line: &str, col: usize) -> (usize, usize) {
+    find_word_bounds(line, col, false)
+}
+
+fn find_word_bounds(line: &str, col: usize, keep_separators: bool) -> (usize, usize) {
+    let chars = line.char_indices().collect::<Vec<_>>();
+    let is_word_char = |index: usize| {
+        let c = chars[index].1;
+        let is_colon = |index: Option<usize>| {
+            index
+                .and_then(|index| chars.get(index))
+                .is_some_and(|&(_, c)| c == ':')
+        };
+
+        is_ident_char(c)
+            || (keep_separators
+                && c == ':'
+                && (is_colon(Some(index + 1)) || is_colon(index.checked_sub(1))))
+    };
+    let offset = |index: usize| chars.get(index).map_or(line.len(), |&(offset, _)| offset);
+
+    let cursor = chars
+        .iter()
+        .position(|&(offset, _)| offset >= col)
+        .unwrap_or(chars.len());
+
+    let mut start = cursor;
+    while start > 0 && is_word_char(start - 1) {
+        start -= 1;
+    }
+
+    let mut end = cursor;
+    while end < chars.len() && is_word_char(end) {
+        end += 1;
+    }
+
+    (offset(start), offset(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Byte offsets: `é` is 3..5 and `😀` is 9..13, which is two UTF-16 units at columns 8 and 9
+    const SOURCE: &str = "; héllo 😀\nnop\n";
+
+    fn file(encoding: PositionEncoding) -> File {
+        let mut file = File::new("test.s", SOURCE.to_string());
+        file.encoding = encoding;
+        file
+    }
+
+    #[test]
+    fn converts_utf8_columns() {
+        let file = file(PositionEncoding::Utf8);
+
+        for (offset, character) in [(2, 2), (5, 5), (9, 9), (13, 13)] {
+            assert_eq!(
+                file.byte_index_to_position(offset).unwrap(),
+                Position::new(0, character)
+            );
+            assert_eq!(
+                file.position_to_byte_index(Position::new(0, character))
+                    .unwrap(),
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn converts_utf16_columns() {
+        let file = file(PositionEncoding::Utf16);
+
+        for (offset, character) in [(2, 2), (5, 4), (9, 8), (13, 10)] {
+            assert_eq!(
+                file.byte_index_to_position(offset).unwrap(),
+                Position::new(0, character)
+            );
+            assert_eq!(
+                file.position_to_byte_index(Position::new(0, character))
+                    .unwrap(),
+                offset
+            );
+        }
+        assert_eq!(
+            file.byte_index_to_position(SOURCE.len()).unwrap(),
+            Position::new(2, 0)
+        );
+    }
+
+    #[test]
+    fn columns_inside_a_character_stay_on_a_boundary() {
+        let file = file(PositionEncoding::Utf16);
+
+        // Between the two halves of the surrogate pair
+        assert_eq!(
+            file.position_to_byte_index(Position::new(0, 9)).unwrap(),
+            13
+        );
+        assert!(matches!(
+            file.byte_index_to_position(4),
+            Err(FileError::InvalidCharBoundary { given: 4 })
+        ));
+    }
+
+    #[test]
+    fn round_trips_the_last_line_with_and_without_a_trailing_newline() {
+        for source in ["nop\nlda #1", "nop\nlda #1\n"] {
+            let file = File::new("test.s", source.to_string());
+
+            for offset in 0..=source.len() {
+                let position = file.byte_index_to_position(offset).unwrap();
+                assert_eq!(
+                    file.position_to_byte_index(position).unwrap(),
+                    offset,
+                    "{source:?} at {offset}"
+                );
+            }
+            assert_eq!(file.get_line(1).unwrap(), Span::new(4, source.len()));
+            assert!(file.get_line(3).is_err());
+        }
+    }
+
+    #[test]
+    fn finds_qualified_names_from_any_point_inside_them() {
+        let line = "lda Player::state::current,x";
+
+        for col in [4, 10, 11, 14, 19, 26] {
+            assert_eq!(find_word_at_pos(line, col), (4, 26), "at {col}");
+        }
+        assert_eq!(find_segment_at_pos(line, 14), (12, 17));
+        assert_eq!(find_segment_at_pos(line, 4), (4, 10));
+    }
+
+    #[test]
+    fn cursor_past_the_end_of_a_line_finds_no_word() {
+        for encoding in [PositionEncoding::Utf8, PositionEncoding::Utf16] {
+            let mut file = File::new("test.s", "lda café\nnop\n".to_string());
+            file.encoding = encoding;
+            let last_column = match encoding {
+                PositionEncoding::Utf8 => 9,
+                PositionEncoding::Utf16 => 8,
+            };
+
+            assert_eq!(
+                file.get_word_at_position(Position::new(0, last_column))
+                    .unwrap(),
+                "café"
+            );
+            assert_eq!(
+                file.get_word_at_position(Position::new(0, last_column + 1))
+                    .unwrap(),
+                ""
+            );
+        }
+    }
+
+    #[test]
+    fn the_last_line_has_a_span_in_every_file() {
+        for (source, last_line, span) in [
+            ("", 0, Span::new(0, 0)),
+            ("nop", 0, Span::new(0, 3)),
+            ("nop\n", 1, Span::new(4, 4)),
+        ] {
+            let file = File::new("test.s", source.to_string());
+
+            assert_eq!(file.get_line(last_line).unwrap(), span, "{source:?}");
+            assert!(file.get_line(last_line + 1).is_err(), "{source:?}");
+            assert_eq!(
+                file.byte_index_to_position(source.len()).unwrap(),
+                Position::new(last_line, span.end - span.start),
+                "{source:?}"
+            );
+        }
     }
 }
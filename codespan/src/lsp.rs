@@ -33,3 +33,12 @@ impl From<lsp_types::Position> for crate::Position {
         }
     }
 }
+
+impl From<crate::PositionEncoding> for lsp_types::PositionEncodingKind {
+    fn from(encoding: crate::PositionEncoding) -> lsp_types::PositionEncodingKind {
+        match encoding {
+            crate::PositionEncoding::Utf8 => lsp_types::PositionEncodingKind::UTF8,
+            crate::PositionEncoding::Utf16 => lsp_types::PositionEncodingKind::UTF16,
+        }
+    }
+}
@@ -17,3 +17,11 @@ impl Display for Position {
         write!(f, "{}:{}", self.line, self.character)
     }
 }
+
+/// The unit LSP columns are counted in, as negotiated with the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+}
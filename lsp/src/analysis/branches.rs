@@ -0,0 +1,526 @@
+use crate::analysis::evaluator::ConstantEvaluator;
+use crate::analysis::visitor::ASTVisitor;
+use codespan::Span;
+use parser::{
+    AddressSize, Ast, DataKind, Expression, ExpressionKind, IfStatement, Instruction,
+    MacroInvocation, Segment, Statement, Token,
+};
+use std::collections::HashMap;
+
+const BRANCHES: [&str; 9] = [
+    "bcc", "bcs", "beq", "bmi", "bne", "bpl", "bvc", "bvs", "bra",
+];
+
+/// A relative branch whose target is further than a signed byte offset can reach.
+pub struct OutOfRangeBranch {
+    pub span: Span,
+    /// Bytes from the end of the branch to its target
+    pub distance: i64,
+}
+
+/// Where a statement is assembled: a program counter that only means something relative to other
+/// positions in the same segment, since the last `.org`, and assembled under the same conditions.
+#[derive(Clone)]
+struct Position {
+    segment: String,
+    org: usize,
+    /// The `.if`/`.repeat` bodies enclosing the statement
+    conditions: Vec<usize>,
+    /// How many macro invocations and includes came before the statement
+    unknowns: usize,
+    pc: i64,
+}
+
+impl Position {
+    // Whether the distance from `from` to this position can be estimated. A label inside a
+    // conditional body is only known to be there when the branch is in that body too.
+    fn reachable_from(&self, from: &Position) -> bool {
+        self.segment == from.segment
+            && self.org == from.org
+            && self.unknowns == from.unknowns
+            && from.conditions.starts_with(&self.conditions)
+    }
+}
+
+enum Target {
+    /// Candidate names for the label, innermost scope first
+    Named(Vec<String>),
+    /// How many unnamed labels came before the branch, and the reference's `:+`/`:-` distance
+    Unnamed(usize, i8),
+}
+
+struct Branch {
+    target: Target,
+    /// The position right after the branch, which the offset is relative to
+    from: Position,
+    span: Span,
+}
+
+#[derive(Clone)]
+struct Location {
+    segment: String,
+    segment_stack: Vec<String>,
+    /// The `.org` count and program counter of every segment seen so far
+    counters: HashMap<String, (usize, i64)>,
+}
+
+/// Lays out the file's code with the smallest size each statement could assemble to, and finds
+/// relative branches to labels that are already too far away under that estimate.
+///
+/// Statements whose size can't be known count as empty, so a reported branch is out of range
+/// however they turn out. Macro invocations and included files could even switch segments, so no
+/// branch across one is reported.
+pub struct BranchRangeChecker<'a> {
+    evaluator: &'a ConstantEvaluator,
+    location: Location,
+    scope: Vec<String>,
+    last_label: String,
+    conditions: Vec<usize>,
+    next_condition: usize,
+    next_org: usize,
+    unknowns: usize,
+    labels: HashMap<String, Position>,
+    /// Unnamed labels in order, with `None` wherever a macro or include might have added more
+    unnamed_labels: Vec<Option<Position>>,
+    branches: Vec<Branch>,
+}
+
+impl<'a> BranchRangeChecker<'a> {
+    pub fn find_out_of_range(ast: &Ast, evaluator: &'a ConstantEvaluator) -> Vec<OutOfRangeBranch> {
+        let mut slf = BranchRangeChecker {
+            evaluator,
+            location: Location {
+                segment: "CODE".to_string(),
+                segment_stack: vec![],
+                counters: HashMap::new(),
+            },
+            scope: vec![],
+            last_label: String::new(),
+            conditions: vec![],
+            next_condition: 0,
+            next_org: 1,
+            unknowns: 0,
+            labels: HashMap::new(),
+            unnamed_labels: vec![],
+            branches: vec![],
+        };
+        for statement in ast.iter() {
+            slf.visit_statement(statement);
+        }
+
+        slf.branches
+            .iter()
+            .filter_map(|branch| {
+                let target = slf.resolve(branch)?;
+                let distance = target.pc - branch.from.pc;
+                (!(-128..=127).contains(&distance)).then_some(OutOfRangeBranch {
+                    span: branch.span,
+                    distance,
+                })
+            })
+            .collect()
+    }
+
+    fn resolve(&self, branch: &Branch) -> Option<&Position> {
+        match &branch.target {
+            Target::Named(candidates) => candidates
+                .iter()
+                .find_map(|name| self.labels.get(name))
+                .filter(|position| position.reachable_from(&branch.from)),
+            Target::Unnamed(before, distance) if *distance > 0 => {
+                let end = *before + *distance as usize;
+                certain_labels(self.unnamed_labels.get(*before..end)?, &branch.from)?.pop()
+            }
+            Target::Unnamed(before, distance) => {
+                let start = before.checked_sub(distance.unsigned_abs() as usize)?;
+                certain_labels(&self.unnamed_labels[start..*before], &branch.from)?
+                    .first()
+                    .copied()
+            }
+        }
+    }
+
+    fn position(&self) -> Position {
+        let (org, pc) = self.counter();
+        Position {
+            segment: self.location.segment.clone(),
+            org,
+            conditions: self.conditions.clone(),
+            unknowns: self.unknowns,
+            pc,
+        }
+    }
+
+    fn counter(&self) -> (usize, i64) {
+        self.location
+            .counters
+            .get(&self.location.segment)
+            .copied()
+            .unwrap_or((0, 0))
+    }
+
+    fn advance(&mut self, size: i64) {
+        let (org, pc) = self.counter();
+        self.location
+            .counters
+            .insert(self.location.segment.clone(), (org, pc + size));
+    }
+
+    fn scoped(&self, name: &str) -> String {
+        self.scope
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(name))
+            .collect::<Vec<_>>()
+            .join("::")
+    }
+
+    fn define_label(&mut self, name: &str) {
+        let name = if name.starts_with('@') {
+            format!("{}{name}", self.last_label)
+        } else {
+            self.last_label = name.to_string();
+            name.to_string()
+        };
+        let position = self.position();
+        self.labels.entry(self.scoped(&name)).or_insert(position);
+    }
+
+    // Looks `name` up the way ca65 does, from the current scope outwards
+    fn label_candidates(&self, name: &str) -> Vec<String> {
+        if let Some(name) = name.strip_prefix("::") {
+            return vec![name.to_string()];
+        }
+        if name.starts_with('@') {
+            return vec![self.scoped(&format!("{}{name}", self.last_label))];
+        }
+
+        (0..=self.scope.len())
+            .rev()
+            .map(|depth| {
+                self.scope[..depth]
+                    .iter()
+                    .map(String::as_str)
+                    .chain(std::iter::once(name))
+                    .collect::<Vec<_>>()
+                    .join("::")
+            })
+            .collect()
+    }
+
+    fn is_absolute_label(&self, name: &str) -> bool {
+        self.label_candidates(name)
+            .iter()
+            .find_map(|name| self.labels.get(name))
+            .is_some_and(|position| !is_zero_page_segment(&position.segment))
+    }
+
+    fn instruction_size(&self, instruction: &Instruction) -> i64 {
+        let mnemonic = instruction.mnemonic.to_lowercase();
+        let Some(operand) = instruction.parameters.first() else {
+            return 1;
+        };
+
+        match mnemonic.as_str() {
+            "jmp" | "jsr" | "mvn" | "mvp" | "brl" | "per" => return 3,
+            "jml" | "jsl" => return 4,
+            _ if BRANCHES.contains(&mnemonic.as_str()) => return 2,
+            _ => {}
+        }
+
+        match &operand.kind {
+            // Accumulator addressing, as in `asl a`
+            ExpressionKind::Literal(name) if name.eq_ignore_ascii_case("a") => 1,
+            ExpressionKind::Immediate(_)
+            | ExpressionKind::MemoryAccess(_)
+            | ExpressionKind::IndexedIndirect(..) => 2,
+            ExpressionKind::Identifier(name) if self.is_absolute_label(name) => 3,
+            _ => match self.evaluator.evaluate_in(operand, &self.scope) {
+                Some(value) if value > 0xff => 3,
+                _ => 2,
+            },
+        }
+    }
+
+    fn data_size(&self, kind: &DataKind, expressions: &[Expression]) -> i64 {
+        let width = match kind {
+            DataKind::Byte | DataKind::LoBytes | DataKind::HiBytes => 1,
+            DataKind::Word | DataKind::Dbyt | DataKind::Addr => 2,
+            DataKind::FarAddr => 3,
+            DataKind::Dword => 4,
+        };
+
+        expressions
+            .iter()
+            .map(|expression| match &expression.kind {
+                ExpressionKind::String(string) if matches!(kind, DataKind::Byte) => {
+                    string_size(string)
+                }
+                _ => width,
+            })
+            .sum()
+    }
+
+    // Walks each body from the same starting point, so the statement as a whole counts as empty
+    fn visit_conditional_bodies<'s>(&mut self, bodies: impl Iterator<Item = &'s [Statement]>) {
+        let location = self.location.clone();
+        let mut changed_segment = false;
+        for body in bodies {
+            self.conditions.push(self.next_condition);
+            self.next_condition += 1;
+            for statement in body {
+                self.visit_statement(statement);
+            }
+            self.conditions.pop();
+
+            changed_segment |= self.location.segment != location.segment;
+            self.location = location.clone();
+        }
+
+        // If a body switched segments, what follows may be assembled in either one
+        if changed_segment {
+            self.location.segment = format!("?{}", self.next_condition);
+        }
+    }
+}
+
+impl ASTVisitor for BranchRangeChecker<'_> {
+    fn visit_include(&mut self, _path: &Token, _span: Span) {
+        self.unknowns += 1;
+        self.unnamed_labels.push(None);
+    }
+    fn visit_label(&mut self, name: &Token, _span: Span) {
+        self.define_label(&name.lexeme);
+    }
+    fn visit_instruction(&mut self, instruction: &Instruction, span: Span) {
+        let mnemonic = instruction.mnemonic.to_lowercase();
+        if BRANCHES.contains(&mnemonic.as_str()) {
+            let target = match instruction.parameters.first().map(|operand| &operand.kind) {
+                Some(ExpressionKind::Identifier(name)) => {
+                    Some(Target::Named(self.label_candidates(name)))
+                }
+                Some(ExpressionKind::UnnamedLabelReference(distance)) => {
+                    Some(Target::Unnamed(self.unnamed_labels.len(), *distance))
+                }
+                _ => None,
+            };
+            if let Some(target) = target {
+                let mut from = self.position();
+                from.pc += 2;
+                self.branches.push(Branch { target, from, span });
+            }
+        }
+
+        self.advance(self.instruction_size(instruction));
+    }
+    fn visit_procedure(
+        &mut self,
+        name: &Token,
+        _address_size: &AddressSize,
+        statements: &[Statement],
+        _span: Span,
+    ) {
+        self.define_label(&name.lexeme);
+        self.scope.push(name.lexeme.clone());
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+        self.scope.pop();
+    }
+    fn visit_segment(&mut self, segment: &Segment, _span: Span) {
+        self.location.segment = match segment {
+            Segment::Literal(name) => name.to_uppercase(),
+            Segment::Identifier(name, attribute) => {
+                let name = name.lexeme.trim_matches('"').to_string();
+                // An explicit `: zeropage` makes any segment a zero page one
+                match attribute {
+                    Some(attribute) if is_zero_page_attribute(&attribute.lexeme) => {
+                        format!("{name}:zeropage")
+                    }
+                    _ => name,
+                }
+            }
+        };
+    }
+    fn visit_tag(&mut self, expression: &Expression, _span: Span) {
+        if let ExpressionKind::Identifier(name) = &expression.kind {
+            self.advance(self.evaluator.size_of(&self.scope, name).unwrap_or(0));
+        }
+    }
+    fn visit_reserve(&mut self, amount: &Expression, _val: &Option<Expression>, _span: Span) {
+        self.advance(
+            self.evaluator
+                .evaluate_in(amount, &self.scope)
+                .unwrap_or(0)
+                .max(0),
+        );
+    }
+    fn visit_macro_invocation(&mut self, _macro_invocation: &MacroInvocation, _span: Span) {
+        self.unknowns += 1;
+        self.unnamed_labels.push(None);
+    }
+    fn visit_scope(&mut self, name: &Option<Token>, statements: &[Statement], _span: Span) {
+        if let Some(name) = name {
+            self.scope.push(name.lexeme.clone());
+        }
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+        if name.is_some() {
+            self.scope.pop();
+        }
+    }
+    fn visit_macro_definition(
+        &mut self,
+        _name: &Token,
+        _parameters: &[Token],
+        _variadic: &bool,
+        _statements: &[Statement],
+        _span: Span,
+    ) {
+    }
+    fn visit_data(&mut self, kind: &DataKind, expressions: &[Expression], _span: Span) {
+        self.advance(self.data_size(kind, expressions));
+    }
+    fn visit_org(&mut self, _address: &Expression, _span: Span) {
+        self.location
+            .counters
+            .insert(self.location.segment.clone(), (self.next_org, 0));
+        self.next_org += 1;
+    }
+    fn visit_repeat(
+        &mut self,
+        _max: &Expression,
+        _incr: &Option<Token>,
+        statements: &[Statement],
+        _span: Span,
+    ) {
+        self.visit_conditional_bodies(std::iter::once(statements));
+    }
+    fn visit_ascii(&mut self, string: &Token, _span: Span) {
+        self.advance(string_size(&string.lexeme) + 1);
+    }
+    fn visit_if(&mut self, if_statement: &IfStatement, _span: Span) {
+        let bodies = std::iter::once(if_statement.if_body.as_slice())
+            .chain(
+                if_statement
+                    .else_ifs
+                    .iter()
+                    .flatten()
+                    .map(|(_, statements)| statements.as_slice()),
+            )
+            .chain(if_statement.else_body.as_deref());
+        self.visit_conditional_bodies(bodies);
+    }
+    fn visit_unnamed_label(&mut self, _span: Span) {
+        self.unnamed_labels.push(Some(self.position()));
+    }
+    fn visit_push_seg(&mut self, _span: Span) {
+        let segment = self.location.segment.clone();
+        self.location.segment_stack.push(segment);
+    }
+    fn visit_pop_seg(&mut self, _span: Span) {
+        if let Some(segment) = self.location.segment_stack.pop() {
+            self.location.segment = segment;
+        }
+    }
+
+    fn visit_expression(&mut self, _expression: &Expression) {}
+}
+
+// Every unnamed label counted on the way to a target has to be certain, or the count is off
+fn certain_labels<'a>(
+    labels: &'a [Option<Position>],
+    from: &Position,
+) -> Option<Vec<&'a Position>> {
+    labels
+        .iter()
+        .map(|label| label.as_ref().filter(|label| label.reachable_from(from)))
+        .collect()
+}
+
+fn is_zero_page_attribute(attribute: &str) -> bool {
+    ["zeropage", "zp", "direct"]
+        .iter()
+        .any(|name| attribute.eq_ignore_ascii_case(name))
+}
+
+fn is_zero_page_segment(segment: &str) -> bool {
+    segment == "ZEROPAGE" || segment == "EXTZP" || segment.ends_with(":zeropage")
+}
+
+// The characters before the first escape sequence are the only ones certain to be one byte each
+fn string_size(lexeme: &str) -> i64 {
+    let contents = lexeme
+        .strip_prefix('"')
+        .and_then(|lexeme| lexeme.strip_suffix('"'))
+        .unwrap_or(lexeme);
+    contents.split('\\').next().unwrap_or("").chars().count() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_file::INSTRUCTIONS;
+
+    fn distances(source: &str) -> Vec<i64> {
+        let tokens = parser::Tokenizer::new(source, &INSTRUCTIONS)
+            .parse()
+            .unwrap();
+        let (ast, errors) = parser::Parser::new(&tokens).parse();
+        assert!(errors.is_empty(), "{errors:?}");
+        let evaluator = ConstantEvaluator::new(&ast);
+
+        BranchRangeChecker::find_out_of_range(&ast, &evaluator)
+            .into_iter()
+            .map(|branch| branch.distance)
+            .collect()
+    }
+
+    #[test]
+    fn finds_branches_out_of_range() {
+        let cases: &[(&str, &str, &[i64])] = &[
+            ("forward at the limit", "beq t\n.res 127\nt:\n", &[]),
+            ("forward past the limit", "beq t\n.res 128\nt:\n", &[128]),
+            ("backward at the limit", "t:\n.res 126\nbeq t\n", &[]),
+            ("backward past the limit", "t:\n.res 127\nbeq t\n", &[-129]),
+            ("unnamed forward", "beq :+\n.res 200\n:\n", &[200]),
+            ("unnamed backward", "nop\n:\n.res 200\nbne :-\n", &[-202]),
+            (
+                "into an .if from outside",
+                "beq t\n.res 200\n.if 1\nt:\n.endif\n",
+                &[],
+            ),
+            (
+                "within an .if",
+                ".if 1\nbeq t\n.res 200\nt:\n.endif\n",
+                &[200],
+            ),
+            (
+                "into another segment",
+                "beq t\n.res 200\n.segment \"DATA\"\nt:\n",
+                &[],
+            ),
+            (
+                "over another segment",
+                "beq t\n.segment \"DATA\"\n.res 200\n.segment \"CODE\"\nt:\n",
+                &[],
+            ),
+            ("across an .org", "beq t\n.res 200\n.org $8000\nt:\n", &[]),
+            (
+                "across a macro invocation",
+                "beq t\n.res 200\nsome_macro\nt:\n",
+                &[],
+            ),
+            (
+                "unnamed across a macro invocation",
+                "beq :+\n.res 200\nsome_macro\n:\n",
+                &[],
+            ),
+        ];
+
+        for (name, source, expected) in cases {
+            assert_eq!(distances(source), *expected, "{name}");
+        }
+    }
+}
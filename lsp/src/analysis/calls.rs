@@ -0,0 +1,53 @@
+use crate::analysis::visitor::ASTVisitor;
+use codespan::Span;
+use parser::{Ast, Expression, ExpressionKind, Instruction, MacroInvocation};
+
+pub struct CallSite {
+    pub name: String,
+    pub span: Span,
+}
+
+/// Collects `jsr`/`jmp` instructions that target a label, along with every macro invocation.
+pub struct CallCollector {
+    calls: Vec<CallSite>,
+}
+
+impl CallCollector {
+    pub fn find_calls(ast: &Ast) -> Vec<CallSite> {
+        let mut slf = CallCollector { calls: Vec::new() };
+        for statement in ast.iter() {
+            slf.visit_statement(statement);
+        }
+        slf.calls
+    }
+}
+
+impl ASTVisitor for CallCollector {
+    fn visit_instruction(&mut self, instruction: &Instruction, _span: Span) {
+        if !["jsr", "jmp", "jsl", "jml"]
+            .iter()
+            .any(|mnemonic| instruction.mnemonic.eq_ignore_ascii_case(mnemonic))
+        {
+            return;
+        }
+
+        // Indirect jumps like `jmp (vector)` don't name their target
+        if let Some(Expression {
+            kind: ExpressionKind::Identifier(name),
+            span,
+        }) = instruction.parameters.first()
+        {
+            self.calls.push(CallSite {
+                name: name.clone(),
+                span: *span,
+            });
+        }
+    }
+
+    fn visit_macro_invocation(&mut self, macro_invocation: &MacroInvocation, _span: Span) {
+        self.calls.push(CallSite {
+            name: macro_invocation.name.lexeme.clone(),
+            span: macro_invocation.name.span,
+        });
+    }
+}
@@ -0,0 +1,502 @@
+use crate::analysis::visitor::ASTVisitor;
+use codespan::Span;
+use parser::{
+    AddressSize, Ast, ConstantAssign, EnumMember, Expression, ExpressionKind, Statement,
+    StatementKind, StructMember, Token, TokenType, parse_number,
+};
+use std::collections::{HashMap, HashSet};
+
+// Guards against cyclic definitions like `A = B` / `B = A`
+const MAX_DEPTH: usize = 32;
+
+pub struct StructField {
+    pub name: Token,
+    /// Unknown once an earlier field's size can't be evaluated
+    pub offset: Option<i64>,
+    pub size: Option<i64>,
+}
+
+pub struct StructLayout {
+    pub fields: Vec<StructField>,
+    pub size: Option<i64>,
+}
+
+pub struct EnumValue {
+    pub member: EnumMember,
+    pub value: Option<i64>,
+}
+
+/// Constants, structs and enum members are keyed by their fully qualified name, like `::Foo::SIZE`,
+/// and looked up from the innermost scope outwards the way `resolve_symbol` does.
+pub struct ConstantEvaluator {
+    /// Each value along with the scope it's evaluated in
+    constants: HashMap<String, (Vec<String>, Expression)>,
+    /// `.set` symbols assigned more than once, which have no single value
+    variables: HashSet<String>,
+    /// Each `.charmap` entry as `(offset, index, code)`, in the order they appear
+    charmap: Vec<(usize, i64, i64)>,
+    structs: HashMap<String, StructLayout>,
+    enum_values: HashMap<String, EnumValue>,
+    scope: Vec<String>,
+}
+
+impl ConstantEvaluator {
+    pub fn new(ast: &Ast) -> Self {
+        let mut evaluator = ConstantEvaluator {
+            constants: HashMap::new(),
+            variables: HashSet::new(),
+            charmap: vec![],
+            structs: HashMap::new(),
+            enum_values: HashMap::new(),
+            scope: vec![],
+        };
+        for statement in ast.iter() {
+            evaluator.visit_statement(statement);
+        }
+        evaluator
+    }
+
+    /// Evaluates the constant with the fully qualified name `fqn`.
+    pub fn evaluate_constant(&self, fqn: &str) -> Option<i64> {
+        self.evaluate_identifier(fqn, &[], 0)
+    }
+
+    /// Evaluates `expression` as written inside `scope`.
+    pub fn evaluate_in(&self, expression: &Expression, scope: &[String]) -> Option<i64> {
+        self.evaluate_at_depth(expression, scope, 0)
+    }
+
+    /// Maps a character code through the `.charmap` table in effect at byte `offset`.
+    pub fn translate_char(&self, code: i64, offset: usize) -> i64 {
+        self.charmap
+            .iter()
+            .rev()
+            .find(|(at, index, _)| *at < offset && *index == code)
+            .map_or(code, |(_, _, mapped)| *mapped)
+    }
+
+    /// Every struct declared in the file, nested ones included.
+    pub fn structs(&self) -> impl Iterator<Item = &StructLayout> {
+        self.structs.values()
+    }
+
+    /// Every enum member in the file.
+    pub fn enum_values(&self) -> impl Iterator<Item = &EnumValue> {
+        self.enum_values.values()
+    }
+
+    /// Evaluates `.sizeof` for a struct (`Point`) or one of its fields (`Point::x`), as written
+    /// inside `scope`.
+    pub fn size_of(&self, scope: &[String], name: &str) -> Option<i64> {
+        if let Some(layout) = resolve(&self.structs, scope, name) {
+            return layout.size;
+        }
+
+        self.find_field(scope, name)?.size
+    }
+
+    fn find_field(&self, scope: &[String], name: &str) -> Option<&StructField> {
+        let (parent, field) = name.rsplit_once("::")?;
+        if parent.is_empty() {
+            return None;
+        }
+        resolve(&self.structs, scope, parent)?
+            .fields
+            .iter()
+            .find(|member| member.name.lexeme == field)
+    }
+
+    fn evaluate_identifier(&self, name: &str, scope: &[String], depth: usize) -> Option<i64> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        // A struct field evaluates to its offset within the struct
+        if let Some(field) = self.find_field(scope, name) {
+            return field.offset;
+        }
+        if let Some(enum_value) = resolve(&self.enum_values, scope, name) {
+            return enum_value.value;
+        }
+        let (scope, expression) = resolve(&self.constants, scope, name)?;
+        self.evaluate_at_depth(expression, scope, depth + 1)
+    }
+
+    fn evaluate_at_depth(
+        &self,
+        expression: &Expression,
+        scope: &[String],
+        depth: usize,
+    ) -> Option<i64> {
+        let eval = |expression: &Expression| self.evaluate_at_depth(expression, scope, depth);
+
+        match &expression.kind {
+            ExpressionKind::Literal(literal) => {
+                // Register names like `A` are parsed as literals but can still name a symbol
+                let Some(value) = parse_number(literal).map(i64::from) else {
+                    return self.evaluate_identifier(literal, scope, depth);
+                };
+                if literal.starts_with('\'') {
+                    Some(self.translate_char(value, expression.span.start))
+                } else {
+                    Some(value)
+                }
+            }
+            ExpressionKind::Identifier(name) => self.evaluate_identifier(name, scope, depth),
+            ExpressionKind::Immediate(expr)
+            | ExpressionKind::Group(expr)
+            | ExpressionKind::UnaryPositive(expr) => eval(expr),
+            ExpressionKind::Unary(operator, expr) => {
+                let value = eval(expr)?;
+                match operator {
+                    TokenType::Plus => Some(value),
+                    TokenType::Minus => Some(value.wrapping_neg()),
+                    TokenType::BitwiseNot => Some(!value),
+                    TokenType::LessThan => Some(value & 0xff),
+                    TokenType::GreaterThan => Some((value >> 8) & 0xff),
+                    TokenType::Caret => Some((value >> 16) & 0xff),
+                    _ => None,
+                }
+            }
+            ExpressionKind::Not(expr) => Some((eval(expr)? == 0) as i64),
+            ExpressionKind::Or(left, right) => Some((eval(left)? != 0 || eval(right)? != 0) as i64),
+            ExpressionKind::And(left, right) => {
+                Some((eval(left)? != 0 && eval(right)? != 0) as i64)
+            }
+            ExpressionKind::Xor(left, right) => {
+                Some(((eval(left)? != 0) ^ (eval(right)? != 0)) as i64)
+            }
+            ExpressionKind::Comparison(operator, left, right) => {
+                let (left, right) = (eval(left)?, eval(right)?);
+                let result = match operator {
+                    TokenType::Equal => left == right,
+                    TokenType::NotEqual => left != right,
+                    TokenType::LessThan => left < right,
+                    TokenType::GreaterThan => left > right,
+                    TokenType::LessThanEq => left <= right,
+                    TokenType::GreaterThanEq => left >= right,
+                    _ => return None,
+                };
+                Some(result as i64)
+            }
+            ExpressionKind::SimpleExpression(operator, left, right) => {
+                let (left, right) = (eval(left)?, eval(right)?);
+                match operator.token_type {
+                    TokenType::Plus => Some(left.wrapping_add(right)),
+                    TokenType::Minus => Some(left.wrapping_sub(right)),
+                    TokenType::BitwiseOr => Some(left | right),
+                    _ => None,
+                }
+            }
+            ExpressionKind::Term(operator, left, right) => {
+                let (left, right) = (eval(left)?, eval(right)?);
+                match operator {
+                    TokenType::Multiply => Some(left.wrapping_mul(right)),
+                    TokenType::Divide => left.checked_div(right),
+                    TokenType::Mod => left.checked_rem(right),
+                    TokenType::BitwiseAnd => Some(left & right),
+                    TokenType::BitwiseXor => Some(left ^ right),
+                    TokenType::ShiftLeft => left.checked_shl(u32::try_from(right).ok()?),
+                    TokenType::ShiftRight => left.checked_shr(u32::try_from(right).ok()?),
+                    _ => None,
+                }
+            }
+            ExpressionKind::PseudoFunction(function, args)
+                if function.lexeme.eq_ignore_ascii_case(".sizeof") =>
+            {
+                match args.as_slice() {
+                    [
+                        Expression {
+                            kind: ExpressionKind::Identifier(name),
+                            ..
+                        },
+                    ] => self.size_of(scope, name),
+                    _ => None,
+                }
+            }
+            ExpressionKind::WordOp(operator, expr) => {
+                let value = eval(expr)?;
+                match operator.lexeme.to_lowercase().as_str() {
+                    ".lobyte" => Some(value & 0xff),
+                    ".hibyte" => Some((value >> 8) & 0xff),
+                    ".bankbyte" => Some((value >> 16) & 0xff),
+                    ".loword" => Some(value & 0xffff),
+                    ".hiword" => Some((value >> 16) & 0xffff),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ASTVisitor for ConstantEvaluator {
+    fn visit_constant_assign(&mut self, statement: &ConstantAssign, _span: Span) {
+        let fqn = self.qualify(&statement.name.lexeme);
+        if statement.reassignable && self.constants.contains_key(&fqn) {
+            self.constants.remove(&fqn);
+            self.variables.insert(fqn.clone());
+        }
+        if !self.variables.contains(&fqn) {
+            self.constants
+                .insert(fqn, (self.scope.clone(), statement.value.clone()));
+        }
+    }
+
+    fn visit_charmap(&mut self, index: &Expression, code: &Expression, span: Span) {
+        if let (Some(index), Some(code)) = (
+            self.evaluate_in(index, &self.scope),
+            self.evaluate_in(code, &self.scope),
+        ) {
+            self.charmap.push((span.start, index, code));
+        }
+    }
+
+    fn visit_scope(&mut self, name: &Option<Token>, statements: &[Statement], _span: Span) {
+        // Anonymous scopes don't add a segment to the names inside them
+        self.scope
+            .extend(name.as_ref().map(|name| name.lexeme.clone()));
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+        if name.is_some() {
+            self.scope.pop();
+        }
+    }
+
+    fn visit_procedure(
+        &mut self,
+        name: &Token,
+        _address_size: &AddressSize,
+        statements: &[Statement],
+        _span: Span,
+    ) {
+        self.scope.push(name.lexeme.clone());
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+        self.scope.pop();
+    }
+
+    fn visit_macro_definition(
+        &mut self,
+        name: &Token,
+        _parameters: &[Token],
+        _variadic: &bool,
+        statements: &[Statement],
+        _span: Span,
+    ) {
+        self.scope.push(name.lexeme.clone());
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+        self.scope.pop();
+    }
+
+    fn visit_struct(&mut self, name: &Token, members: &[StructMember], _span: Span) {
+        let fqn = self.qualify(&name.lexeme);
+        self.scope.push(name.lexeme.clone());
+        let layout = self.layout_struct(members);
+        self.scope.pop();
+        self.structs.insert(fqn, layout);
+    }
+
+    // Members count up from 0, or from the last explicit value. Members of a named enum live in
+    // its scope, so a member's value can refer to earlier members of the same enum.
+    fn visit_enum(&mut self, name: &Option<Token>, members: &[EnumMember], _span: Span) {
+        self.scope
+            .extend(name.as_ref().map(|name| name.lexeme.clone()));
+        let mut next = Some(0);
+        for member in members {
+            let value = match &member.value {
+                Some(value) => self.evaluate_in(value, &self.scope),
+                None => next,
+            };
+            next = value.map(|value| value + 1);
+            self.enum_values.insert(
+                self.qualify(&member.name.lexeme),
+                EnumValue {
+                    member: member.clone(),
+                    value,
+                },
+            );
+        }
+        if name.is_some() {
+            self.scope.pop();
+        }
+    }
+}
+
+impl ConstantEvaluator {
+    // Lays fields out one after another, so each offset is the sum of the sizes before it
+    fn layout_struct(&mut self, members: &[StructMember]) -> StructLayout {
+        let mut fields = vec![];
+        let mut offset = Some(0);
+
+        for member in members {
+            let size = match member {
+                StructMember::Field {
+                    name,
+                    data_type,
+                    tag,
+                    count,
+                } => {
+                    let size = self.field_size(data_type, tag, count);
+                    fields.push(StructField {
+                        name: name.clone(),
+                        offset,
+                        size,
+                    });
+                    size
+                }
+                StructMember::Struct(statement) => match &statement.kind {
+                    StatementKind::Struct(name, members) => {
+                        let fqn = self.qualify(&name.lexeme);
+                        self.scope.push(name.lexeme.clone());
+                        let layout = self.layout_struct(members);
+                        self.scope.pop();
+                        let size = layout.size;
+                        self.structs.insert(fqn, layout);
+                        size
+                    }
+                    _ => None,
+                },
+            };
+            offset = offset.zip(size).map(|(offset, size)| offset + size);
+        }
+
+        StructLayout {
+            fields,
+            size: offset,
+        }
+    }
+
+    fn field_size(
+        &self,
+        data_type: &Token,
+        tag: &Option<Token>,
+        count: &Option<Expression>,
+    ) -> Option<i64> {
+        let count = match count {
+            Some(count) => self.evaluate_in(count, &self.scope)?,
+            None => 1,
+        };
+        let size = match data_type.lexeme.to_lowercase().as_str() {
+            ".byte" | ".res" => 1,
+            ".word" | ".addr" | ".dbyt" => 2,
+            ".faraddr" => 3,
+            ".dword" => 4,
+            ".tag" => resolve(&self.structs, &self.scope, &tag.as_ref()?.lexeme)?.size?,
+            _ => return None,
+        };
+
+        Some(size * count)
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        [&["".to_owned()], &self.scope[..], &[name.to_owned()]]
+            .concat()
+            .join("::")
+    }
+}
+
+// Looks `name` up as seen from `scope`, searching from the innermost scope outwards
+fn resolve<'a, T>(table: &'a HashMap<String, T>, scope: &[String], name: &str) -> Option<&'a T> {
+    if name.starts_with("::") {
+        return table.get(name);
+    }
+
+    (0..=scope.len()).rev().find_map(|i| {
+        let fqn = [&["".to_owned()], &scope[..i], &[name.to_owned()]]
+            .concat()
+            .join("::");
+        table.get(&fqn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_file::INSTRUCTIONS;
+
+    fn evaluator(source: &str) -> ConstantEvaluator {
+        let tokens = parser::Tokenizer::new(source, &INSTRUCTIONS)
+            .parse()
+            .unwrap();
+        let (ast, errors) = parser::Parser::new(&tokens).parse();
+        assert!(errors.is_empty(), "{errors:?}");
+        ConstantEvaluator::new(&ast)
+    }
+
+    #[test]
+    fn constants_with_the_same_name_in_different_scopes_keep_their_own_value() {
+        let evaluator = evaluator(
+            ".scope A\nSIZE = 4\n.endscope\n.scope B\nSIZE = 8\nLEN = SIZE * 2\n.endscope\nTOTAL = A::SIZE + B::SIZE\n",
+        );
+
+        assert_eq!(evaluator.evaluate_constant("::A::SIZE"), Some(4));
+        assert_eq!(evaluator.evaluate_constant("::B::SIZE"), Some(8));
+        assert_eq!(evaluator.evaluate_constant("::B::LEN"), Some(16));
+        assert_eq!(evaluator.evaluate_constant("::TOTAL"), Some(12));
+        assert_eq!(evaluator.evaluate_constant("::SIZE"), None);
+
+        let size = Expression {
+            kind: ExpressionKind::Identifier("SIZE".to_string()),
+            span: Span::NONE,
+        };
+        assert_eq!(evaluator.evaluate_in(&size, &["A".to_string()]), Some(4));
+        assert_eq!(evaluator.evaluate_in(&size, &["B".to_string()]), Some(8));
+    }
+
+    #[test]
+    fn struct_size_adds_up_its_fields() {
+        let evaluator =
+            evaluator(".struct Point\nxpos .byte\nypos .word\n.endstruct\nSIZE = .sizeof(Point)\n");
+
+        assert_eq!(evaluator.size_of(&[], "Point"), Some(3));
+        assert_eq!(evaluator.size_of(&[], "Point::ypos"), Some(2));
+        assert_eq!(evaluator.evaluate_constant("::SIZE"), Some(3));
+    }
+
+    #[test]
+    fn character_constants_use_the_charmap_in_effect_where_they_are_written() {
+        let evaluator = evaluator(
+            "BEFORE = 'A'\n.charmap 'A', 1\nFIRST = 'A'\n.charmap $41, 2\nSECOND = 'A'\nOTHER = 'B'\n",
+        );
+
+        assert_eq!(evaluator.evaluate_constant("::BEFORE"), Some(65));
+        assert_eq!(evaluator.evaluate_constant("::FIRST"), Some(1));
+        assert_eq!(evaluator.evaluate_constant("::SECOND"), Some(2));
+        assert_eq!(evaluator.evaluate_constant("::OTHER"), Some(66));
+    }
+
+    #[test]
+    fn reserved_struct_fields_take_a_symbolic_count() {
+        let evaluator = evaluator(
+            "WIDTH = 8\n.struct Row\nflags .byte\ntiles .res WIDTH * 2\nlast .byte\n.endstruct\n",
+        );
+
+        assert_eq!(evaluator.size_of(&[], "Row::tiles"), Some(16));
+        assert_eq!(evaluator.size_of(&[], "Row"), Some(18));
+    }
+
+    #[test]
+    fn enum_members_count_up_from_earlier_members() {
+        let evaluator = evaluator(".enum Foo\nA\nB = A+4\nC\n.endenum\nNEXT = Foo::C + 1\n");
+
+        assert_eq!(evaluator.evaluate_constant("::Foo::A"), Some(0));
+        assert_eq!(evaluator.evaluate_constant("::Foo::B"), Some(4));
+        assert_eq!(evaluator.evaluate_constant("::Foo::C"), Some(5));
+        assert_eq!(evaluator.evaluate_constant("::NEXT"), Some(6));
+    }
+
+    #[test]
+    fn tagged_fields_take_the_size_of_their_struct() {
+        let evaluator = evaluator(
+            ".struct Point\nxpos .word\nypos .word\n.endstruct\n.struct Sprite\npos .tag Point\nflags .byte\n.endstruct\n",
+        );
+
+        assert_eq!(evaluator.size_of(&[], "Sprite::pos"), Some(4));
+        assert_eq!(evaluator.size_of(&[], "Sprite"), Some(5));
+        assert_eq!(evaluator.evaluate_constant("::Sprite::flags"), Some(4));
+    }
+}
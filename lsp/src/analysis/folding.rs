@@ -0,0 +1,92 @@
+use crate::analysis::visitor::ASTVisitor;
+use codespan::Span;
+use parser::{Ast, EnumMember, Expression, IfStatement, Statement, Token};
+
+/// Collects the spans of `.if` and `.repeat` blocks, along with anonymous `.scope`s and `.enum`s.
+///
+/// Named scopes, structs and macros are already folded through the scope tree, so they are not
+/// included here.
+pub struct BlockCollector {
+    blocks: Vec<Span>,
+}
+
+impl BlockCollector {
+    pub fn find_blocks(ast: &Ast) -> Vec<Span> {
+        let mut slf = BlockCollector { blocks: Vec::new() };
+        for statement in ast.iter() {
+            slf.visit_statement(statement);
+        }
+        slf.blocks
+    }
+}
+
+impl ASTVisitor for BlockCollector {
+    fn visit_scope(&mut self, name: &Option<Token>, statements: &[Statement], span: Span) {
+        if name.is_none() {
+            self.blocks.push(span);
+        }
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+    }
+
+    fn visit_enum(&mut self, name: &Option<Token>, _variants: &[EnumMember], span: Span) {
+        if name.is_none() {
+            self.blocks.push(span);
+        }
+    }
+
+    fn visit_repeat(
+        &mut self,
+        _max: &Expression,
+        _incr: &Option<Token>,
+        statements: &[Statement],
+        span: Span,
+    ) {
+        self.blocks.push(span);
+        for statement in statements {
+            self.visit_statement(statement);
+        }
+    }
+
+    fn visit_if(&mut self, if_statement: &IfStatement, span: Span) {
+        self.blocks.push(span);
+        for statement in if_statement.if_body.iter() {
+            self.visit_statement(statement);
+        }
+        for (_, statements) in if_statement.else_ifs.iter().flatten() {
+            for statement in statements {
+                self.visit_statement(statement);
+            }
+        }
+        for statement in if_statement.else_body.iter().flatten() {
+            self.visit_statement(statement);
+        }
+    }
+}
+
+/// Finds runs of two or more consecutive `;` comment lines, as inclusive line ranges.
+pub fn find_comment_blocks(source: &str) -> Vec<(usize, usize)> {
+    let mut blocks = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut last = 0;
+
+    for (index, line) in source.lines().enumerate() {
+        if line.trim_start().starts_with(';') {
+            start.get_or_insert(index);
+            last = index;
+        } else if let Some(first) = start.take()
+            && last > first
+        {
+            blocks.push((first, last));
+        }
+    }
+
+    if let Some(first) = start
+        && last > first
+    {
+        blocks.push((first, last));
+    }
+
+    blocks
+}
@@ -1,3 +1,9 @@
+pub mod branches;
+pub mod calls;
+pub mod evaluator;
+pub mod folding;
+pub mod operands;
 pub mod scope_analyzer;
+pub mod selection;
 pub mod symbol_resolver;
 pub mod visitor;
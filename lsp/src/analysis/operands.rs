@@ -0,0 +1,53 @@
+use crate::analysis::visitor::ASTVisitor;
+use codespan::Span;
+use parser::{Ast, DataKind, Expression, ExpressionKind, Instruction, Statement, Token};
+
+/// Collects instruction operands and data elements that are a bare symbol, such as `SPRITE_Y` in
+/// `lda #SPRITE_Y` or `.byte SPRITE_Y`.
+pub struct SymbolicOperandCollector {
+    operands: Vec<Expression>,
+}
+
+impl SymbolicOperandCollector {
+    pub fn find_operands(ast: &Ast) -> Vec<Expression> {
+        let mut slf = SymbolicOperandCollector { operands: vec![] };
+        for statement in ast.iter() {
+            slf.visit_statement(statement);
+        }
+        slf.operands
+    }
+
+    fn add(&mut self, expression: &Expression) {
+        let symbol = match &expression.kind {
+            ExpressionKind::Immediate(inner) => inner,
+            _ => expression,
+        };
+        if matches!(symbol.kind, ExpressionKind::Identifier(_)) {
+            self.operands.push(expression.clone());
+        }
+    }
+}
+
+impl ASTVisitor for SymbolicOperandCollector {
+    fn visit_instruction(&mut self, instruction: &Instruction, _span: Span) {
+        // The index register of `lda table, x` is parsed as a second operand
+        if let Some(operand) = instruction.parameters.first() {
+            self.add(operand);
+        }
+    }
+    // Parameters have no value until the macro is invoked
+    fn visit_macro_definition(
+        &mut self,
+        _name: &Token,
+        _parameters: &[Token],
+        _variadic: &bool,
+        _statements: &[Statement],
+        _span: Span,
+    ) {
+    }
+    fn visit_data(&mut self, _kind: &DataKind, expressions: &[Expression], _span: Span) {
+        for expression in expressions {
+            self.add(expression);
+        }
+    }
+}
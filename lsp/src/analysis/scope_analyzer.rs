@@ -2,18 +2,35 @@ use crate::analysis::visitor::ASTVisitor;
 use crate::cache_file::Include;
 use codespan::Span;
 use parser::{
-    Ast, ConstantAssign, EnumMember, Expression, ImportExport, Statement, StructMember, Token,
+    AddressSize, Ast, ConstantAssign, EnumMember, Expression, IfStatement, ImportExport, Statement,
+    StructMember, Token,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 
 #[derive(Debug, Clone)]
 pub enum Symbol {
-    Scope { name: Token },
-    Label { name: Token },
-    Macro { name: Token, parameters: Vec<Token> },
-    Constant { name: Token },
-    Parameter { name: Token }, // Disabled for now, need to track macro scopes
+    Scope {
+        name: Token,
+    },
+    Label {
+        name: Token,
+    },
+    Macro {
+        name: Token,
+        parameters: Vec<Token>,
+        variadic: bool,
+    },
+    Constant {
+        name: Token,
+    },
+    Define {
+        name: Token,
+        parameters: Vec<Token>,
+    },
+    Parameter {
+        name: Token,
+    },
 }
 
 impl Symbol {
@@ -23,6 +40,7 @@ impl Symbol {
             Symbol::Label { name, .. } => name,
             Symbol::Macro { name, .. } => name,
             Symbol::Constant { name, .. } => name,
+            Symbol::Define { name, .. } => name,
             Symbol::Parameter { name, .. } => name,
         };
 
@@ -35,6 +53,7 @@ impl Symbol {
             Symbol::Label { name, .. } => name.lexeme.clone(),
             Symbol::Macro { name, .. } => name.lexeme.clone(),
             Symbol::Constant { name, .. } => name.lexeme.clone(),
+            Symbol::Define { name, .. } => name.lexeme.clone(),
             Symbol::Parameter { name, .. } => name.lexeme.clone(),
         }
     }
@@ -44,14 +63,52 @@ impl Symbol {
             Symbol::Scope { name } => name.lexeme.clone(),
             Symbol::Label { name, .. } => format!("{}:", name.lexeme),
             Symbol::Macro {
-                name, parameters, ..
-            } => Self::format_parameters(name, parameters),
+                name,
+                parameters,
+                variadic,
+            } => Self::format_parameters(name, parameters, *variadic),
             Symbol::Constant { name, .. } => name.lexeme.clone(),
+            Symbol::Define { name, parameters } if parameters.is_empty() => {
+                format!(".define {}", name.lexeme)
+            }
+            Symbol::Define { name, parameters } => format!(
+                ".define {}({})",
+                name.lexeme,
+                parameters
+                    .iter()
+                    .map(|parameter| parameter.lexeme.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
             Symbol::Parameter { name, .. } => name.lexeme.clone(),
         }
     }
 
-    fn format_parameters(name: &Token, parameters: &[Token]) -> String {
+    pub fn get_parameters(&self) -> Vec<String> {
+        match self {
+            Symbol::Macro {
+                parameters,
+                variadic,
+                ..
+            } => {
+                let mut parameters = parameters
+                    .iter()
+                    .map(|parameter| parameter.lexeme.clone())
+                    .collect::<Vec<_>>();
+                if *variadic && let Some(last) = parameters.last_mut() {
+                    last.push_str("...");
+                }
+                parameters
+            }
+            Symbol::Define { parameters, .. } => parameters
+                .iter()
+                .map(|parameter| parameter.lexeme.clone())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn format_parameters(name: &Token, parameters: &[Token], variadic: bool) -> String {
         let mut output = String::new();
 
         write!(&mut output, ".macro {} ", name.lexeme).unwrap();
@@ -63,11 +120,24 @@ impl Symbol {
             }
             .unwrap()
         }
+        // The last parameter collects any remaining arguments
+        if variadic {
+            output.push_str("...");
+        }
 
         output
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Redefinition {
+    pub original: Symbol,
+    pub redefinition: Symbol,
+}
+
+// `.repeat` bodies get an unnamed scope so each loop counter is only visible inside its own loop
+pub const REPEAT_SCOPE: &str = "__repeat";
+
 #[derive(Clone, Debug)]
 pub struct Scope {
     pub name: String,
@@ -77,7 +147,11 @@ pub struct Scope {
 }
 
 impl Scope {
-    fn find_inner_scope(&self, index: usize) -> Option<Vec<Scope>> {
+    pub fn is_invisible(&self) -> bool {
+        self.name == REPEAT_SCOPE
+    }
+
+    pub fn find_inner_scope(&self, index: usize) -> Option<Vec<Scope>> {
         if index < self.span.start || index >= self.span.end {
             return None;
         }
@@ -103,6 +177,12 @@ pub struct ScopeAnalyzer {
     pub stack: Vec<Scope>,
     pub symtab: HashMap<String, Symbol>,
     pub includes: Vec<Include>,
+    pub binary_includes: Vec<Token>,
+    pub redefinitions: Vec<Redefinition>,
+    declarations: HashSet<String>,
+    variables: HashSet<String>,
+    conditional_depth: usize,
+    cheap_label_owner: Option<String>,
 }
 
 impl ScopeAnalyzer {
@@ -130,6 +210,12 @@ impl ScopeAnalyzer {
                 children: vec![],
             }],
             includes: vec![],
+            binary_includes: vec![],
+            redefinitions: vec![],
+            declarations: HashSet::new(),
+            variables: HashSet::new(),
+            conditional_depth: 0,
+            cheap_label_owner: None,
             symtab: HashMap::new(),
         }
     }
@@ -159,6 +245,19 @@ impl ScopeAnalyzer {
 
     #[inline]
     fn format_name(&self, name: &Token) -> String {
+        let stack: Vec<String> = self.stack[1..]
+            .iter()
+            .filter(|s| !s.is_invisible())
+            .map(|s| s.name.clone())
+            .collect();
+        [&["".to_owned()], &stack[..], &[name.lexeme.clone()]]
+            .concat()
+            .join("::")
+            .to_string()
+    }
+
+    // Unlike `format_name`, this keeps invisible scopes in the path
+    fn format_local_name(&self, name: &Token) -> String {
         let stack: Vec<String> = self.stack[1..].iter().map(|s| s.name.clone()).collect();
         [&["".to_owned()], &stack[..], &[name.lexeme.clone()]]
             .concat()
@@ -183,7 +282,31 @@ impl ScopeAnalyzer {
     }
 
     fn insert_symbol(&mut self, name: &Token, symbol: Symbol) {
-        self.symtab.insert(self.format_name(name), symbol);
+        let fqn = self.format_name(name);
+
+        // Cheap local labels may be reused between regular labels, and conditional branches may define the same symbol
+        if let Some(original) = self.symtab.get(&fqn)
+            && !self.declarations.remove(&fqn)
+            && self.conditional_depth == 0
+            && !name.lexeme.starts_with('@')
+        {
+            self.redefinitions.push(Redefinition {
+                original: original.clone(),
+                redefinition: symbol,
+            });
+            return;
+        }
+
+        self.symtab.insert(fqn, symbol);
+    }
+
+    fn declare_symbol(&mut self, name: &Token, symbol: Symbol) {
+        let fqn = self.format_name(name);
+
+        if !self.symtab.contains_key(&fqn) {
+            self.declarations.insert(fqn.clone());
+            self.symtab.insert(fqn, symbol);
+        }
     }
 }
 
@@ -193,49 +316,76 @@ impl ASTVisitor for ScopeAnalyzer {
             self.insert_symbol(name, Symbol::Scope { name: name.clone() });
 
             self.push_scope(name, span);
+            let owner = self.cheap_label_owner.take();
 
             for statement in statements {
                 self.visit_statement(statement);
             }
 
+            self.cheap_label_owner = owner;
             self.pop_scope();
         }
     }
     fn visit_constant_assign(&mut self, statement: &ConstantAssign, _span: Span) {
-        self.insert_symbol(
-            &statement.name,
-            Symbol::Constant {
-                name: statement.name.clone(),
-            },
-        );
+        // Symbols assigned with `.set` may be set again, so only the first assignment is kept
+        let fqn = self.format_name(&statement.name);
+        if !(statement.reassignable && self.variables.contains(&fqn)) {
+            if statement.reassignable {
+                self.variables.insert(fqn);
+            }
+            self.insert_symbol(
+                &statement.name,
+                Symbol::Constant {
+                    name: statement.name.clone(),
+                },
+            );
+        }
         self.visit_expression(&statement.value);
     }
-    fn visit_procedure(&mut self, name: &Token, _far: &bool, statements: &[Statement], span: Span) {
+    fn visit_procedure(
+        &mut self,
+        name: &Token,
+        _address_size: &AddressSize,
+        statements: &[Statement],
+        span: Span,
+    ) {
         self.insert_symbol(name, Symbol::Scope { name: name.clone() });
 
         self.push_scope(name, span);
+        let owner = self.cheap_label_owner.take();
 
         for statement in statements {
             self.visit_statement(statement);
         }
 
+        self.cheap_label_owner = owner;
         self.pop_scope()
     }
     fn visit_macro_definition(
         &mut self,
         name: &Token,
         parameters: &[Token],
+        variadic: &bool,
         statements: &[Statement],
         span: Span,
     ) {
-        self.insert_symbol(name, Symbol::Scope { name: name.clone() });
+        self.insert_symbol(
+            name,
+            Symbol::Macro {
+                name: name.clone(),
+                parameters: parameters.to_vec(),
+                variadic: *variadic,
+            },
+        );
 
+        // Parameters are only visible inside the macro body, so they live in the macro's scope
         self.push_scope(name, span);
+        let owner = self.cheap_label_owner.take();
 
         for parameter in parameters.iter() {
             self.insert_symbol(
                 parameter,
-                Symbol::Scope {
+                Symbol::Parameter {
                     name: parameter.clone(),
                 },
             );
@@ -245,6 +395,7 @@ impl ASTVisitor for ScopeAnalyzer {
             self.visit_statement(statement);
         }
 
+        self.cheap_label_owner = owner;
         self.pop_scope()
     }
     fn visit_define(
@@ -254,12 +405,19 @@ impl ASTVisitor for ScopeAnalyzer {
         expr: &Expression,
         span: Span,
     ) {
+        self.insert_symbol(
+            ident,
+            Symbol::Define {
+                name: ident.clone(),
+                parameters: params.clone().unwrap_or_default(),
+            },
+        );
         if let Some(params) = params {
             self.push_scope(ident, span);
             for param in params.iter() {
                 self.insert_symbol(
                     param,
-                    Symbol::Constant {
+                    Symbol::Parameter {
                         name: param.clone(),
                     },
                 );
@@ -268,8 +426,25 @@ impl ASTVisitor for ScopeAnalyzer {
         }
         self.visit_expression(expr);
     }
+    fn visit_undefine(&mut self, name: &Token, _span: Span) {
+        self.symtab.remove(&self.format_name(name));
+    }
+    fn visit_del_macro(&mut self, name: &Token, _span: Span) {
+        self.symtab.remove(&self.format_name(name));
+    }
     fn visit_label(&mut self, name: &Token, _span: Span) {
-        self.insert_symbol(name, Symbol::Label { name: name.clone() });
+        if !name.is_cheap_local() {
+            self.cheap_label_owner = Some(name.lexeme.clone());
+            self.insert_symbol(name, Symbol::Label { name: name.clone() });
+            return;
+        }
+
+        // Cheap local labels live under the regular label that precedes them
+        let mut scoped_name = name.clone();
+        if let Some(owner) = &self.cheap_label_owner {
+            scoped_name.lexeme = format!("{owner}::{}", name.lexeme);
+        }
+        self.insert_symbol(&scoped_name, Symbol::Label { name: name.clone() });
     }
     fn visit_struct(&mut self, name: &Token, members: &[StructMember], span: Span) {
         self.insert_symbol(name, Symbol::Scope { name: name.clone() });
@@ -278,7 +453,7 @@ impl ASTVisitor for ScopeAnalyzer {
 
         for member in members.iter() {
             match member {
-                StructMember::Field(field) => {
+                StructMember::Field { name: field, .. } => {
                     self.insert_symbol(
                         field,
                         Symbol::Constant {
@@ -310,6 +485,16 @@ impl ASTVisitor for ScopeAnalyzer {
             }
 
             self.pop_scope()
+        } else {
+            // Members of an anonymous enum belong to the enclosing scope
+            for member in members.iter() {
+                self.insert_symbol(
+                    &member.name,
+                    Symbol::Constant {
+                        name: member.name.clone(),
+                    },
+                );
+            }
         }
     }
 
@@ -318,17 +503,25 @@ impl ASTVisitor for ScopeAnalyzer {
         _max: &Expression,
         incr: &Option<Token>,
         statements: &[Statement],
-        _span: Span,
+        span: Span,
     ) {
-        // TODO: figure out how to have "invisible scopes"
-        // self.push_scope("__repeat".to_owned(), _span);
+        self.stack.push(Scope {
+            name: REPEAT_SCOPE.to_owned(),
+            name_span: span,
+            span,
+            children: vec![],
+        });
         if let Some(incr) = incr {
-            self.insert_symbol(incr, Symbol::Constant { name: incr.clone() });
+            // Sibling loops commonly reuse the same counter name, so this isn't a redefinition
+            self.symtab.insert(
+                self.format_local_name(incr),
+                Symbol::Constant { name: incr.clone() },
+            );
         }
         for statement in statements {
             self.visit_statement(statement);
         }
-        // self.pop_scope()
+        self.pop_scope()
     }
 
     fn visit_import(&mut self, imports: &[ImportExport], _zero_page: &bool, _span: Span) {
@@ -357,7 +550,35 @@ impl ASTVisitor for ScopeAnalyzer {
 
     fn visit_global(&mut self, identifiers: &[Token], _zero_page: &bool, _span: Span) {
         for identifier in identifiers {
-            self.insert_symbol(
+            self.declare_symbol(
+                identifier,
+                Symbol::Constant {
+                    name: identifier.clone(),
+                },
+            );
+        }
+    }
+
+    fn visit_if(&mut self, if_statement: &IfStatement, _span: Span) {
+        self.conditional_depth += 1;
+        for statement in if_statement.if_body.iter() {
+            self.visit_statement(statement);
+        }
+        for (_, statements) in if_statement.else_ifs.iter().flatten() {
+            for statement in statements {
+                self.visit_statement(statement);
+            }
+        }
+        for statement in if_statement.else_body.iter().flatten() {
+            self.visit_statement(statement);
+        }
+        self.conditional_depth -= 1;
+    }
+
+    fn visit_local(&mut self, identifiers: &[Token], _span: Span) {
+        // Locals are declared up front and usually defined as labels later in the macro body
+        for identifier in identifiers {
+            self.declare_symbol(
                 identifier,
                 Symbol::Constant {
                     name: identifier.clone(),
@@ -372,4 +593,190 @@ impl ASTVisitor for ScopeAnalyzer {
             scope: self.stack.clone(),
         })
     }
+
+    fn visit_include_binary(
+        &mut self,
+        path: &Token,
+        _offset: &Option<Expression>,
+        _end: &Option<Expression>,
+        _span: Span,
+    ) {
+        self.binary_includes.push(path.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::symbol_resolver::SymbolResolver;
+    use crate::cache_file::INSTRUCTIONS;
+    use crate::data::symbol::resolve_symbol;
+    use crate::test_support::load;
+
+    fn analyze(source: &str) -> ScopeAnalyzer {
+        let tokens = parser::Tokenizer::new(source, &INSTRUCTIONS)
+            .parse()
+            .unwrap();
+        let (ast, errors) = parser::Parser::new(&tokens).parse();
+        assert!(errors.is_empty(), "{errors:?}");
+        let mut analyzer = ScopeAnalyzer::new(ast);
+        analyzer.analyze();
+        analyzer
+    }
+
+    fn redefinitions(source: &str) -> Vec<String> {
+        analyze(source)
+            .redefinitions
+            .iter()
+            .map(|redefinition| redefinition.redefinition.get_name())
+            .collect()
+    }
+
+    fn symbols(source: &str) -> Vec<String> {
+        let mut symbols = analyze(source).symtab.into_keys().collect::<Vec<_>>();
+        symbols.sort();
+        symbols
+    }
+
+    #[test]
+    fn flags_symbols_defined_twice() {
+        let cases: &[(&str, &str, &[&str])] = &[
+            ("duplicate label", "foo: nop\nfoo: nop\n", &["foo"]),
+            ("declared with .global", ".global foo\nfoo: nop\n", &[]),
+            (
+                "declared with .local",
+                ".macro inc16\n.local tmp\ntmp: nop\n.endmacro\n",
+                &[],
+            ),
+            ("reassigned with .set", "n .set 1\nn .set 2\n", &[]),
+            (
+                "defined again after .undefine",
+                ".define FOO 1\n.undefine FOO\n.define FOO 2\n",
+                &[],
+            ),
+            (
+                "counter of sibling .repeat loops",
+                ".repeat 2, i\nnop\n.endrepeat\n.repeat 3, i\nnop\n.endrepeat\n",
+                &[],
+            ),
+            (
+                "defined in both branches of an .if",
+                ".if 1\nfoo = 1\n.else\nfoo = 2\n.endif\n",
+                &[],
+            ),
+            (
+                "cheap local under different labels",
+                "first:\n@loop: nop\nsecond:\n@loop: nop\n",
+                &[],
+            ),
+        ];
+
+        for (name, source, expected) in cases {
+            assert_eq!(redefinitions(source), *expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn flags_two_loop_labels_in_the_same_proc() {
+        assert_eq!(
+            redefinitions(".proc main\nloop: dex\nbne loop\nloop: dey\nbne loop\n.endproc\n"),
+            vec!["loop"]
+        );
+        assert!(
+            redefinitions(".proc a\nloop: dex\n.endproc\n.proc b\nloop: dey\n.endproc\n")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn undefine_removes_the_define() {
+        assert_eq!(
+            symbols(".define FOO 1\n.define BAR 2\n.undefine FOO\n"),
+            vec!["::BAR"]
+        );
+    }
+
+    #[tokio::test]
+    async fn locals_resolve_inside_their_macro() {
+        let (service, ids) = load(&[(
+            "main.s",
+            ".macro inc16 addr\n.local skip\ninc addr\nbne skip\ninc addr+1\nskip:\n.endmacro\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+        let file = state.files.get(ids[0]);
+
+        let access = SymbolResolver::find_identifiers(&file.ast)
+            .into_iter()
+            .find(|identifier| identifier.name == "skip")
+            .unwrap();
+        let symbol = resolve_symbol(&file.symbols, &access.scope, &access.name).unwrap();
+        assert_eq!(symbol.fqn, "::inc16::skip");
+        assert!(!symbols(".macro inc16\n.local skip\n.endmacro\n").contains(&"::skip".to_string()));
+    }
+
+    #[test]
+    fn describes_variadic_macros() {
+        let symtab = analyze(".macro poke addr, vals...\n.endmacro\n").symtab;
+        let symbol = &symtab["::poke"];
+        assert_eq!(symbol.get_description(), ".macro poke addr, vals...");
+        assert_eq!(symbol.get_parameters(), ["addr", "vals..."]);
+    }
+
+    #[test]
+    fn defines_are_recorded_with_their_parameters() {
+        let analyzer = analyze(".define WIDTH 32\n.define MAX(a, b) ((a) > (b))\n");
+
+        assert!(matches!(
+            &analyzer.symtab["::WIDTH"],
+            Symbol::Define { parameters, .. } if parameters.is_empty()
+        ));
+        assert_eq!(analyzer.symtab["::MAX"].get_parameters(), vec!["a", "b"]);
+        assert_eq!(
+            analyzer.symtab["::MAX"].get_description(),
+            ".define MAX(a, b)"
+        );
+        assert!(matches!(
+            &analyzer.symtab["::MAX::a"],
+            Symbol::Parameter { .. }
+        ));
+    }
+
+    #[test]
+    fn delmacro_forgets_the_macro() {
+        assert_eq!(
+            symbols(".macro push\npha\n.endmacro\n.delmacro push\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn symbols_in_every_branch_of_an_if_are_recorded() {
+        assert_eq!(
+            symbols(".if FOO\nfirst: nop\n.elseif BAR\nsecond: nop\n.else\nthird: nop\n.endif\n"),
+            vec!["::first", "::second", "::third"]
+        );
+    }
+
+    #[test]
+    fn nested_repeats_each_get_their_own_counter() {
+        let source = ".repeat 4, i\n.repeat 2, i\n.byte i\n.endrepeat\n.endrepeat\n";
+
+        assert!(redefinitions(source).is_empty());
+        assert_eq!(
+            symbols(source),
+            vec!["::__repeat::__repeat::i", "::__repeat::i"]
+        );
+    }
+
+    #[test]
+    fn cheap_local_labels_belong_to_the_label_before_them() {
+        let source = "first:\n@loop: dex\nbne @loop\nsecond:\n@loop: dey\nbne @loop\n";
+
+        assert!(redefinitions(source).is_empty());
+        assert_eq!(
+            symbols(source),
+            vec!["::first", "::first::@loop", "::second", "::second::@loop"]
+        );
+    }
 }
@@ -0,0 +1,43 @@
+use crate::analysis::visitor::{ASTVisitor, walk_expression, walk_statement};
+use codespan::Span;
+use parser::{Ast, Expression, Statement};
+
+/// Collects the spans of the statements and expressions enclosing an offset, outermost first.
+pub struct SelectionCollector {
+    offset: usize,
+    spans: Vec<Span>,
+}
+
+impl SelectionCollector {
+    pub fn find_spans(ast: &Ast, offset: usize) -> Vec<Span> {
+        let mut slf = SelectionCollector {
+            offset,
+            spans: Vec::new(),
+        };
+        for statement in ast.iter() {
+            slf.visit_statement(statement);
+        }
+        slf.spans
+    }
+
+    // The end is inclusive so a cursor just after the last character still selects the node
+    fn contains(&self, span: Span) -> bool {
+        span.start <= self.offset && self.offset <= span.end
+    }
+}
+
+impl ASTVisitor for SelectionCollector {
+    fn visit_statement(&mut self, statement: &Statement) {
+        if self.contains(statement.span) {
+            self.spans.push(statement.span);
+            walk_statement(self, statement);
+        }
+    }
+
+    fn visit_expression(&mut self, expression: &Expression) {
+        if self.contains(expression.span) {
+            self.spans.push(expression.span);
+            walk_expression(self, expression);
+        }
+    }
+}
@@ -1,24 +1,32 @@
+use crate::analysis::scope_analyzer::REPEAT_SCOPE;
 use crate::analysis::visitor::ASTVisitor;
 use codespan::Span;
-use parser::{Ast, EnumMember, Expression, ImportExport, Statement, StructMember, Token};
+use parser::{
+    AddressSize, Ast, EnumMember, Expression, ImportExport, Statement, StructMember, Token,
+};
 
 #[derive(Debug)]
 pub struct IdentifierAccess {
     pub name: String,
     pub span: Span,
     pub scope: Vec<String>,
+    pub in_macro: bool,
 }
 
 pub struct SymbolResolver {
     identifiers: Vec<IdentifierAccess>,
     scope_stack: Vec<String>,
+    macro_depth: usize,
+    cheap_label_owner: Option<String>,
 }
 
 impl SymbolResolver {
-    pub fn find_identifiers(ast: Ast) -> Vec<IdentifierAccess> {
+    pub fn find_identifiers(ast: &Ast) -> Vec<IdentifierAccess> {
         let mut slf = SymbolResolver {
             identifiers: Vec::new(),
             scope_stack: Vec::new(),
+            macro_depth: 0,
+            cheap_label_owner: None,
         };
         for statement in ast.iter() {
             slf.visit_statement(statement);
@@ -32,11 +40,13 @@ impl ASTVisitor for SymbolResolver {
         if let Some(name) = name {
             self.scope_stack.push(name.to_string());
         }
+        let owner = self.cheap_label_owner.take();
 
         for statement in statements {
             self.visit_statement(statement);
         }
 
+        self.cheap_label_owner = owner;
         if name.is_some() {
             self.scope_stack.pop();
         }
@@ -45,23 +55,32 @@ impl ASTVisitor for SymbolResolver {
         &mut self,
         name: &Token,
         _parameters: &[Token],
+        _variadic: &bool,
         statements: &[Statement],
         _span: Span,
     ) {
         self.scope_stack.push(name.to_string());
+        self.macro_depth += 1;
+        let owner = self.cheap_label_owner.take();
 
-        // Skip type checking in macros for now. Might be good to add local label completion at some point, but ultimately we don't know the context the macro is invoked in yet
-
+        // Parameters resolve through the macro's scope. Anything else depends on the context the macro is invoked in, so it's flagged as such
         for statement in statements {
             self.visit_statement(statement);
         }
 
+        self.cheap_label_owner = owner;
+        self.macro_depth -= 1;
         self.scope_stack.pop();
     }
-    fn visit_enum(&mut self, name: &Option<Token>, _variants: &[EnumMember], _span: Span) {
+    fn visit_enum(&mut self, name: &Option<Token>, variants: &[EnumMember], _span: Span) {
+        // Member values can refer to earlier members without qualifying them
         if let Some(name) = name {
             self.scope_stack.push(name.to_string());
-
+        }
+        for value in variants.iter().filter_map(|variant| variant.value.as_ref()) {
+            self.visit_expression(value);
+        }
+        if name.is_some() {
             self.scope_stack.pop();
         }
     }
@@ -78,10 +97,22 @@ impl ASTVisitor for SymbolResolver {
             self.scope_stack.pop();
         }
     }
-    fn visit_struct(&mut self, name: &Token, _members: &[StructMember], _span: Span) {
-        self.scope_stack.push(name.lexeme.clone());
-
-        self.scope_stack.pop();
+    fn visit_struct(&mut self, _name: &Token, members: &[StructMember], _span: Span) {
+        // Structs embedded with `.tag` and symbolic counts like `.res WIDTH` are looked up from
+        // where the struct is declared
+        for member in members {
+            match member {
+                StructMember::Field { tag, count, .. } => {
+                    if let Some(tag) = tag {
+                        self.visit_identifier(&tag.lexeme, tag.span);
+                    }
+                    if let Some(count) = count {
+                        self.visit_expression(count);
+                    }
+                }
+                StructMember::Struct(strct) => self.visit_statement(strct),
+            }
+        }
     }
     fn visit_repeat(
         &mut self,
@@ -90,7 +121,7 @@ impl ASTVisitor for SymbolResolver {
         statements: &[Statement],
         _span: Span,
     ) {
-        self.scope_stack.push("__repeat".to_owned());
+        self.scope_stack.push(REPEAT_SCOPE.to_owned());
 
         for statement in statements {
             self.visit_statement(statement);
@@ -101,24 +132,36 @@ impl ASTVisitor for SymbolResolver {
     fn visit_procedure(
         &mut self,
         name: &Token,
-        _far: &bool,
+        _address_size: &AddressSize,
         statements: &[Statement],
         _span: Span,
     ) {
         self.scope_stack.push(name.to_string());
+        let owner = self.cheap_label_owner.take();
 
         for statement in statements {
             self.visit_statement(statement);
         }
 
+        self.cheap_label_owner = owner;
         self.scope_stack.pop();
     }
+    fn visit_label(&mut self, name: &Token, _span: Span) {
+        if !name.is_cheap_local() {
+            self.cheap_label_owner = Some(name.lexeme.clone());
+        }
+    }
     fn visit_identifier(&mut self, ident: &str, span: Span) {
         let scope = self.scope_stack[..].to_vec();
+        let name = match &self.cheap_label_owner {
+            Some(owner) if ident.starts_with('@') => format!("{owner}::{ident}"),
+            _ => ident.to_owned(),
+        };
         self.identifiers.push(IdentifierAccess {
-            name: ident.to_owned(),
+            name,
             span,
             scope,
+            in_macro: self.macro_depth > 0,
         });
     }
 
@@ -130,8 +173,86 @@ impl ASTVisitor for SymbolResolver {
                     name: export.name.to_string(),
                     span: export.name.span,
                     scope: scope.clone(),
+                    in_macro: self.macro_depth > 0,
                 })
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_file::INSTRUCTIONS;
+
+    fn identifiers(source: &str) -> Vec<String> {
+        let tokens = parser::Tokenizer::new(source, &INSTRUCTIONS)
+            .parse()
+            .unwrap();
+        let (ast, errors) = parser::Parser::new(&tokens).parse();
+        assert!(errors.is_empty(), "{errors:?}");
+
+        SymbolResolver::find_identifiers(&ast)
+            .into_iter()
+            .map(|identifier| identifier.name)
+            .collect()
+    }
+
+    #[test]
+    fn constructors_reference_their_routine() {
+        assert_eq!(
+            identifiers(".constructor init, 7\ninit: rts\n"),
+            vec!["init"]
+        );
+    }
+
+    #[test]
+    fn incbin_offsets_and_lengths_reference_symbols() {
+        assert_eq!(
+            identifiers(".incbin \"tiles.chr\", TILE_OFFSET, TILE_COUNT * 16\n"),
+            vec!["TILE_OFFSET", "TILE_COUNT"]
+        );
+    }
+
+    #[test]
+    fn reservation_counts_reference_symbols() {
+        assert_eq!(
+            identifiers(".res WIDTH*2, FILL\n.struct Row\ntiles .res WIDTH\n.endstruct\n"),
+            vec!["WIDTH", "FILL", "WIDTH"]
+        );
+    }
+
+    #[test]
+    fn address_tables_reference_every_entry() {
+        assert_eq!(
+            identifiers(".addr a, b, c\n.faraddr far_routine\nlsr a\n"),
+            vec!["a", "b", "c", "far_routine"]
+        );
+    }
+
+    #[test]
+    fn references_in_every_branch_of_an_if_are_found() {
+        assert_eq!(
+            identifiers(".if FOO\njsr first\n.elseif BAR\njsr second\n.else\njsr third\n.endif\n"),
+            vec!["FOO", "first", "BAR", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn string_builtins_reference_their_arguments() {
+        assert_eq!(
+            identifiers(
+                "A1 = .concat(\"a\", PREFIX)\nA2 = .string(VALUE)\nA3 = .strlen(NAME)\nA4 = .strat(TEXT, INDEX)\n"
+            ),
+            vec!["PREFIX", "VALUE", "NAME", "TEXT", "INDEX"]
+        );
+    }
+
+    #[test]
+    fn assert_conditions_reference_symbols() {
+        assert_eq!(
+            identifiers(".assert SIZE < 256, error, \"too big\"\n"),
+            vec!["SIZE"]
+        );
+    }
+}
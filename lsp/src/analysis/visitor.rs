@@ -1,69 +1,13 @@
 use codespan::Span;
 use parser::{
-    ConstantAssign, EnumMember, Expression, ExpressionKind, IfKind, ImportExport, Instruction,
-    MacroInvocation, MacroParameter, Segment, Statement, StatementKind, StructMember, Token,
-    TokenType,
+    AddressSize, ConDesKind, ConstantAssign, CpuState, DataKind, EnumMember, Expression,
+    ExpressionKind, IfKind, IfStatement, ImportExport, Instruction, MacroInvocation,
+    MacroParameter, Segment, Statement, StatementKind, StructMember, Token, TokenType,
 };
 
 pub trait ASTVisitor {
     fn visit_statement(&mut self, statement: &Statement) {
-        match &statement.kind {
-            StatementKind::ConstantAssign(stmt) => self.visit_constant_assign(stmt, statement.span),
-            StatementKind::Include(path) => self.visit_include(path, statement.span),
-            StatementKind::Label(name) => self.visit_label(name, statement.span),
-            StatementKind::Instruction(instruction) => {
-                self.visit_instruction(instruction, statement.span)
-            }
-            StatementKind::Procedure(name, far, statements) => {
-                self.visit_procedure(name, far, statements, statement.span)
-            }
-            StatementKind::Enum(name, variants) => self.visit_enum(name, variants, statement.span),
-            StatementKind::Struct(name, members) => {
-                self.visit_struct(name, members, statement.span)
-            }
-            StatementKind::Macro => self.visit_macro(statement.span),
-            StatementKind::SetCPU(cpu) => self.visit_set_cpu(cpu, statement.span),
-            StatementKind::Segment(segment) => self.visit_segment(segment, statement.span),
-            StatementKind::Tag(expression) => self.visit_tag(expression, statement.span),
-            StatementKind::Reserve(amount, val) => self.visit_reserve(amount, val, statement.span),
-            StatementKind::MacroInvocation(macro_invocation) => {
-                self.visit_macro_invocation(macro_invocation, statement.span)
-            }
-            StatementKind::MacroPack(pack) => self.visit_macro_pack(pack, statement.span),
-            StatementKind::Feature(name) => self.visit_feature(name, statement.span),
-            StatementKind::Scope(name, statements) => {
-                self.visit_scope(name, statements, statement.span)
-            }
-            StatementKind::IncludeBinary(path, offset, end) => {
-                self.visit_include_binary(path, offset, end, statement.span)
-            }
-            StatementKind::MacroDefinition(name, parameters, statements) => {
-                self.visit_macro_definition(name, parameters, statements, statement.span)
-            }
-            StatementKind::Data(expressions) => self.visit_data(expressions, statement.span),
-            StatementKind::Org(address) => self.visit_org(address, statement.span),
-            StatementKind::Repeat(max, incr, statements) => {
-                self.visit_repeat(max, incr, statements, statement.span)
-            }
-            StatementKind::Global {
-                identifiers,
-                zero_page,
-            } => self.visit_global(identifiers, zero_page, statement.span),
-            StatementKind::Export { exports, zero_page } => {
-                self.visit_export(exports, zero_page, statement.span)
-            }
-            StatementKind::Import { imports, zero_page } => {
-                self.visit_import(imports, zero_page, statement.span)
-            }
-            StatementKind::Ascii(string) => self.visit_ascii(string, statement.span),
-            StatementKind::If(if_statement, statements) => {
-                self.visit_if(if_statement, statements, statement.span)
-            }
-            StatementKind::UnnamedLabel => self.visit_unnamed_label(statement.span),
-            StatementKind::Define(ident, params, expr) => {
-                self.visit_define(ident, params, expr, statement.span)
-            }
-        }
+        walk_statement(self, statement);
     }
 
     fn visit_constant_assign(&mut self, statement: &ConstantAssign, _span: Span) {
@@ -79,7 +23,7 @@ pub trait ASTVisitor {
     fn visit_procedure(
         &mut self,
         _name: &Token,
-        _far: &bool,
+        _address_size: &AddressSize,
         statements: &[Statement],
         _span: Span,
     ) {
@@ -108,8 +52,8 @@ pub trait ASTVisitor {
             }
         }
     }
-    fn visit_macro_pack(&mut self, _pack: &str, _span: Span) {}
-    fn visit_feature(&mut self, _name: &str, _span: Span) {}
+    fn visit_macro_pack(&mut self, _pack: &Token, _span: Span) {}
+    fn visit_feature(&mut self, _name: &Token, _span: Span) {}
     fn visit_scope(&mut self, _name: &Option<Token>, statements: &[Statement], _span: Span) {
         for statement in statements {
             self.visit_statement(statement);
@@ -118,15 +62,22 @@ pub trait ASTVisitor {
     fn visit_include_binary(
         &mut self,
         _path: &Token,
-        _offset: &Option<Token>,
-        _end: &Option<Token>,
+        offset: &Option<Expression>,
+        end: &Option<Expression>,
         _span: Span,
     ) {
+        if let Some(offset) = offset {
+            self.visit_expression(offset);
+        }
+        if let Some(end) = end {
+            self.visit_expression(end);
+        }
     }
     fn visit_macro_definition(
         &mut self,
         _name: &Token,
         _parameters: &[Token],
+        _variadic: &bool,
         statements: &[Statement],
         _span: Span,
     ) {
@@ -134,12 +85,14 @@ pub trait ASTVisitor {
             self.visit_statement(statement);
         }
     }
-    fn visit_data(&mut self, expressions: &[Expression], _span: Span) {
+    fn visit_data(&mut self, _kind: &DataKind, expressions: &[Expression], _span: Span) {
         for expression in expressions {
             self.visit_expression(expression);
         }
     }
-    fn visit_org(&mut self, _address: &str, _span: Span) {}
+    fn visit_org(&mut self, address: &Expression, _span: Span) {
+        self.visit_expression(address);
+    }
     fn visit_repeat(
         &mut self,
         max: &Expression,
@@ -156,14 +109,23 @@ pub trait ASTVisitor {
     fn visit_export(&mut self, _exports: &[ImportExport], _zero_page: &bool, _span: Span) {}
     fn visit_import(&mut self, _imports: &[ImportExport], _zero_page: &bool, _span: Span) {}
     fn visit_ascii(&mut self, _string: &Token, _span: Span) {}
-    fn visit_if(&mut self, if_statement: &IfKind, statements: &[Statement], _span: Span) {
-        match if_statement {
+    fn visit_if(&mut self, if_statement: &IfStatement, _span: Span) {
+        match &if_statement.kind {
             IfKind::WithExpression(expression) => self.visit_expression(expression),
             IfKind::NoParams => {}
             IfKind::WithTokens(_tokens) => {}
         }
 
-        for statement in statements {
+        for statement in if_statement.if_body.iter() {
+            self.visit_statement(statement);
+        }
+        for (condition, statements) in if_statement.else_ifs.iter().flatten() {
+            self.visit_expression(condition);
+            for statement in statements {
+                self.visit_statement(statement);
+            }
+        }
+        for statement in if_statement.else_body.iter().flatten() {
             self.visit_statement(statement);
         }
     }
@@ -177,48 +139,46 @@ pub trait ASTVisitor {
     ) {
         self.visit_expression(expr);
     }
+    fn visit_push_seg(&mut self, _span: Span) {}
+    fn visit_pop_seg(&mut self, _span: Span) {}
+    fn visit_push_cpu(&mut self, _span: Span) {}
+    fn visit_pop_cpu(&mut self, _span: Span) {}
+    fn visit_condes(
+        &mut self,
+        name: &Token,
+        kind: &ConDesKind,
+        priority: &Option<Expression>,
+        _span: Span,
+    ) {
+        self.visit_identifier(&name.lexeme, name.span);
+        if let ConDesKind::Other(kind) = kind {
+            self.visit_expression(kind);
+        }
+        if let Some(priority) = priority {
+            self.visit_expression(priority);
+        }
+    }
+    fn visit_undefine(&mut self, _name: &Token, _span: Span) {}
+    fn visit_del_macro(&mut self, _name: &Token, _span: Span) {}
+    fn visit_exit_macro(&mut self, _span: Span) {}
+    fn visit_local(&mut self, _identifiers: &[Token], _span: Span) {}
+    fn visit_cpu_state(&mut self, _state: &CpuState, _span: Span) {}
+    fn visit_assert(
+        &mut self,
+        condition: &Expression,
+        _action: &Token,
+        _message: &Option<Token>,
+        _span: Span,
+    ) {
+        self.visit_expression(condition);
+    }
+    fn visit_charmap(&mut self, index: &Expression, code: &Expression, _span: Span) {
+        self.visit_expression(index);
+        self.visit_expression(code);
+    }
 
     fn visit_expression(&mut self, expression: &Expression) {
-        match &expression.kind {
-            ExpressionKind::Immediate(expr) => self.visit_immediate(expr, expression.span),
-            ExpressionKind::Unary(token, expr) => self.visit_unary(token, expr, expression.span),
-            ExpressionKind::Literal(string) => self.visit_literal(string, expression.span),
-            ExpressionKind::Group(group) => self.visit_group(group, expression.span),
-            ExpressionKind::MemoryAccess(expr) => self.visit_memory_access(expr, expression.span),
-            ExpressionKind::UnaryPositive(expr) => self.visit_unary_positive(expr, expression.span),
-            ExpressionKind::Math(tok, expr1, expr2) => {
-                self.visit_math(tok, expr1, expr2, expression.span)
-            }
-            ExpressionKind::Not(expr) => self.visit_not(expr, expression.span),
-            ExpressionKind::Or(expr1, expr2) => self.visit_or(expr1, expr2, expression.span),
-            ExpressionKind::And(expr1, expr2) => self.visit_and(expr1, expr2, expression.span),
-            ExpressionKind::Xor(expr1, expr2) => self.visit_xor(expr1, expr2, expression.span),
-            ExpressionKind::Comparison(tok, expr1, expr2) => {
-                self.visit_comparison(tok, expr1, expr2, expression.span)
-            }
-            ExpressionKind::SimpleExpression(tok, expr1, expr2) => {
-                self.visit_simple_expression(tok, expr1, expr2, expression.span)
-            }
-            ExpressionKind::Term(tok, expr1, expr2) => {
-                self.visit_term(tok, expr1, expr2, expression.span)
-            }
-            ExpressionKind::Bank(expr) => self.visit_bank(expr, expression.span),
-            ExpressionKind::WordOp(tok, expr) => self.visit_word_op(tok, expr, expression.span),
-            ExpressionKind::Match(expr1, expr2) => self.visit_match(expr1, expr2, expression.span),
-            ExpressionKind::Identifier(ident) => self.visit_identifier(ident, expression.span),
-            ExpressionKind::UnnamedLabelReference(reference) => {
-                self.visit_unnamed_label_reference(reference, expression.span)
-            }
-            ExpressionKind::String(str) => self.visit_string(str, expression.span),
-            ExpressionKind::Extract(tok, expr1, expr2) => {
-                self.visit_extract(tok, expr1, expr2, expression.span)
-            }
-            ExpressionKind::TokenList(toks) => self.visit_token_list(toks, expression.span),
-            ExpressionKind::Call(callee, arguments) => {
-                self.visit_call(callee, arguments, expression.span)
-            },
-            ExpressionKind::PseudoFunction(name, args) => self.visit_pseudo_function(name, args, expression.span),
-        }
+        walk_expression(self, expression);
     }
 
     fn visit_immediate(&mut self, expression: &Expression, _span: Span) {
@@ -234,6 +194,9 @@ pub trait ASTVisitor {
     fn visit_memory_access(&mut self, expr: &Expression, _span: Span) {
         self.visit_expression(expr);
     }
+    fn visit_indexed_indirect(&mut self, expr: &Expression, _register: &Token, _span: Span) {
+        self.visit_expression(expr);
+    }
     fn visit_unary_positive(&mut self, expr: &Expression, _span: Span) {
         self.visit_expression(expr);
     }
@@ -299,6 +262,10 @@ pub trait ASTVisitor {
         self.visit_expression(expr);
     }
     fn visit_match(&mut self, _expr1: &Expression, _expr2: &Expression, _span: Span) {}
+    fn visit_blank(&mut self, _tokens: &Expression, _span: Span) {}
+    fn visit_const(&mut self, expr: &Expression, _span: Span) {
+        self.visit_expression(expr);
+    }
     fn visit_pseudo_function(&mut self, _name: &Token, args: &[Expression], _span: Span) {
         for arg in args.iter() {
             self.visit_expression(arg);
@@ -322,4 +289,151 @@ pub trait ASTVisitor {
             self.visit_expression(expression);
         }
     }
+    fn visit_sprintf(&mut self, format: &Expression, arguments: &[Expression], _span: Span) {
+        self.visit_expression(format);
+        for expression in arguments {
+            self.visit_expression(expression);
+        }
+    }
+}
+
+/// Dispatches a statement to the matching `visit_*` method. Visitors that override
+/// `visit_statement` call this to keep descending into the statement.
+pub fn walk_statement<V: ASTVisitor + ?Sized>(visitor: &mut V, statement: &Statement) {
+    match &statement.kind {
+        StatementKind::ConstantAssign(stmt) => visitor.visit_constant_assign(stmt, statement.span),
+        StatementKind::Include(path) => visitor.visit_include(path, statement.span),
+        StatementKind::Label(name) => visitor.visit_label(name, statement.span),
+        StatementKind::Instruction(instruction) => {
+            visitor.visit_instruction(instruction, statement.span)
+        }
+        StatementKind::Procedure(name, address_size, statements) => {
+            visitor.visit_procedure(name, address_size, statements, statement.span)
+        }
+        StatementKind::Enum(name, variants) => visitor.visit_enum(name, variants, statement.span),
+        StatementKind::Struct(name, members) => visitor.visit_struct(name, members, statement.span),
+        StatementKind::Macro => visitor.visit_macro(statement.span),
+        StatementKind::SetCPU(cpu) => visitor.visit_set_cpu(cpu, statement.span),
+        StatementKind::Segment(segment) => visitor.visit_segment(segment, statement.span),
+        StatementKind::Tag(expression) => visitor.visit_tag(expression, statement.span),
+        StatementKind::Reserve(amount, val) => visitor.visit_reserve(amount, val, statement.span),
+        StatementKind::MacroInvocation(macro_invocation) => {
+            visitor.visit_macro_invocation(macro_invocation, statement.span)
+        }
+        StatementKind::MacroPack(pack) => visitor.visit_macro_pack(pack, statement.span),
+        StatementKind::Feature(name) => visitor.visit_feature(name, statement.span),
+        StatementKind::Scope(name, statements) => {
+            visitor.visit_scope(name, statements, statement.span)
+        }
+        StatementKind::IncludeBinary(path, offset, end) => {
+            visitor.visit_include_binary(path, offset, end, statement.span)
+        }
+        StatementKind::MacroDefinition(name, parameters, variadic, statements) => {
+            visitor.visit_macro_definition(name, parameters, variadic, statements, statement.span)
+        }
+        StatementKind::Data(kind, expressions) => {
+            visitor.visit_data(kind, expressions, statement.span)
+        }
+        StatementKind::Org(address) => visitor.visit_org(address, statement.span),
+        StatementKind::Repeat(max, incr, statements) => {
+            visitor.visit_repeat(max, incr, statements, statement.span)
+        }
+        StatementKind::Global {
+            identifiers,
+            zero_page,
+        } => visitor.visit_global(identifiers, zero_page, statement.span),
+        StatementKind::Export { exports, zero_page } => {
+            visitor.visit_export(exports, zero_page, statement.span)
+        }
+        StatementKind::Import { imports, zero_page } => {
+            visitor.visit_import(imports, zero_page, statement.span)
+        }
+        StatementKind::Ascii(string) => visitor.visit_ascii(string, statement.span),
+        StatementKind::If(if_statement) => visitor.visit_if(if_statement, statement.span),
+        StatementKind::UnnamedLabel => visitor.visit_unnamed_label(statement.span),
+        StatementKind::Define(ident, params, expr) => {
+            visitor.visit_define(ident, params, expr, statement.span)
+        }
+        StatementKind::PushSeg => visitor.visit_push_seg(statement.span),
+        StatementKind::PopSeg => visitor.visit_pop_seg(statement.span),
+        StatementKind::PushCpu => visitor.visit_push_cpu(statement.span),
+        StatementKind::PopCpu => visitor.visit_pop_cpu(statement.span),
+        StatementKind::ConDes {
+            name,
+            kind,
+            priority,
+        } => visitor.visit_condes(name, kind, priority, statement.span),
+        StatementKind::Undefine(name) => visitor.visit_undefine(name, statement.span),
+        StatementKind::DelMacro(name) => visitor.visit_del_macro(name, statement.span),
+        StatementKind::ExitMacro => visitor.visit_exit_macro(statement.span),
+        StatementKind::Local(identifiers) => visitor.visit_local(identifiers, statement.span),
+        StatementKind::CpuState(state) => visitor.visit_cpu_state(state, statement.span),
+        StatementKind::Assert {
+            condition,
+            action,
+            message,
+        } => visitor.visit_assert(condition, action, message, statement.span),
+        StatementKind::Charmap { index, code } => {
+            visitor.visit_charmap(index, code, statement.span)
+        }
+    }
+}
+
+/// Dispatches an expression to the matching `visit_*` method, like [`walk_statement`].
+pub fn walk_expression<V: ASTVisitor + ?Sized>(visitor: &mut V, expression: &Expression) {
+    match &expression.kind {
+        ExpressionKind::Immediate(expr) => visitor.visit_immediate(expr, expression.span),
+        ExpressionKind::Unary(token, expr) => visitor.visit_unary(token, expr, expression.span),
+        ExpressionKind::Literal(string) => visitor.visit_literal(string, expression.span),
+        ExpressionKind::Group(group) => visitor.visit_group(group, expression.span),
+        ExpressionKind::MemoryAccess(expr) => visitor.visit_memory_access(expr, expression.span),
+        ExpressionKind::IndexedIndirect(expr, register) => {
+            visitor.visit_indexed_indirect(expr, register, expression.span)
+        }
+        ExpressionKind::UnaryPositive(expr) => visitor.visit_unary_positive(expr, expression.span),
+        ExpressionKind::Math(tok, expr1, expr2) => {
+            visitor.visit_math(tok, expr1, expr2, expression.span)
+        }
+        ExpressionKind::Not(expr) => visitor.visit_not(expr, expression.span),
+        ExpressionKind::Or(expr1, expr2) => visitor.visit_or(expr1, expr2, expression.span),
+        ExpressionKind::And(expr1, expr2) => visitor.visit_and(expr1, expr2, expression.span),
+        ExpressionKind::Xor(expr1, expr2) => visitor.visit_xor(expr1, expr2, expression.span),
+        ExpressionKind::Comparison(tok, expr1, expr2) => {
+            visitor.visit_comparison(tok, expr1, expr2, expression.span)
+        }
+        ExpressionKind::SimpleExpression(tok, expr1, expr2) => {
+            visitor.visit_simple_expression(tok, expr1, expr2, expression.span)
+        }
+        ExpressionKind::Term(tok, expr1, expr2) => {
+            visitor.visit_term(tok, expr1, expr2, expression.span)
+        }
+        ExpressionKind::Bank(expr) => visitor.visit_bank(expr, expression.span),
+        ExpressionKind::WordOp(tok, expr) => visitor.visit_word_op(tok, expr, expression.span),
+        ExpressionKind::Match(expr1, expr2) => visitor.visit_match(expr1, expr2, expression.span),
+        ExpressionKind::Identifier(ident) => visitor.visit_identifier(ident, expression.span),
+        ExpressionKind::UnnamedLabelReference(reference) => {
+            visitor.visit_unnamed_label_reference(reference, expression.span)
+        }
+        ExpressionKind::String(str) => visitor.visit_string(str, expression.span),
+        ExpressionKind::Extract(tok, expr1, expr2) => {
+            visitor.visit_extract(tok, expr1, expr2, expression.span)
+        }
+        ExpressionKind::TokenList(toks) => visitor.visit_token_list(toks, expression.span),
+        ExpressionKind::Call(callee, arguments) => {
+            visitor.visit_call(callee, arguments, expression.span)
+        }
+        ExpressionKind::PseudoFunction(name, args)
+            if name.lexeme.eq_ignore_ascii_case(".sprintf") =>
+        {
+            match args.split_first() {
+                Some((format, args)) => visitor.visit_sprintf(format, args, expression.span),
+                None => visitor.visit_pseudo_function(name, args, expression.span),
+            }
+        }
+        ExpressionKind::PseudoFunction(name, args) => {
+            visitor.visit_pseudo_function(name, args, expression.span)
+        }
+        ExpressionKind::Blank(tokens) => visitor.visit_blank(tokens, expression.span),
+        ExpressionKind::Const(expr) => visitor.visit_const(expr, expression.span),
+    }
 }
@@ -1,46 +1,89 @@
-use crate::analysis::scope_analyzer::Scope;
-use crate::cache_file::CacheFile;
+use crate::analysis::evaluator::ConstantEvaluator;
+use crate::analysis::folding::{BlockCollector, find_comment_blocks};
+use crate::analysis::operands::SymbolicOperandCollector;
+use crate::analysis::scope_analyzer::{Scope, ScopeAnalyzer};
+use crate::analysis::selection::SelectionCollector;
+use crate::analysis::symbol_resolver::SymbolResolver;
+use crate::cache_file::{CacheFile, INSTRUCTIONS};
 use crate::completion::{
-    Ca65DotOperatorCompletionProvider, Ca65KeywordCompletionProvider, CompletionProvider,
-    FeatureCompletionProvider, InstructionCompletionProvider, MacpackCompletionProvider,
+    BlockSnippetCompletionProvider, Ca65DotOperatorCompletionProvider,
+    Ca65KeywordCompletionProvider, CompletionData, CompletionProvider, FeatureCompletionProvider,
+    IncludePathCompletion, InstructionCompletionProvider, MacpackCompletionProvider,
     SymbolCompletionProvider,
 };
 use crate::data::configuration::Configuration;
 use crate::data::files::Files;
+use crate::data::symbol::{Symbol, SymbolType, resolve_symbol};
 use crate::definition::Definition;
 use crate::documentation::DOCUMENTATION_COLLECTION;
 use crate::error::file_error_to_lsp;
+use crate::formatting::format_source;
 use crate::index_engine::IndexEngine;
 use crate::state::State;
-use codespan::FileId;
-use codespan::{File, Span};
-use std::path::Path;
-use std::process::Output;
+use codespan::{FileId, PositionEncoding};
+use codespan::{File, Position, Range, Span};
+use parser::{Expression, ExpressionKind, Token, TokenType, parse_number};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tower_lsp_server::lsp_types::{
+    CallHierarchyIncomingCall, CallHierarchyIncomingCallsParams, CallHierarchyItem,
+    CallHierarchyOutgoingCall, CallHierarchyOutgoingCallsParams, CallHierarchyPrepareParams,
+    CallHierarchyServerCapability, CodeAction, CodeActionKind, CodeActionOrCommand,
     CodeActionParams, CodeActionProviderCapability, CodeActionResponse, CompletionItem,
-    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic, DiagnosticSeverity,
-    DidChangeWatchedFilesParams, DidChangeWorkspaceFoldersParams, DocumentSymbol,
-    DocumentSymbolParams, DocumentSymbolResponse, FileOperationRegistrationOptions, FoldingRange,
+    CompletionOptions, CompletionParams, CompletionResponse, CreateFile, CreateFileOptions,
+    Diagnostic, DiagnosticSeverity, DidChangeWatchedFilesParams, DidChangeWorkspaceFoldersParams,
+    DocumentChangeOperation, DocumentChanges, DocumentFormattingParams, DocumentHighlight,
+    DocumentHighlightKind, DocumentHighlightParams, DocumentLink, DocumentLinkOptions,
+    DocumentLinkParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    Documentation, FileOperationRegistrationOptions, FoldingRange, FoldingRangeKind,
     FoldingRangeParams, FoldingRangeProviderCapability, HoverContents, HoverProviderCapability,
-    InitializedParams, InlayHint, InlayHintLabel, InlayHintParams, LocationLink, MarkupContent,
-    MarkupKind, MessageType, OneOf, Registration, SymbolKind,
-    WorkspaceFileOperationsServerCapabilities, WorkspaceFoldersServerCapabilities,
-    WorkspaceServerCapabilities,
+    InitializedParams, InlayHint, InlayHintLabel, InlayHintParams, Location, LocationLink,
+    MarkupContent, MarkupKind, MessageType, OneOf, OptionalVersionedTextDocumentIdentifier,
+    ParameterInformation, ParameterLabel, PositionEncodingKind, PrepareRenameResponse,
+    ReferenceParams, Registration, RenameOptions, RenameParams, ResourceOp, SelectionRange,
+    SelectionRangeParams, SelectionRangeProviderCapability, SemanticToken, SemanticTokenType,
+    SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions,
+    SemanticTokensParams, SemanticTokensResult, SemanticTokensServerCapabilities, SignatureHelp,
+    SignatureHelpOptions, SignatureHelpParams, SignatureInformation, SymbolKind, TextDocumentEdit,
+    TextDocumentPositionParams, TextEdit, WorkspaceEdit, WorkspaceFileOperationsServerCapabilities,
+    WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities, WorkspaceSymbol,
+    WorkspaceSymbolParams, WorkspaceSymbolResponse,
 };
 use tower_lsp_server::{
-    jsonrpc::Result, lsp_types::{
-        DidChangeTextDocumentParams, DidOpenTextDocumentParams, GotoDefinitionParams,
-        GotoDefinitionResponse, Hover, HoverParams, InitializeParams, InitializeResult,
-        MarkedString, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+    jsonrpc::{Error, Result}, lsp_types::{
+        DidChangeTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
+        GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverParams, InitializeParams,
+        InitializeResult, MarkedString, ServerCapabilities, TextDocumentSyncCapability,
+        TextDocumentSyncKind, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, Uri,
     },
     Client,
     LanguageServer,
 };
+use url::Url;
 use crate::data::convert_uri::convert_uri;
 
+// Keeps an empty workspace symbol query from sending every symbol in the workspace
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+// How long typing has to pause before ca65 checks the edited units
+const ASSEMBLY_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// The legend sent to clients; semantic tokens refer to these by index
+const SEMANTIC_TOKEN_TYPES: [SemanticTokenType; 9] = [
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::MACRO,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::STRING,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::NAMESPACE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::COMMENT,
+];
+
 #[allow(dead_code)]
 pub struct Asm {
     client: Client,
@@ -62,6 +105,7 @@ impl Asm {
                 Arc::from(SymbolCompletionProvider {}),
                 Arc::from(InstructionCompletionProvider {}),
                 Arc::from(Ca65KeywordCompletionProvider {}),
+                Arc::from(BlockSnippetCompletionProvider {}),
                 Arc::from(Ca65DotOperatorCompletionProvider {}),
                 Arc::from(MacpackCompletionProvider {}),
                 Arc::from(FeatureCompletionProvider {}),
@@ -71,7 +115,18 @@ impl Asm {
         }
     }
 
+    #[cfg(test)]
+    pub fn state(&self) -> &Arc<Mutex<State>> {
+        &self.state
+    }
+
     async fn index(&self, file_id: FileId) {
+        let ca65 = self
+            .configuration
+            .lock()
+            .await
+            .get_ca65_path()
+            .filter(|path| path.exists());
         let mut state = self.state.lock().await;
         let indexing_state = state.files.index(file_id).await;
         let units = state.units.find_related(file_id);
@@ -80,7 +135,7 @@ impl Asm {
             for unit in units.iter() {
                 // TODO: handle diagnostics
                 let (deps, _diagnostics) = IndexEngine::calculate_deps(&mut state.files, *unit);
-                state.units.insert(file_id, deps);
+                state.units.insert(*unit, deps);
             }
         }
 
@@ -89,29 +144,67 @@ impl Asm {
             state.units[*unit].symbols = symbols;
         }
 
-        // diagnostics.extend(IndexEngine::invalidate(&mut state, file_id).await);
+        // The other files in these units keep their parse results, but what they reference may
+        // have changed, so only their lints are run again
+        let mut affected = HashSet::new();
+        for unit in units.iter() {
+            affected.insert(*unit);
+            affected.extend(state.units[*unit].deps.iter().copied());
+        }
+        affected.remove(&file_id);
 
-        // eprintln!(
-        //     "Affected Files: {:#?}",
-        //     state
-        //         .units
-        //         .find_related(file_id)
-        //         .iter()
-        //         .map(|id| { state.files.get_uri(*id).as_str().to_owned() })
-        //         .collect::<Vec<_>>()
-        // );
+        // An affected include can also belong to units this file isn't part of
+        let lint_units = std::iter::once(file_id)
+            .chain(affected.iter().copied())
+            .flat_map(|id| state.units.find_related(id))
+            .collect::<HashSet<_>>();
+        let referenced = IndexEngine::find_referenced_symbols(&state, lint_units);
 
-        state
-            .publish_diagnostics(file_id, indexing_state.diagnostics)
-            .await;
+        let mut diagnostics = indexing_state.diagnostics;
+        diagnostics.extend(IndexEngine::lint(&state, file_id, &referenced));
+        state.publish_diagnostics(file_id, diagnostics).await;
+
+        for id in affected {
+            let mut diagnostics = state.files.get(id).diagnostics.clone();
+            diagnostics.extend(IndexEngine::lint(&state, id, &referenced));
+            state.publish_diagnostics(id, diagnostics).await;
+        }
+
+        // Every edit starts another run, and the earlier ones give up once they see it
+        if let Some(ca65) = ca65 {
+            for assembly in Assembly::for_file(&state, file_id) {
+                let state = self.state.clone();
+                let ca65 = ca65.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(ASSEMBLY_DEBOUNCE).await;
+                    assembly.run(state, ca65).await;
+                });
+            }
+        }
     }
 
     async fn load_config(&self, path: &Path) -> Result<()> {
         let uri_str = "file://".to_owned() + path.to_str().unwrap();
         let uri = Uri::from_str(&uri_str).unwrap();
 
-        match Configuration::load(path) {
+        let encoding = self.state.lock().await.position_encoding;
+        match Configuration::load(path, encoding) {
             Ok(configuration) => {
+                let mut state = self.state.lock().await;
+                state.default_cpu = configuration.default_cpu().to_string();
+                state.lints = configuration.lints.clone();
+
+                // Lint levels and the default CPU may have changed, so every file is linted again
+                let units = state.units.0.keys().copied().collect::<Vec<_>>();
+                let referenced = IndexEngine::find_referenced_symbols(&state, units);
+                let ids = state.files.iter().map(|file| file.id).collect::<Vec<_>>();
+                for id in ids {
+                    let mut diagnostics = state.files.get(id).diagnostics.clone();
+                    diagnostics.extend(IndexEngine::lint(&state, id, &referenced));
+                    state.publish_diagnostics(id, diagnostics).await;
+                }
+                drop(state);
+
                 *self.configuration.lock().await = configuration;
                 self.client.publish_diagnostics(uri, vec![], None).await;
             }
@@ -126,15 +219,111 @@ impl Asm {
     }
 }
 
-#[allow(dead_code)]
-async fn make_diagnostics_from_ca65_output(
+/// One run of ca65 over a unit, remembering which versions of its files it started from.
+struct Assembly {
+    root: FileId,
+    path: PathBuf,
+    directory: PathBuf,
+    /// Files whose earlier ca65 messages this run replaces, even if it reports nothing for them
+    files: Vec<(FileId, Option<i32>)>,
+}
+
+impl Assembly {
+    /// ca65 assembles whole units, so an include is checked through the files including it.
+    fn for_file(state: &State, id: FileId) -> Vec<Assembly> {
+        state
+            .units
+            .find_related(id)
+            .into_iter()
+            .filter_map(|root| Assembly::new(state, root))
+            .collect()
+    }
+
+    fn new(state: &State, root: FileId) -> Option<Assembly> {
+        let path = state.files.get_path(root)?;
+        let directory = path.parent()?.to_path_buf();
+        let files = std::iter::once(root)
+            .chain(state.units.get(&root)?.deps.iter().copied())
+            .map(|file| (file, state.version(file)))
+            .collect();
+
+        Some(Assembly {
+            root,
+            path,
+            directory,
+            files,
+        })
+    }
+
+    /// ca65 reads the files from disk, so its lines only match while none of them has been edited
+    /// since the run was planned or has unsaved changes.
+    fn is_current(&self, state: &State) -> bool {
+        self.files.iter().all(|(file, version)| {
+            state.version(*file) == *version
+                && state
+                    .files
+                    .get_path(*file)
+                    .and_then(|path| std::fs::read_to_string(path).ok())
+                    .is_some_and(|saved| saved == state.files.get(*file).file.source)
+        })
+    }
+
+    async fn run(self, state: Arc<Mutex<State>>, ca65: PathBuf) {
+        if !self.is_current(&*state.lock().await) {
+            return;
+        }
+
+        let Ok(output_dir) = tempfile::tempdir() else {
+            return;
+        };
+        let Ok(output) = tokio::process::Command::new(ca65)
+            .current_dir(&self.directory)
+            .arg(&self.path)
+            .arg("-o")
+            .arg(output_dir.path().join("lint.o"))
+            .output()
+            .await
+        else {
+            return;
+        };
+
+        let mut state = state.lock().await;
+        if !self.is_current(&state) {
+            return;
+        }
+
+        let mut diagnostics = make_diagnostics_from_ca65_output(
+            &state.files,
+            self.root,
+            &String::from_utf8_lossy(&output.stderr),
+        );
+        for (file, _) in self.files {
+            diagnostics.entry(file).or_default();
+        }
+        for (file, diagnostics) in diagnostics {
+            state.publish_toolchain_diagnostics(file, diagnostics).await;
+        }
+    }
+}
+
+/// Turns the messages ca65 printed while assembling `file_id` into diagnostics for the files they
+/// name. ca65 runs from the assembled file's directory, so relative names are resolved against it,
+/// and messages about files that aren't indexed are dropped.
+fn make_diagnostics_from_ca65_output(
     files: &Files,
     file_id: FileId,
-    output: &Output,
-) -> Vec<Diagnostic> {
-    let mut diagnostics = vec![];
+    stderr: &str,
+) -> HashMap<FileId, Vec<Diagnostic>> {
+    let mut diagnostics: HashMap<FileId, Vec<Diagnostic>> = HashMap::new();
+    let Some(directory) = files
+        .get_path(file_id)
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+    else {
+        return diagnostics;
+    };
 
-    for line in String::from_utf8(output.stderr.clone()).unwrap().lines() {
+    // Each message looks like `file.s:12: Error: Unexpected trailing garbage characters`
+    for line in stderr.lines() {
         let message: Vec<&str> = line.splitn(4, ":").map(|part| part.trim()).collect();
 
         if message.len() < 4 {
@@ -142,21 +331,30 @@ async fn make_diagnostics_from_ca65_output(
             continue;
         }
 
-        let file = &files.get(file_id).file;
-
-        let line_span = file
-            .get_line(message[1].parse::<usize>().unwrap() - 1)
-            .unwrap();
-        let range = file.byte_span_to_range(line_span).unwrap();
+        let Some(id) = files.find_by_path(&directory.join(message[0])) else {
+            continue;
+        };
+        let file = &files.get(id).file;
+        let Some(line_span) = message[1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|line| file.get_line(line.checked_sub(1)?).ok())
+        else {
+            continue;
+        };
+        let Ok(range) = file.byte_span_to_range(line_span) else {
+            continue;
+        };
         let severity = match message[2] {
             "Error" => Some(DiagnosticSeverity::ERROR),
+            "Warning" => Some(DiagnosticSeverity::WARNING),
             _ => None,
         };
-        diagnostics.push(Diagnostic::new(
+        diagnostics.entry(id).or_default().push(Diagnostic::new(
             range.into(),
             severity,
             None,
-            None,
+            Some("ca65".to_string()),
             message[3].to_string(),
             None,
             None,
@@ -177,15 +375,43 @@ impl LanguageServer for Asm {
 
         state.client_capabilities = params.capabilities.clone();
 
+        // Prefer UTF-8 columns when the client offers them, which saves converting every position
+        let supports_utf8 = params
+            .capabilities
+            .general
+            .and_then(|general| general.position_encodings)
+            .is_some_and(|encodings| encodings.contains(&PositionEncodingKind::UTF8));
+        state.set_position_encoding(if supports_utf8 {
+            PositionEncoding::Utf8
+        } else {
+            PositionEncoding::Utf16
+        });
+
         Ok(InitializeResult {
             server_info: None,
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::INCREMENTAL,
+                position_encoding: Some(state.position_encoding.into()),
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                        ..Default::default()
+                    },
                 )),
                 definition_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
                 completion_provider: Some(CompletionOptions {
-                    trigger_characters: Some(vec![".".to_string()]),
+                    resolve_provider: Some(true),
+                    trigger_characters: Some(vec![
+                        ".".to_string(),
+                        "\"".to_string(),
+                        "/".to_string(),
+                    ]),
                     ..Default::default()
                 }),
                 code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
@@ -202,6 +428,33 @@ impl LanguageServer for Asm {
                 inlay_hint_provider: Some(OneOf::Left(true)),
                 folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
                 document_symbol_provider: Some(OneOf::Left(true)),
+                document_highlight_provider: Some(OneOf::Left(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                call_hierarchy_provider: Some(CallHierarchyServerCapability::Simple(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                document_link_provider: Some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: Default::default(),
+                }),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec![",".to_string()]),
+                    retrigger_characters: None,
+                    work_done_progress_options: Default::default(),
+                }),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(
+                        SemanticTokensOptions {
+                            legend: SemanticTokensLegend {
+                                token_types: SEMANTIC_TOKEN_TYPES.to_vec(),
+                                token_modifiers: vec![],
+                            },
+                            full: Some(SemanticTokensFullOptions::Bool(true)),
+                            range: None,
+                            work_done_progress_options: Default::default(),
+                        },
+                    ),
+                ),
+                document_formatting_provider: Some(OneOf::Left(true)),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
                 ..ServerCapabilities::default()
             },
@@ -262,11 +515,44 @@ impl LanguageServer for Asm {
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let mut state = self.state.lock().await;
         let id = state.reload_source(&params.text_document, params.content_changes);
+        state.clear_toolchain_diagnostics(id);
         drop(state);
 
         self.index(id).await;
     }
 
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let configuration = self.configuration.lock().await;
+        if !configuration.toolchain.lint_on_save {
+            return;
+        }
+        let Some(ca65) = configuration.get_ca65_path() else {
+            return;
+        };
+        drop(configuration);
+
+        if !ca65.exists() {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("ca65 not found at {}", ca65.display()),
+                )
+                .await;
+            return;
+        }
+        let state = self.state.lock().await;
+        let Some(id) = state.files.sources.get(&params.text_document.uri).copied() else {
+            return;
+        };
+        let assemblies = Assembly::for_file(&state, id);
+        drop(state);
+
+        // Assembling can take a moment, so it happens in the background to keep typing responsive
+        for assembly in assemblies {
+            tokio::spawn(assembly.run(self.state.clone(), ca65.clone()));
+        }
+    }
+
     async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
@@ -278,6 +564,46 @@ impl LanguageServer for Asm {
             .sources
             .get(&params.text_document_position_params.text_document.uri)
         {
+            let file = state.files.get(*id);
+            let offset = file
+                .file
+                .position_to_byte_index(params.text_document_position_params.position.into())
+                .map_err(file_error_to_lsp)?;
+            let on_path = |token: &&Token| token.span.start <= offset && offset < token.span.end;
+
+            // Jump straight to the file named by an `.include` or `.incbin` path
+            let include = file
+                .includes
+                .iter()
+                .map(|include| &include.path)
+                .find(on_path);
+            let binary_include = file.binary_includes.iter().find(on_path);
+            if let Some(path) = include.or(binary_include) {
+                let name = &path.lexeme[1..path.lexeme.len() - 1];
+                let target_uri = if include.is_some() {
+                    match state.files.resolve_import(*id, name) {
+                        Ok(Some(target)) => state.files.get_uri(target),
+                        _ => return Ok(None),
+                    }
+                } else {
+                    match state.files.resolve_binary_include(*id, name) {
+                        Ok(target) => target,
+                        Err(_) => return Ok(None),
+                    }
+                };
+                let origin = file
+                    .file
+                    .byte_span_to_range(path.span)
+                    .map_err(file_error_to_lsp)?;
+
+                return Ok(Some(GotoDefinitionResponse::Link(vec![LocationLink {
+                    origin_selection_range: Some(origin.into()),
+                    target_uri,
+                    target_range: Default::default(),
+                    target_selection_range: Default::default(),
+                }])));
+            }
+
             let (definitions, span) = self
                 .definition
                 .get_definition_position(
@@ -329,12 +655,44 @@ impl LanguageServer for Asm {
             .sources
             .get(&params.text_document_position_params.text_document.uri)
         {
-            let word = state
-                .files
-                .get(*id)
+            let file = state.files.get(*id);
+            let offset = file
                 .file
-                .get_word_at_position(params.text_document_position_params.position.into())
+                .position_to_byte_index(params.text_document_position_params.position.into())
                 .map_err(file_error_to_lsp)?;
+            if let Some(token) = file.tokens.iter().find(|tok| {
+                tok.token_type == TokenType::Number
+                    && tok.span.start <= offset
+                    && offset < tok.span.end
+            }) && let Some(mut value) = parse_number(&token.lexeme)
+            {
+                if token.lexeme.starts_with('\'') {
+                    let evaluator = ConstantEvaluator::new(&file.ast);
+                    value = evaluator.translate_char(value.into(), token.span.start) as u32;
+                }
+                return Ok(Some(Hover {
+                    range: Some(
+                        file.file
+                            .byte_span_to_range(token.span)
+                            .map_err(file_error_to_lsp)?
+                            .into(),
+                    ),
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: number_to_markdown(value),
+                    }),
+                }));
+            }
+
+            let Ok(word) = file
+                .file
+                .get_word_at_position(params.text_document_position_params.position.into())
+            else {
+                return Ok(None);
+            };
+            if word.is_empty() {
+                return Ok(None);
+            }
 
             // TODO: take context into account when choosing to show hover doc
             for (_doc_kind, doc) in DOCUMENTATION_COLLECTION.get().unwrap() {
@@ -361,7 +719,7 @@ impl LanguageServer for Asm {
             return if let Some((definitions, _span)) = definitions {
                 let documentation = definitions
                     .first()
-                    .map(|symbol| format!("```ca65\n{}\n```", symbol.comment.clone()))
+                    .map(|symbol| symbol_documentation(&state, symbol))
                     .map(MarkedString::from_markdown);
                 Ok(documentation.map(|doc| Hover {
                     range: None,
@@ -395,205 +753,2354 @@ impl LanguageServer for Asm {
         Ok(None)
     }
 
-    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<WorkspaceSymbolResponse>> {
+        let state = self.state.lock().await;
+        let query = params.query.to_lowercase();
+
+        let mut seen = HashSet::new();
+        let mut symbols = vec![];
+
+        for unit in state.units.0.values() {
+            for symbol in unit.symbols.iter() {
+                if symbols.len() >= MAX_WORKSPACE_SYMBOLS {
+                    break;
+                }
+                if !fuzzy_match(&query, &symbol.fqn) || !seen.insert((symbol.file_id, symbol.span))
+                {
+                    continue;
+                }
+
+                let file = state.files.get(symbol.file_id);
+                let range = file
+                    .file
+                    .byte_span_to_range(symbol.span)
+                    .map_err(file_error_to_lsp)?
+                    .into();
+                let (container_name, name) = match symbol.fqn.rsplit_once("::") {
+                    Some((parent, name)) => (
+                        Some(parent.to_string()).filter(|parent| !parent.is_empty()),
+                        name,
+                    ),
+                    None => (None, symbol.fqn.as_str()),
+                };
+
+                symbols.push(WorkspaceSymbol {
+                    name: name.to_string(),
+                    kind: symbol_kind(symbol.sym_type),
+                    tags: None,
+                    container_name,
+                    location: OneOf::Left(Location::new(
+                        state.files.get_uri(symbol.file_id),
+                        range,
+                    )),
+                    data: None,
+                });
+            }
+        }
+
+        Ok(Some(WorkspaceSymbolResponse::Nested(symbols)))
+    }
+
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        let state = self.state.lock().await;
+
+        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
+            let file = state.files.get(*id);
+            let mut selections = vec![];
+
+            // Grows from the token under the cursor through expressions and statements to the
+            // enclosing scopes, and finally the whole file
+            for position in params.positions {
+                let offset = file
+                    .file
+                    .position_to_byte_index(position.into())
+                    .map_err(file_error_to_lsp)?;
+                let mut spans = vec![Span::new(0, file.file.source.len())];
+                spans.extend(
+                    file.scopes
+                        .iter()
+                        .find_map(|scope| scope.find_inner_scope(offset))
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|scope| scope.span),
+                );
+                spans.extend(SelectionCollector::find_spans(&file.ast, offset));
+                spans.extend(
+                    file.tokens
+                        .iter()
+                        .find(|token| {
+                            token.token_type != TokenType::EOL
+                                && token.span.start <= offset
+                                && offset < token.span.end
+                        })
+                        .map(|token| token.span),
+                );
+
+                selections.push(
+                    spans_to_selection_range(&file.file, spans).unwrap_or(SelectionRange {
+                        range: Range {
+                            start: position.into(),
+                            end: position.into(),
+                        }
+                        .into(),
+                        parent: None,
+                    }),
+                );
+            }
+
+            return Ok(Some(selections));
+        }
+
+        Ok(None)
+    }
+
+    async fn prepare_call_hierarchy(
+        &self,
+        params: CallHierarchyPrepareParams,
+    ) -> Result<Option<Vec<CallHierarchyItem>>> {
         let state = self.state.lock().await;
 
         if let Some(id) = state
             .files
             .sources
-            .get(&params.text_document_position.text_document.uri)
+            .get(&params.text_document_position_params.text_document.uri)
         {
-            let mut completion_items: Vec<CompletionItem> = vec![];
-            for provider in self.completion_providers.iter() {
-                completion_items.extend(provider.completions_for(
+            let Some((definitions, _span)) = self
+                .definition
+                .get_definition_position(
                     &state,
                     *id,
-                    params.text_document_position.position.into(),
-                ));
-            }
-            Ok(Some(CompletionResponse::Array(completion_items)))
-        } else {
-            Ok(None)
+                    params.text_document_position_params.position.into(),
+                )
+                .map_err(file_error_to_lsp)?
+            else {
+                return Ok(None);
+            };
+
+            return Ok(definitions
+                .first()
+                .filter(|symbol| {
+                    matches!(
+                        symbol.sym_type,
+                        SymbolType::Label | SymbolType::Macro | SymbolType::Scope
+                    )
+                })
+                .and_then(|symbol| symbol_to_call_hierarchy_item(&state, symbol))
+                .map(|item| vec![item]));
         }
-    }
 
-    async fn code_action(&self, _params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
-        // self.client
-        //     .log_message(
-        //         MessageType::INFO,
-        //         format!("Uri: {}", params.text_document.uri.as_str()),
-        //     )
-        //     .await;
-        // let config_uri = Uri::from_str(
-        //     "file:///home/tech/Documents/ca65-lsp/project.toml",
-        // )
-        //     .unwrap();
-        //
-        // Ok(Some(vec![CodeActionOrCommand::CodeAction(CodeAction {
-        //     title: "Create workspace file".to_string(),
-        //     edit: Some(WorkspaceEdit {
-        //         document_changes: Some(DocumentChanges::Operations(vec![
-        //             DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
-        //                 uri: config_uri.clone(),
-        //                 annotation_id: None,
-        //                 options: Some(CreateFileOptions {
-        //                     overwrite: Some(false),
-        //                     ignore_if_exists: Some(true),
-        //                 }),
-        //             })),
-        //             DocumentChangeOperation::Edit(TextDocumentEdit {
-        //                 text_document: OptionalVersionedTextDocumentIdentifier {
-        //                     uri: config_uri.clone(),
-        //                     version: None,
-        //                 },
-        //                 edits: vec![OneOf::Left(TextEdit::new(
-        //                     Range::new(Position::new(0, 0), Position::new(0, 0)),
-        //                     "[compiler]\n".to_owned(),
-        //                 ))],
-        //             }),
-        //         ])),
-        //         ..Default::default()
-        //     }),
-        //     // diagnostics: Some(vec![
-        //     //     Diagnostic::new(
-        //     //         Range::new(Position::new(0, 0), Position::new(0, 1)),
-        //     //         Some(DiagnosticSeverity::WARNING),
-        //     //         None,
-        //     //         None,
-        //     //         "Requires folder based project".to_owned(),
-        //     //         None,
-        //     //         None,
-        //     //     )
-        //     // ]),
-        //     // command: Some(Command::new("Open file".to_string(), "vscode.open".to_string(), Some(vec![
-        //     //     serde_json::json!(config_uri.to_string())
-        //     // ]))),
-        //     kind: Some(CodeActionKind::QUICKFIX),
-        //     ..Default::default()
-        // })]))
         Ok(None)
     }
-    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
-        self.client
-            .log_message(MessageType::INFO, format!("Config: {:#?}", params))
-            .await;
+
+    async fn incoming_calls(
+        &self,
+        params: CallHierarchyIncomingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyIncomingCall>>> {
+        let state = self.state.lock().await;
+        let Some((id, fqn)) = call_hierarchy_item_symbol(&state, &params.item) else {
+            return Ok(None);
+        };
+
+        let calls = IndexEngine::find_calls(&state, id)
+            .into_iter()
+            .filter(|call| call.target.fqn == fqn)
+            .map(|call| (call.caller, call.file, call.span));
+
+        Ok(Some(
+            group_calls(&state, calls)
+                .into_iter()
+                .map(|(from, ranges)| CallHierarchyIncomingCall {
+                    from,
+                    from_ranges: ranges.into_iter().map(Into::into).collect(),
+                })
+                .collect(),
+        ))
     }
 
-    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
-        if let Some(event) = params.changes.first() {
-            self.load_config(Path::new(event.uri.path().as_str()))
-                .await
-                .expect("load_config failed");
-        }
+    async fn outgoing_calls(
+        &self,
+        params: CallHierarchyOutgoingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyOutgoingCall>>> {
+        let state = self.state.lock().await;
+        let Some((id, fqn)) = call_hierarchy_item_symbol(&state, &params.item) else {
+            return Ok(None);
+        };
+
+        let calls = IndexEngine::find_calls(&state, id)
+            .into_iter()
+            .filter(|call| call.caller.fqn == fqn)
+            .map(|call| (call.target, call.file, call.span));
+
+        Ok(Some(
+            group_calls(&state, calls)
+                .into_iter()
+                .map(|(to, ranges)| CallHierarchyOutgoingCall {
+                    to,
+                    from_ranges: ranges.into_iter().map(Into::into).collect(),
+                })
+                .collect(),
+        ))
     }
 
-    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
         let state = self.state.lock().await;
+        let position = params.text_document_position_params;
 
-        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
-            let file = &state.files.get(*id);
-            Ok(Some(
-                file.scopes
-                    .iter()
-                    .flat_map(|scope| scope_to_folding_range(&file.file, scope))
-                    .collect(),
-            ))
-        } else {
-            Ok(None)
+        let Some(id) = state.files.sources.get(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let units = state.units.find_related(*id);
+        let Some(unit) = units.first() else {
+            return Ok(None);
+        };
+
+        let Some((definitions, _span)) = self
+            .definition
+            .get_definition_position(&state, *id, position.position.into())
+            .map_err(file_error_to_lsp)?
+        else {
+            return Ok(None);
+        };
+        let Some(target) = definitions.first() else {
+            return Ok(None);
+        };
+
+        let file = state.files.get(*id);
+        let symbols = &state.units[*unit].symbols;
+        let mut highlights = vec![];
+
+        if target.file_id == *id {
+            highlights.push(DocumentHighlight {
+                range: file
+                    .file
+                    .byte_span_to_range(target.span)
+                    .map_err(file_error_to_lsp)?
+                    .into(),
+                kind: Some(DocumentHighlightKind::WRITE),
+            });
+        }
+
+        for identifier in SymbolResolver::find_identifiers(&file.ast) {
+            let resolved = resolve_symbol(symbols, &identifier.scope, &identifier.name);
+            if resolved.is_some_and(|symbol| symbol.fqn == target.fqn) {
+                highlights.push(DocumentHighlight {
+                    range: file
+                        .file
+                        .byte_span_to_range(identifier.span)
+                        .map_err(file_error_to_lsp)?
+                        .into(),
+                    kind: Some(DocumentHighlightKind::READ),
+                });
+            }
         }
+
+        Ok(Some(highlights))
     }
-    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
         let state = self.state.lock().await;
+        let position = params.text_document_position;
 
-        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
-            let file = &state.files.get(*id);
-            Ok(Some(
-                file.scopes
-                    .iter()
-                    .flat_map(|scope| scope_to_inlay_hint(&file.file, scope))
-                    .collect(),
-            ))
-        } else {
-            Ok(None)
+        let Some(id) = state.files.sources.get(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let Some((definitions, _span)) = self
+            .definition
+            .get_definition_position(&state, *id, position.position.into())
+            .map_err(file_error_to_lsp)?
+        else {
+            return Ok(None);
+        };
+        let Some(target) = definitions.first() else {
+            return Ok(None);
+        };
+
+        let mut references = IndexEngine::find_references(&state, *id, target);
+        if params.context.include_declaration {
+            references.push((target.file_id, target.span));
+        }
+        references.sort_by_key(|(file_id, span)| (file_id.get(), span.start));
+
+        let mut locations = vec![];
+        for (file_id, span) in references {
+            locations.push(Location {
+                uri: state.files.get_uri(file_id),
+                range: state
+                    .files
+                    .get(file_id)
+                    .file
+                    .byte_span_to_range(span)
+                    .map_err(file_error_to_lsp)?
+                    .into(),
+            });
         }
+
+        Ok(Some(locations))
     }
-}
 
-fn scope_to_folding_range(file: &File, scope: &Scope) -> Vec<FoldingRange> {
-    if let Ok(range) = file.byte_span_to_range(scope.span) {
-        let mut results = vec![FoldingRange {
-            start_line: range.start.line as u32,
-            start_character: None,
-            end_line: (range.end.line - 1) as u32,
-            end_character: None,
-            kind: None,
-            collapsed_text: None,
-        }];
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let state = self.state.lock().await;
 
-        results.extend(
-            scope
-                .children
-                .iter()
-                .flat_map(|scope| scope_to_folding_range(file, scope)),
-        );
+        let Some(id) = state.files.sources.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let file = state.files.get(*id);
+        let offset = file
+            .file
+            .position_to_byte_index(params.position.into())
+            .map_err(file_error_to_lsp)?;
 
-        results
-    } else {
-        Vec::new()
+        // Instructions, directives and registers are never renameable symbols
+        let on_identifier = file.tokens.iter().any(|token| {
+            token.token_type == TokenType::Identifier
+                && token.span.start <= offset
+                && offset <= token.span.end
+                && !is_register(&token.lexeme)
+        });
+        if !on_identifier {
+            return Ok(None);
+        }
+
+        let Some((definitions, span)) = self
+            .definition
+            .get_definition_position(&state, *id, params.position.into())
+            .map_err(file_error_to_lsp)?
+        else {
+            return Ok(None);
+        };
+        if definitions.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(PrepareRenameResponse::Range(
+            file.file
+                .byte_span_to_range(span)
+                .map_err(file_error_to_lsp)?
+                .into(),
+        )))
     }
-}
 
-fn scope_to_inlay_hint(file: &File, scope: &Scope) -> Vec<InlayHint> {
-    if let Ok(range) = file.byte_span_to_range(scope.span) {
-        let mut results = vec![InlayHint {
-            position: range.end.into(),
-            label: InlayHintLabel::String(scope.name.clone()),
-            kind: None,
-            text_edits: None,
-            tooltip: None,
-            padding_left: Some(true),
-            padding_right: None,
-            data: None,
-        }];
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let state = self.state.lock().await;
+        let position = params.text_document_position;
 
-        results.extend(
-            scope
-                .children
+        let Some(id) = state.files.sources.get(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        if !is_symbol_name(&params.new_name) {
+            return Err(Error::invalid_params(format!(
+                "{} is not a valid symbol name",
+                params.new_name
+            )));
+        }
+        let units = state.units.find_related(*id);
+        if units.is_empty() {
+            return Ok(None);
+        }
+        let Some((definitions, _span)) = self
+            .definition
+            .get_definition_position(&state, *id, position.position.into())
+            .map_err(file_error_to_lsp)?
+        else {
+            return Ok(None);
+        };
+        let Some(target) = definitions.first() else {
+            return Ok(None);
+        };
+        let name = target.fqn.rsplit("::").next().unwrap_or(&target.fqn);
+        // `@` is what makes a label cheap, so renaming can't add or remove it
+        if name.starts_with('@') != params.new_name.starts_with('@') {
+            return Err(Error::invalid_params(format!(
+                "{name} can't be renamed to {}",
+                params.new_name
+            )));
+        }
+
+        let renamed_fqn = match target.fqn.rsplit_once("::") {
+            Some((parent, _)) => format!("{parent}::{}", params.new_name),
+            None => params.new_name.clone(),
+        };
+        if units.iter().any(|unit| {
+            state.units[*unit]
+                .symbols
                 .iter()
-                .flat_map(|scope| scope_to_inlay_hint(file, scope)),
-        );
+                .any(|symbol| symbol.fqn == renamed_fqn)
+        }) {
+            return Err(Error::invalid_params(format!(
+                "{} is already defined in this scope",
+                params.new_name
+            )));
+        }
 
-        results
-    } else {
-        Vec::new()
-    }
-}
+        let mut references = IndexEngine::find_references(&state, *id, target);
+        references.push((target.file_id, target.span));
 
-fn scope_to_symbol(scope: &Scope, file: &CacheFile) -> Option<DocumentSymbol> {
-    if let Ok(range) = file.file.byte_span_to_range(scope.span) {
-        let range = range.into();
-        Some(DocumentSymbol {
-            name: scope.name.clone(),
-            detail: None,
-            kind: SymbolKind::NAMESPACE,
-            tags: None,
-            deprecated: None,
-            range,
-            selection_range: range,
-            children: {
-                let children: Vec<DocumentSymbol> = scope
-                    .children
+        let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+        for (file_id, span) in references {
+            let file = &state.files.get(file_id).file;
+            // Qualified accesses like `Foo::bar` only have their last segment renamed
+            if !file.source[span.start..span.end].ends_with(name) {
+                continue;
+            }
+            let span = Span::new(span.end - name.len(), span.end);
+
+            changes
+                .entry(state.files.get_uri(file_id))
+                .or_default()
+                .push(TextEdit {
+                    range: file
+                        .byte_span_to_range(span)
+                        .map_err(file_error_to_lsp)?
+                        .into(),
+                    new_text: params.new_name.clone(),
+                });
+        }
+
+        Ok(Some(WorkspaceEdit::new(changes)))
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let state = self.state.lock().await;
+        let position = params.text_document_position_params;
+
+        let Some(id) = state.files.sources.get(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let units = state.units.find_related(*id);
+        let Some(unit) = units.first() else {
+            return Ok(None);
+        };
+        let file = state.files.get(*id);
+        let offset = file
+            .file
+            .position_to_byte_index(position.position.into())
+            .map_err(file_error_to_lsp)?;
+        let scope = ScopeAnalyzer::search(&file.scopes, offset);
+        let symbols = &state.units[*unit].symbols;
+
+        let tokens = state
+            .files
+            .line_tokens(*id, position.position.into())
+            .into_iter()
+            .filter(|token| token.span.end <= offset)
+            .collect::<Vec<_>>();
+        let Some((index, symbol)) = tokens.iter().enumerate().find_map(|(index, token)| {
+            if token.token_type != TokenType::Identifier {
+                return None;
+            }
+            resolve_symbol(symbols, &scope[1..], &token.lexeme)
+                .filter(|symbol| matches!(symbol.sym_type, SymbolType::Macro))
+                .map(|symbol| (index, symbol))
+        }) else {
+            return Ok(None);
+        };
+
+        // Arguments may be wrapped in one pair of parentheses, and braces group a single argument
+        let arguments = &tokens[index + 1..];
+        let wrapped = arguments
+            .first()
+            .is_some_and(|token| token.token_type == TokenType::LeftParen);
+        let mut depth = 0;
+        let mut active_parameter = 0;
+        for token in arguments {
+            match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                TokenType::Comma if depth == i32::from(wrapped) => active_parameter += 1,
+                _ => {}
+            }
+        }
+
+        let parameters = symbol
+            .parameters
+            .iter()
+            .map(|parameter| ParameterInformation {
+                label: ParameterLabel::Simple(parameter.clone()),
+                documentation: None,
+            })
+            .collect::<Vec<_>>();
+        let active_parameter = active_parameter.min(parameters.len().saturating_sub(1)) as u32;
+
+        Ok(Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: symbol.comment.clone(),
+                documentation: None,
+                parameters: Some(parameters),
+                active_parameter: Some(active_parameter),
+            }],
+            active_signature: Some(0),
+            active_parameter: Some(active_parameter),
+        }))
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let state = self.state.lock().await;
+
+        let Some(id) = state.files.sources.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let file = state.files.get(*id);
+        let units = state.units.find_related(*id);
+        let symbols = units
+            .first()
+            .map(|unit| state.units[*unit].symbols.as_slice())
+            .unwrap_or_default();
+
+        let mut spans = file
+            .tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(index, token)| {
+                let token_type = match token.token_type {
+                    TokenType::Instruction => SemanticTokenType::KEYWORD,
+                    TokenType::Macro => SemanticTokenType::MACRO,
+                    TokenType::Number => SemanticTokenType::NUMBER,
+                    TokenType::String => SemanticTokenType::STRING,
+                    TokenType::Identifier => {
+                        let scope = ScopeAnalyzer::search(&file.scopes, token.span.start);
+                        let name = qualified_name(&file.tokens, index);
+                        match resolve_symbol(symbols, &scope[1..], &name)?.sym_type {
+                            SymbolType::Label => SemanticTokenType::FUNCTION,
+                            SymbolType::Constant => SemanticTokenType::VARIABLE,
+                            SymbolType::Macro | SymbolType::Define => SemanticTokenType::MACRO,
+                            SymbolType::Scope => SemanticTokenType::NAMESPACE,
+                            SymbolType::Parameter => SemanticTokenType::PARAMETER,
+                        }
+                    }
+                    _ => return None,
+                };
+                Some((token.span, token_type))
+            })
+            .chain(
+                file.comments
                     .iter()
-                    .filter_map(|child| scope_to_symbol(child, file))
-                    .collect();
-                if children.is_empty() {
-                    None
-                } else {
-                    Some(children)
+                    .map(|span| (*span, SemanticTokenType::COMMENT)),
+            )
+            .collect::<Vec<_>>();
+        spans.sort_by_key(|(span, _)| span.start);
+
+        let mut data = vec![];
+        let (mut line, mut character) = (0, 0);
+        for (span, token_type) in spans {
+            let Ok(range) = file.file.byte_span_to_range(span) else {
+                continue;
+            };
+            // Multi-line tokens would need splitting, which clients don't all support
+            if range.start.line != range.end.line {
+                continue;
+            }
+
+            let delta_line = range.start.line - line;
+            let delta_start = if delta_line == 0 {
+                range.start.character - character
+            } else {
+                range.start.character
+            };
+            data.push(SemanticToken {
+                delta_line: delta_line as u32,
+                delta_start: delta_start as u32,
+                length: (range.end.character - range.start.character) as u32,
+                token_type: SEMANTIC_TOKEN_TYPES
+                    .iter()
+                    .position(|legend| *legend == token_type)
+                    .unwrap_or_default() as u32,
+                token_modifiers_bitset: 0,
+            });
+            (line, character) = (range.start.line, range.start.character);
+        }
+
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data,
+        })))
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let state = self.state.lock().await;
+        let configuration = self.configuration.lock().await;
+
+        let Some(id) = state.files.sources.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let file = &state.files.get(*id).file;
+        let Some(formatted) = format_source(&file.source, &configuration.format) else {
+            return Ok(None);
+        };
+        if formatted == file.source {
+            return Ok(Some(vec![]));
+        }
+
+        let end = file
+            .byte_index_to_position(file.source.len())
+            .map_err(file_error_to_lsp)?;
+
+        Ok(Some(vec![TextEdit {
+            range: Range {
+                start: Position::new(0, 0),
+                end,
+            }
+            .into(),
+            new_text: formatted,
+        }]))
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> Result<Option<Vec<DocumentLink>>> {
+        let state = self.state.lock().await;
+
+        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
+            let file = state.files.get(*id);
+            let mut links = vec![];
+
+            for include in file.includes.iter() {
+                let path = &include.path.lexeme[1..include.path.lexeme.len() - 1];
+                if let Ok(Some(target)) = state.files.resolve_import(*id, path) {
+                    links.push(DocumentLink {
+                        range: file
+                            .file
+                            .byte_span_to_range(include.path.span)
+                            .map_err(file_error_to_lsp)?
+                            .into(),
+                        target: Some(state.files.get_uri(target)),
+                        tooltip: None,
+                        data: None,
+                    });
                 }
-            },
+            }
+
+            for path in file.binary_includes.iter() {
+                if let Ok(target) = state
+                    .files
+                    .resolve_binary_include(*id, &path.lexeme[1..path.lexeme.len() - 1])
+                {
+                    links.push(DocumentLink {
+                        range: file
+                            .file
+                            .byte_span_to_range(path.span)
+                            .map_err(file_error_to_lsp)?
+                            .into(),
+                        target: Some(target),
+                        tooltip: None,
+                        data: None,
+                    });
+                }
+            }
+
+            return Ok(Some(links));
+        }
+        Ok(None)
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let state = self.state.lock().await;
+
+        if let Some(id) = state
+            .files
+            .sources
+            .get(&params.text_document_position.text_document.uri)
+        {
+            let mut completion_items: Vec<CompletionItem> = vec![];
+            for provider in self.completion_providers.iter() {
+                completion_items.extend(provider.completions_for(
+                    &state,
+                    *id,
+                    params.text_document_position.position.into(),
+                ));
+            }
+
+            // Reading the directory doesn't need the state, so it happens after unlocking it
+            let include_paths = IncludePathCompletion::at(
+                &state,
+                *id,
+                params.text_document_position.position.into(),
+            );
+            drop(state);
+            if let Some(include_paths) = include_paths {
+                completion_items.extend(include_paths.list());
+            }
+
+            Ok(Some(CompletionResponse::Array(completion_items)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn completion_resolve(&self, mut item: CompletionItem) -> Result<CompletionItem> {
+        let Some(data) = item
+            .data
+            .clone()
+            .and_then(|data| serde_json::from_value::<CompletionData>(data).ok())
+        else {
+            return Ok(item);
+        };
+        let state = self.state.lock().await;
+
+        // The file may have been reindexed since the list was sent, so the symbol is looked up again
+        if let Some(symbol) = state
+            .files
+            .iter()
+            .find(|file| file.id.get() == data.file)
+            .and_then(|file| file.symbols.iter().find(|symbol| symbol.fqn == data.fqn))
+        {
+            item.detail = Some(symbol.comment.clone());
+            item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: symbol_documentation(&state, symbol),
+            }));
+        }
+
+        Ok(item)
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let state = self.state.lock().await;
+        let mut actions = vec![];
+
+        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
+            let file = &state.files.get(*id).file;
+            if let (Ok(start), Ok(end)) = (
+                file.position_to_byte_index(params.range.start.into()),
+                file.position_to_byte_index(params.range.end.into()),
+            ) {
+                actions.extend(include_fixes(&state.files, *id, Span::new(start, end)));
+            }
+        }
+        actions.extend(create_config_action(state.workspace_folder.as_ref()));
+
+        Ok((!actions.is_empty()).then_some(actions))
+    }
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        self.client
+            .log_message(MessageType::INFO, format!("Config: {:#?}", params))
+            .await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        if let Some(event) = params.changes.first() {
+            self.load_config(Path::new(event.uri.path().as_str()))
+                .await
+                .expect("load_config failed");
+        }
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let state = self.state.lock().await;
+
+        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
+            let file = &state.files.get(*id);
+            let mut ranges: Vec<FoldingRange> = file
+                .scopes
+                .iter()
+                .flat_map(|scope| scope_to_folding_range(&file.file, scope))
+                .collect();
+
+            ranges.extend(
+                BlockCollector::find_blocks(&file.ast)
+                    .into_iter()
+                    .filter_map(|span| span_to_folding_range(&file.file, span)),
+            );
+            ranges.extend(find_comment_blocks(&file.file.source).into_iter().map(
+                |(start, end)| FoldingRange {
+                    start_line: start as u32,
+                    start_character: None,
+                    end_line: end as u32,
+                    end_character: None,
+                    kind: Some(FoldingRangeKind::Comment),
+                    collapsed_text: None,
+                },
+            ));
+
+            Ok(Some(ranges))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let state = self.state.lock().await;
+        let constant_values = self.configuration.lock().await.inlay_hints.constant_values;
+
+        if let Some(id) = state.files.sources.get(&params.text_document.uri) {
+            let file = &state.files.get(*id);
+            let mut hints = file
+                .scopes
+                .iter()
+                .flat_map(|scope| scope_to_inlay_hint(&file.file, scope))
+                .collect::<Vec<_>>();
+            let evaluator = ConstantEvaluator::new(&file.ast);
+
+            // Enum members show the value they count up to, unless it's already written out
+            for enum_value in evaluator.enum_values() {
+                if let Some(Expression {
+                    kind: ExpressionKind::Literal(_),
+                    ..
+                }) = enum_value.member.value
+                {
+                    continue;
+                }
+                let Some(value) = enum_value.value else {
+                    continue;
+                };
+                let Ok(range) = file.file.byte_span_to_range(enum_value.member.name.span) else {
+                    continue;
+                };
+                hints.push(InlayHint {
+                    position: range.end.into(),
+                    label: InlayHintLabel::String(format!("= {value}")),
+                    kind: None,
+                    text_edits: None,
+                    tooltip: None,
+                    padding_left: Some(true),
+                    padding_right: None,
+                    data: None,
+                });
+            }
+
+            // Struct fields show their offset at the end of the line declaring them
+            for layout in evaluator.structs() {
+                hints.extend(layout.fields.iter().filter_map(|field| {
+                    Some(InlayHint {
+                        position: line_end(&file.file, field.name.span)?.into(),
+                        label: InlayHintLabel::String(format!("offset {}", field.offset?)),
+                        kind: None,
+                        text_edits: None,
+                        tooltip: None,
+                        padding_left: Some(true),
+                        padding_right: None,
+                        data: None,
+                    })
+                }));
+            }
+
+            // Operands naming a constant show what it evaluates to, when that can be worked out
+            let operands = if constant_values {
+                SymbolicOperandCollector::find_operands(&file.ast)
+            } else {
+                vec![]
+            };
+            for operand in operands {
+                let scope = ScopeAnalyzer::search(&file.scopes, operand.span.start);
+                let Some(value) = evaluator.evaluate_in(&operand, &scope[1..]) else {
+                    continue;
+                };
+                let Ok(range) = file.file.byte_span_to_range(operand.span) else {
+                    continue;
+                };
+                hints.push(InlayHint {
+                    position: range.end.into(),
+                    label: InlayHintLabel::String(format!("= {}", format_constant(value))),
+                    kind: None,
+                    text_edits: None,
+                    tooltip: None,
+                    padding_left: Some(true),
+                    padding_right: None,
+                    data: None,
+                });
+            }
+
+            hints.sort_by_key(|hint| (hint.position.line, hint.position.character));
+            Ok(Some(hints))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Offers to create the workspace config, unless there already is one
+fn create_config_action(workspace_folder: Option<&Uri>) -> Option<CodeActionOrCommand> {
+    let config_path = Url::from_str(workspace_folder?.as_str())
+        .ok()?
+        .to_file_path()
+        .ok()?
+        .join("ca65.toml");
+    if config_path.exists() {
+        return None;
+    }
+    let config_uri = Uri::from_str(Url::from_file_path(&config_path).ok()?.as_str()).ok()?;
+
+    // Once created, the file watcher registered in `initialized` picks up the new config
+    Some(CodeActionOrCommand::CodeAction(CodeAction {
+        title: "Create ca65.toml workspace config".to_string(),
+        edit: Some(WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: config_uri.clone(),
+                    annotation_id: None,
+                    options: Some(CreateFileOptions {
+                        overwrite: Some(false),
+                        ignore_if_exists: Some(true),
+                    }),
+                })),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: config_uri,
+                        version: None,
+                    },
+                    edits: vec![OneOf::Left(TextEdit::new(
+                        Default::default(),
+                        "[toolchain]\n# cc65 = \"/path/to/cc65/bin\"\n".to_owned(),
+                    ))],
+                }),
+            ])),
+            ..Default::default()
+        }),
+        kind: Some(CodeActionKind::SOURCE),
+        ..Default::default()
+    }))
+}
+
+// Offers to point each `.include` in `span` that doesn't resolve at an indexed file with a similar
+// name, or to create the file it names
+fn include_fixes(files: &Files, id: FileId, span: Span) -> Vec<CodeActionOrCommand> {
+    let cache_file = files.get(id);
+    let uri = files.get_uri(id);
+    let mut actions = vec![];
+
+    for include in cache_file.includes.iter() {
+        let token = &include.path;
+        if token.span.start > span.end || token.span.end < span.start {
+            continue;
+        }
+        let Some(path) = token
+            .lexeme
+            .strip_prefix('"')
+            .and_then(|lexeme| lexeme.strip_suffix('"'))
+        else {
+            continue;
+        };
+        if files.resolve_import(id, path).is_ok() {
+            continue;
+        }
+        let Ok(range) = cache_file.file.byte_span_to_range(token.span) else {
+            continue;
+        };
+        let range = range.into();
+
+        for candidate in files.find_similar_includes(id, path) {
+            let edit = TextEdit::new(range, format!("\"{candidate}\""));
+            let changes = HashMap::from([(uri.clone(), vec![edit])]);
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Change include to \"{candidate}\""),
+                edit: Some(WorkspaceEdit::new(changes)),
+                kind: Some(CodeActionKind::QUICKFIX),
+                ..Default::default()
+            }));
+        }
+
+        if let Ok(include_uri) = files.resolve_include_uri(id, path) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Create {path}"),
+                edit: Some(WorkspaceEdit {
+                    document_changes: Some(DocumentChanges::Operations(vec![
+                        DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                            uri: include_uri,
+                            annotation_id: None,
+                            options: Some(CreateFileOptions {
+                                overwrite: Some(false),
+                                ignore_if_exists: Some(true),
+                            }),
+                        })),
+                    ])),
+                    ..Default::default()
+                }),
+                kind: Some(CodeActionKind::QUICKFIX),
+                ..Default::default()
+            }));
+        }
+    }
+
+    actions
+}
+
+fn scope_to_folding_range(file: &File, scope: &Scope) -> Vec<FoldingRange> {
+    // Single-line scopes, like `.define`s with parameters, have nothing to fold
+    let mut results = Vec::from_iter(span_to_folding_range(file, scope.span));
+
+    results.extend(
+        scope
+            .children
+            .iter()
+            .flat_map(|scope| scope_to_folding_range(file, scope)),
+    );
+
+    results
+}
+
+fn symbol_kind(sym_type: SymbolType) -> SymbolKind {
+    match sym_type {
+        SymbolType::Label => SymbolKind::FUNCTION,
+        SymbolType::Constant | SymbolType::Define => SymbolKind::CONSTANT,
+        SymbolType::Macro => SymbolKind::OPERATOR,
+        SymbolType::Scope => SymbolKind::NAMESPACE,
+        SymbolType::Parameter => SymbolKind::VARIABLE,
+    }
+}
+
+fn symbol_to_call_hierarchy_item(state: &State, symbol: &Symbol) -> Option<CallHierarchyItem> {
+    let range = state
+        .files
+        .get(symbol.file_id)
+        .file
+        .byte_span_to_range(symbol.span)
+        .ok()?
+        .into();
+
+    Some(CallHierarchyItem {
+        name: symbol
+            .fqn
+            .rsplit_once("::")
+            .map_or(symbol.fqn.clone(), |(_, name)| name.to_string()),
+        kind: symbol_kind(symbol.sym_type),
+        tags: None,
+        detail: Some(symbol.comment.clone()),
+        uri: state.files.get_uri(symbol.file_id),
+        range,
+        selection_range: range,
+        data: Some(serde_json::Value::String(symbol.fqn.clone())),
+    })
+}
+
+// The file an item came from, along with the qualified name stashed in its data
+fn call_hierarchy_item_symbol(state: &State, item: &CallHierarchyItem) -> Option<(FileId, String)> {
+    let id = *state.files.sources.get(&item.uri)?;
+    let fqn = item.data.as_ref()?.as_str()?.to_string();
+
+    Some((id, fqn))
+}
+
+// Merges the call sites of each symbol into one item with all of their ranges
+fn group_calls(
+    state: &State,
+    calls: impl Iterator<Item = (Symbol, FileId, Span)>,
+) -> Vec<(CallHierarchyItem, Vec<Range>)> {
+    let mut groups: Vec<(Symbol, Vec<Range>)> = vec![];
+    for (symbol, file, span) in calls {
+        let Ok(range) = state.files.get(file).file.byte_span_to_range(span) else {
+            continue;
+        };
+        match groups.iter_mut().find(|(group, _)| group.fqn == symbol.fqn) {
+            Some((_, ranges)) => ranges.push(range),
+            None => groups.push((symbol, vec![range])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|(symbol, ranges)| {
+            Some((symbol_to_call_hierarchy_item(state, &symbol)?, ranges))
         })
-    } else {
-        None
+        .collect()
+}
+
+// Nests the spans from largest to smallest, dropping any that don't fit inside the one before
+fn spans_to_selection_range(file: &File, mut spans: Vec<Span>) -> Option<SelectionRange> {
+    spans.sort_by_key(|span| std::cmp::Reverse(span.end - span.start));
+
+    let mut selection: Option<SelectionRange> = None;
+    let mut outer: Option<Span> = None;
+    for span in spans {
+        if outer
+            .is_some_and(|outer| span == outer || span.start < outer.start || span.end > outer.end)
+        {
+            continue;
+        }
+        selection = Some(SelectionRange {
+            range: file.byte_span_to_range(span).ok()?.into(),
+            parent: selection.map(Box::new),
+        });
+        outer = Some(span);
+    }
+
+    selection
+}
+
+fn span_to_folding_range(file: &File, span: Span) -> Option<FoldingRange> {
+    let range = file.byte_span_to_range(span).ok()?;
+    let end_line = range.end.line.saturating_sub(1);
+    if end_line <= range.start.line {
+        return None;
+    }
+
+    Some(FoldingRange {
+        start_line: range.start.line as u32,
+        start_character: None,
+        end_line: end_line as u32,
+        end_character: None,
+        kind: Some(FoldingRangeKind::Region),
+        collapsed_text: None,
+    })
+}
+
+// The name the identifier at `index` is written as, along with the scopes qualifying it, so `bar`
+// in `Foo::bar` resolves inside `Foo`
+fn qualified_name(tokens: &[Token], index: usize) -> String {
+    let is = |index: usize, token_type: TokenType| tokens[index].token_type == token_type;
+    let mut start = index;
+    while start >= 2
+        && is(start - 1, TokenType::ScopeSeparator)
+        && is(start - 2, TokenType::Identifier)
+    {
+        start -= 2;
+    }
+    if start >= 1 && is(start - 1, TokenType::ScopeSeparator) {
+        start -= 1;
+    }
+
+    tokens[start..=index]
+        .iter()
+        .map(|token| token.lexeme.as_str())
+        .collect()
+}
+
+// The end of the line containing `span`, ignoring trailing whitespace
+fn line_end(file: &File, span: Span) -> Option<Position> {
+    let line = file.byte_index_to_position(span.start).ok()?.line;
+    let line_span = file.get_line(line).ok()?;
+    let source = file.get_line_source(line_span).ok()?;
+
+    file.byte_index_to_position(line_span.start + source.trim_end().len())
+        .ok()
+}
+
+fn scope_to_inlay_hint(file: &File, scope: &Scope) -> Vec<InlayHint> {
+    if scope.is_invisible() {
+        return scope
+            .children
+            .iter()
+            .flat_map(|scope| scope_to_inlay_hint(file, scope))
+            .collect();
+    }
+
+    if let Ok(range) = file.byte_span_to_range(scope.span) {
+        let mut results = vec![InlayHint {
+            position: range.end.into(),
+            label: InlayHintLabel::String(scope.name.clone()),
+            kind: None,
+            text_edits: None,
+            tooltip: None,
+            padding_left: Some(true),
+            padding_right: None,
+            data: None,
+        }];
+
+        results.extend(
+            scope
+                .children
+                .iter()
+                .flat_map(|scope| scope_to_inlay_hint(file, scope)),
+        );
+
+        results
+    } else {
+        Vec::new()
+    }
+}
+
+// Matches when every query character appears in order, ignoring case
+fn fuzzy_match(query: &str, candidate: &str) -> bool {
+    let mut candidate = candidate.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .all(|wanted| candidate.any(|found| found == wanted))
+}
+
+fn is_register(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "a" | "x" | "y" | "s" | "z")
+}
+
+// Whether `name` reads back as exactly one symbol, so it can replace one
+fn is_symbol_name(name: &str) -> bool {
+    let tokens = parser::Tokenizer::new(name, &INSTRUCTIONS).parse();
+    matches!(
+        tokens.as_deref(),
+        Ok([token]) if token.token_type == TokenType::Identifier && token.lexeme == name
+    ) && !is_register(name)
+}
+
+// Negative values read better in decimal than as a 64-bit two's complement in hex
+fn format_constant(value: i64) -> String {
+    if value < 0 {
+        value.to_string()
+    } else {
+        format!("${value:X}")
+    }
+}
+
+fn number_to_markdown(value: u32) -> String {
+    let (size, width) = match value {
+        0..=0xff => ("byte", 2),
+        0x100..=0xffff => ("word", 4),
+        0x10000..=0xffffff => ("far", 6),
+        _ => ("dword", 8),
+    };
+
+    format!(
+        "| Decimal | Hex | Binary | Size |\n|---|---|---|---|\n| {value} | ${value:0hex$X} | %{value:0bin$b} | {size} |",
+        hex = width,
+        bin = width * 4,
+    )
+}
+
+fn scope_to_symbol(scope: &Scope, file: &CacheFile) -> Option<DocumentSymbol> {
+    if scope.is_invisible() {
+        return None;
+    }
+
+    if let Ok(range) = file.file.byte_span_to_range(scope.span) {
+        let range = range.into();
+        Some(DocumentSymbol {
+            name: scope.name.clone(),
+            detail: None,
+            kind: SymbolKind::NAMESPACE,
+            tags: None,
+            deprecated: None,
+            range,
+            selection_range: range,
+            children: {
+                let children: Vec<DocumentSymbol> = scope
+                    .children
+                    .iter()
+                    .filter_map(|child| scope_to_symbol(child, file))
+                    .collect();
+                if children.is_empty() {
+                    None
+                } else {
+                    Some(children)
+                }
+            },
+        })
+    } else {
+        None
+    }
+}
+
+// Shows the symbol's definition line and its documentation comment, along with the value of
+// constants that can be evaluated
+fn symbol_documentation(state: &State, symbol: &Symbol) -> String {
+    let mut doc = format!("```ca65\n{}\n```", symbol.comment.clone());
+    if matches!(symbol.sym_type, SymbolType::Constant) {
+        let evaluator = ConstantEvaluator::new(&state.files.get(symbol.file_id).ast);
+        if let Some(value) = evaluator.evaluate_constant(&symbol.fqn) {
+            doc.push_str(&format!("\n\nValue: `{value}`"));
+            if value >= 0 {
+                doc.push_str(&format!(" (`${value:X}`)"));
+            }
+        }
+    }
+    if let Some(documentation) = &symbol.documentation {
+        doc.push_str(&format!("\n\n{documentation}"));
+    }
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{load, load_on_disk, uri};
+    use std::fs;
+    use tower_lsp_server::LspService;
+    use tower_lsp_server::jsonrpc::ErrorCode;
+    use tower_lsp_server::lsp_types::{
+        self, ReferenceContext, TextDocumentContentChangeEvent, TextDocumentIdentifier,
+        VersionedTextDocumentIdentifier,
+    };
+
+    fn at(name: &str, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri(name) },
+            position: lsp_types::Position::new(line, character),
+        }
+    }
+
+    fn rename_params(name: &str, line: u32, character: u32, new_name: &str) -> RenameParams {
+        RenameParams {
+            text_document_position: at(name, line, character),
+            new_name: new_name.to_string(),
+            work_done_progress_params: Default::default(),
+        }
+    }
+
+    fn add_file(files: &mut Files, uri: &str, source: &str) -> FileId {
+        let uri = Uri::from_str(uri).unwrap();
+        let id = files.add(uri.clone(), source.to_string());
+        files.sources.insert(uri, id);
+        id
+    }
+
+    #[test]
+    fn ca65_messages_are_routed_to_the_file_they_name() {
+        let mut files = Files::new();
+        let main = add_file(
+            &mut files,
+            "file:///project/main.s",
+            ".include \"inc/macros.inc\"\nlda #$100\n",
+        );
+        let include = add_file(&mut files, "file:///project/inc/macros.inc", "foo bar\n");
+
+        let stderr = "main.s:2: Error: Range error (256 not in [0..255])\n\
+                      inc/macros.inc:1: Warning: Unknown instruction\n\
+                      /elsewhere/other.s:1: Error: Not part of the workspace\n\
+                      garbage\n";
+        let diagnostics = make_diagnostics_from_ca65_output(&files, main, stderr);
+
+        assert_eq!(diagnostics.len(), 2);
+        let main_diagnostics = &diagnostics[&main];
+        assert_eq!(main_diagnostics.len(), 1);
+        assert_eq!(main_diagnostics[0].range.start.line, 1);
+        assert_eq!(
+            main_diagnostics[0].severity,
+            Some(DiagnosticSeverity::ERROR)
+        );
+        assert_eq!(
+            main_diagnostics[0].message,
+            "Range error (256 not in [0..255])"
+        );
+
+        let include_diagnostics = &diagnostics[&include];
+        assert_eq!(include_diagnostics.len(), 1);
+        assert_eq!(include_diagnostics[0].range.start.line, 0);
+        assert_eq!(
+            include_diagnostics[0].severity,
+            Some(DiagnosticSeverity::WARNING)
+        );
+    }
+
+    #[test]
+    fn ca65_messages_match_percent_encoded_paths() {
+        let mut files = Files::new();
+        let main = add_file(&mut files, "file:///my%20project/main.s", "nop\n");
+
+        let diagnostics = make_diagnostics_from_ca65_output(
+            &files,
+            main,
+            "/my project/main.s:1: Error: Something went wrong\n",
+        );
+
+        assert_eq!(diagnostics[&main].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rename_only_replaces_the_last_segment_of_qualified_names() {
+        let (service, _) =
+            load(&[("main.s", ".scope Foo\nbar: rts\n.endscope\njsr Foo::bar\n")]).await;
+
+        let edit = service
+            .inner()
+            .rename(rename_params("main.s", 1, 0, "baz"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut edits = edit.changes.unwrap().remove(&uri("main.s")).unwrap();
+        edits.sort_by_key(|edit| edit.range.start.line);
+        let ranges = edits
+            .iter()
+            .map(|edit| {
+                assert_eq!(edit.new_text, "baz");
+                (
+                    edit.range.start.line,
+                    edit.range.start.character,
+                    edit.range.end.character,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1, 0, 3), (3, 9, 12)]);
+    }
+
+    #[tokio::test]
+    async fn rename_rejects_names_taken_in_any_unit() {
+        let (service, _) = load(&[
+            ("a.s", ".include \"shared.inc\"\n"),
+            ("b.s", ".include \"shared.inc\"\ntaken: rts\n"),
+            ("shared.inc", "helper: rts\n"),
+        ])
+        .await;
+
+        let error = service
+            .inner()
+            .rename(rename_params("shared.inc", 0, 0, "taken"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.code, ErrorCode::InvalidParams);
+    }
+
+    #[tokio::test]
+    async fn rename_rejects_names_that_are_not_a_single_symbol() {
+        let (service, _) = load(&[("main.s", "foo: rts\n")]).await;
+
+        for new_name in ["x", "lda", "1st", "two words", "Foo::bar", ""] {
+            let error = service
+                .inner()
+                .rename(rename_params("main.s", 0, 0, new_name))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidParams, "{new_name:?}");
+        }
+    }
+
+    async fn inlay_hint_labels(service: &LspService<Asm>, name: &str) -> Option<Vec<String>> {
+        let hints = service
+            .inner()
+            .inlay_hint(InlayHintParams {
+                work_done_progress_params: Default::default(),
+                text_document: TextDocumentIdentifier { uri: uri(name) },
+                range: lsp_types::Range::new(
+                    lsp_types::Position::new(0, 0),
+                    lsp_types::Position::new(u32::MAX, 0),
+                ),
+            })
+            .await
+            .unwrap()?;
+
+        Some(
+            hints
+                .into_iter()
+                .map(|hint| match hint.label {
+                    InlayHintLabel::String(label) => label,
+                    InlayHintLabel::LabelParts(_) => panic!("expected a plain label"),
+                })
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn only_operand_hints_follow_the_constant_values_setting() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".enum\nFIRST\nSECOND\n.endenum\n.struct Point\nxpos .byte\nypos .word\n.endstruct\nlda #SECOND\n",
+        )])
+        .await;
+
+        assert_eq!(
+            inlay_hint_labels(&service, "main.s").await.unwrap(),
+            vec!["= 0", "= 1", "offset 0", "offset 1", "Point"]
+        );
+
+        service
+            .inner()
+            .configuration
+            .lock()
+            .await
+            .inlay_hints
+            .constant_values = true;
+        assert_eq!(
+            inlay_hint_labels(&service, "main.s").await.unwrap(),
+            vec!["= 0", "= 1", "offset 0", "offset 1", "Point", "= $1"]
+        );
+    }
+
+    #[test]
+    fn formats_negative_constants_in_decimal() {
+        assert_eq!(format_constant(0xff), "$FF");
+        assert_eq!(format_constant(0), "$0");
+        assert_eq!(format_constant(-1), "-1");
+    }
+
+    #[tokio::test]
+    async fn operands_naming_constants_show_their_value() {
+        let (service, _) = load(&[(
+            "main.s",
+            "SCREEN = $2000\nOFFSET = -1\nlda SCREEN\nldx #OFFSET\nlda unknown\n.word SCREEN\n",
+        )])
+        .await;
+        service
+            .inner()
+            .configuration
+            .lock()
+            .await
+            .inlay_hints
+            .constant_values = true;
+
+        assert_eq!(
+            inlay_hint_labels(&service, "main.s").await.unwrap(),
+            vec!["= $2000", "= -1", "= $2000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn references_include_uses_in_the_including_file() {
+        let (service, _) = load(&[
+            ("main.s", ".include \"lib.inc\"\njsr helper\njmp helper\n"),
+            ("lib.inc", "helper: rts\n"),
+        ])
+        .await;
+
+        let locations = service
+            .inner()
+            .references(ReferenceParams {
+                text_document_position: at("lib.inc", 0, 0),
+                context: ReferenceContext {
+                    include_declaration: false,
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let locations = locations
+            .iter()
+            .map(|location| (location.uri.clone(), location.range.start.line))
+            .collect::<Vec<_>>();
+        assert_eq!(locations, vec![(uri("main.s"), 1), (uri("main.s"), 2)]);
+    }
+
+    #[tokio::test]
+    async fn prepare_rename_only_accepts_symbols() {
+        let (service, _) = load(&[("main.s", "buffer: nop\nlda buffer,x\n")]).await;
+        let server = service.inner();
+
+        // The `x` register after the comma
+        assert_eq!(
+            server.prepare_rename(at("main.s", 1, 11)).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            server.prepare_rename(at("main.s", 1, 0)).await.unwrap(),
+            None
+        );
+        match server.prepare_rename(at("main.s", 1, 4)).await.unwrap() {
+            Some(PrepareRenameResponse::Range(range)) => {
+                assert_eq!(
+                    (range.start, range.end),
+                    (
+                        lsp_types::Position::new(1, 4),
+                        lsp_types::Position::new(1, 10)
+                    )
+                );
+            }
+            other => panic!("expected the range of `buffer`, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn document_highlight_marks_the_definition_and_each_use() {
+        let (service, _) = load(&[("main.s", "loop: dex\nbne loop\njmp loop\n.word loop\n")]).await;
+
+        let highlights = service
+            .inner()
+            .document_highlight(DocumentHighlightParams {
+                text_document_position_params: at("main.s", 1, 4),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let kinds = highlights
+            .iter()
+            .map(|highlight| (highlight.range.start.line, highlight.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, Some(DocumentHighlightKind::WRITE)),
+                (1, Some(DocumentHighlightKind::READ)),
+                (2, Some(DocumentHighlightKind::READ)),
+                (3, Some(DocumentHighlightKind::READ)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn signature_help_tracks_the_active_macro_parameter() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".macro poke addr, value\n.endmacro\npoke $2000, 1\n",
+        )])
+        .await;
+
+        let help = service
+            .inner()
+            .signature_help(SignatureHelpParams {
+                context: None,
+                text_document_position_params: at("main.s", 2, 12),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(help.active_parameter, Some(1));
+        let signature = &help.signatures[0];
+        assert_eq!(signature.label, ".macro poke addr, value");
+        let parameters = signature
+            .parameters
+            .iter()
+            .flatten()
+            .map(|parameter| match &parameter.label {
+                ParameterLabel::Simple(label) => label.as_str(),
+                other => panic!("expected a simple label, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parameters, ["addr", "value"]);
+    }
+
+    #[tokio::test]
+    async fn completion_documentation_is_resolved_on_demand() {
+        let (service, _) = load(&[(
+            "main.s",
+            "; Clears the screen\nclear_screen: rts\njsr clear\n",
+        )])
+        .await;
+        let server = service.inner();
+
+        let Some(CompletionResponse::Array(items)) = server
+            .completion(CompletionParams {
+                text_document_position: at("main.s", 2, 9),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected a completion list");
+        };
+        let item = items
+            .into_iter()
+            .find(|item| item.label == "clear_screen")
+            .unwrap();
+        assert_eq!(item.documentation, None);
+
+        let item = server.completion_resolve(item).await.unwrap();
+        match item.documentation {
+            Some(Documentation::MarkupContent(content)) => {
+                assert!(
+                    content.value.contains("Clears the screen"),
+                    "{}",
+                    content.value
+                );
+            }
+            other => panic!("expected markdown documentation, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn document_links_point_at_included_files() {
+        let (service, _) = load(&[
+            ("main.s", ".include \"inc/defs.inc\"\n"),
+            ("inc/defs.inc", "SCREEN = $0400\n"),
+        ])
+        .await;
+
+        let links = service
+            .inner()
+            .document_link(DocumentLinkParams {
+                text_document: TextDocumentIdentifier { uri: uri("main.s") },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].target, Some(uri("inc/defs.inc")));
+        assert_eq!(
+            (links[0].range.start, links[0].range.end),
+            (
+                lsp_types::Position::new(0, 9),
+                lsp_types::Position::new(0, 23)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn hover_shows_the_comment_block_above_a_constant() {
+        let (service, _) = load(&[(
+            "main.s",
+            "; Base of screen memory\n; Cleared on reset\nSCREEN = $0400\nlda SCREEN\n",
+        )])
+        .await;
+
+        let hover = service
+            .inner()
+            .hover(HoverParams {
+                text_document_position_params: at("main.s", 3, 5),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let HoverContents::Scalar(MarkedString::String(content)) = hover.contents else {
+            panic!("expected a string, got {:?}", hover.contents);
+        };
+        assert!(
+            content.ends_with("Base of screen memory\nCleared on reset"),
+            "{content}"
+        );
+    }
+
+    #[tokio::test]
+    async fn folding_ranges_cover_macros_and_conditionals() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".macro clear\nlda #0\n.endmacro\n.if DEBUG\nbrk\n.else\nnop\n.endif\n",
+        )])
+        .await;
+
+        let ranges = service
+            .inner()
+            .folding_range(FoldingRangeParams {
+                text_document: TextDocumentIdentifier { uri: uri("main.s") },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut lines = ranges
+            .iter()
+            .map(|range| (range.start_line, range.end_line))
+            .collect::<Vec<_>>();
+        lines.sort();
+        // Folding stops before the closing directive so it stays visible
+        assert_eq!(lines, vec![(0, 1), (3, 6)]);
+    }
+
+    #[tokio::test]
+    async fn missing_includes_offer_similarly_named_files() {
+        let (service, _) = load(&[
+            ("main.s", ".include \"utils.s\"\n"),
+            ("utils.inc", "helper: rts\n"),
+        ])
+        .await;
+
+        let actions = service
+            .inner()
+            .code_action(CodeActionParams {
+                text_document: TextDocumentIdentifier { uri: uri("main.s") },
+                range: lsp_types::Range::new(
+                    lsp_types::Position::new(0, 10),
+                    lsp_types::Position::new(0, 10),
+                ),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let CodeActionOrCommand::CodeAction(action) = &actions[0] else {
+            panic!("expected a code action, got {:?}", actions[0]);
+        };
+        assert_eq!(action.title, "Change include to \"utils.inc\"");
+        let edits = &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri("main.s")];
+        assert_eq!(edits[0].new_text, "\"utils.inc\"");
+        assert_eq!(
+            (edits[0].range.start, edits[0].range.end),
+            (
+                lsp_types::Position::new(0, 9),
+                lsp_types::Position::new(0, 18)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn hover_evaluates_the_constant_in_scope() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".scope A\nSIZE = 4\n.endscope\n.scope B\nSIZE = 8\nlda #SIZE\n.endscope\n",
+        )])
+        .await;
+
+        let hover = service
+            .inner()
+            .hover(HoverParams {
+                text_document_position_params: at("main.s", 5, 6),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let HoverContents::Scalar(MarkedString::String(content)) = hover.contents else {
+            panic!("expected a string, got {:?}", hover.contents);
+        };
+        assert!(content.contains("Value: `8`"), "{content}");
+    }
+
+    /// Writes a stand-in for ca65 that reports `message` whatever it's asked to assemble.
+    #[cfg(unix)]
+    fn stub_ca65(directory: &Path, message: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = directory.join("ca65");
+        fs::write(&path, format!("#!/bin/sh\necho '{message}' >&2\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn saving_an_include_assembles_the_units_including_it() {
+        let directory = tempfile::tempdir().unwrap();
+        let ca65 = stub_ca65(directory.path(), "lib.inc:1: Error: Boom");
+        let (service, ids) = load_on_disk(
+            directory.path(),
+            &[("main.s", ".include \"lib.inc\"\n"), ("lib.inc", "nop\n")],
+        )
+        .await;
+        let state = service.inner().state();
+
+        let assemblies = Assembly::for_file(&*state.lock().await, ids[1]);
+        assert_eq!(
+            assemblies
+                .iter()
+                .map(|assembly| assembly.root)
+                .collect::<Vec<_>>(),
+            vec![ids[0]]
+        );
+        for assembly in assemblies {
+            assembly.run(state.clone(), ca65.clone()).await;
+        }
+
+        let state = state.lock().await;
+        assert_eq!(state.toolchain_diagnostics(ids[0]).unwrap().len(), 0);
+        assert_eq!(
+            state.toolchain_diagnostics(ids[1]).unwrap()[0].message,
+            "Boom"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ca65_results_are_dropped_when_a_file_changed_meanwhile() {
+        let directory = tempfile::tempdir().unwrap();
+        let ca65 = stub_ca65(directory.path(), "main.s:1: Error: Boom");
+        let (service, ids) = load_on_disk(directory.path(), &[("main.s", "nop\n")]).await;
+        let state = service.inner().state();
+
+        let assemblies = Assembly::for_file(&*state.lock().await, ids[0]);
+        let uri = state.lock().await.files.get_uri(ids[0]);
+        state.lock().await.reload_source(
+            &VersionedTextDocumentIdentifier { uri, version: 2 },
+            vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "\nnop\n".to_string(),
+            }],
+        );
+        for assembly in assemblies {
+            assembly.run(state.clone(), ca65.clone()).await;
+        }
+
+        assert_eq!(state.lock().await.toolchain_diagnostics(ids[0]), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ca65_checks_files_once_typing_pauses() {
+        let directory = tempfile::tempdir().unwrap();
+        stub_ca65(directory.path(), "main.s:1: Error: Illegal opcode");
+        let (service, ids) = load_on_disk(directory.path(), &[("main.s", "bad opcode\n")]).await;
+        service.inner().configuration.lock().await.toolchain.cc65 =
+            Some(directory.path().to_string_lossy().to_string());
+
+        service.inner().index(ids[0]).await;
+        let state = service.inner().state();
+        assert_eq!(state.lock().await.toolchain_diagnostics(ids[0]), None);
+
+        let mut diagnostics = None;
+        for _ in 0..50 {
+            tokio::time::sleep(ASSEMBLY_DEBOUNCE / 5).await;
+            diagnostics = state.lock().await.toolchain_diagnostics(ids[0]).cloned();
+            if diagnostics.is_some() {
+                break;
+            }
+        }
+        let diagnostics = diagnostics.expect("ca65 never reported back");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].source.as_deref(), Some("ca65"));
+        assert_eq!(diagnostics[0].message, "Illegal opcode");
+    }
+
+    async fn negotiated_encoding(
+        offered: Option<Vec<PositionEncodingKind>>,
+    ) -> (Option<PositionEncodingKind>, PositionEncoding) {
+        let (service, _) = load(&[]).await;
+        let result = service
+            .inner()
+            .initialize(InitializeParams {
+                capabilities: lsp_types::ClientCapabilities {
+                    general: Some(lsp_types::GeneralClientCapabilities {
+                        position_encodings: offered,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let encoding = service.inner().state().lock().await.position_encoding;
+        (result.capabilities.position_encoding, encoding)
+    }
+
+    #[tokio::test]
+    async fn utf8_is_chosen_only_when_the_client_offers_it() {
+        assert_eq!(
+            negotiated_encoding(Some(vec![
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ]))
+            .await,
+            (Some(PositionEncodingKind::UTF8), PositionEncoding::Utf8)
+        );
+        assert_eq!(
+            negotiated_encoding(Some(vec![PositionEncodingKind::UTF16])).await,
+            (Some(PositionEncodingKind::UTF16), PositionEncoding::Utf16)
+        );
+        assert_eq!(
+            negotiated_encoding(None).await,
+            (Some(PositionEncodingKind::UTF16), PositionEncoding::Utf16)
+        );
+    }
+
+    #[tokio::test]
+    async fn editing_an_include_only_relints_the_files_related_to_it() {
+        let (service, ids) = load(&[
+            ("a.s", ".include \"shared.inc\"\n"),
+            ("b.s", ".include \"shared.inc\"\n"),
+            ("shared.inc", "helper: rts\n"),
+            ("c.s", ".include \"other.inc\"\n"),
+            ("other.inc", "other: rts\n"),
+        ])
+        .await;
+
+        // Anything republished loses this marker
+        let marker = Diagnostic {
+            message: "stale".to_string(),
+            ..Default::default()
+        };
+        let mut state = service.inner().state().lock().await;
+        for id in ids.iter() {
+            state.publish_diagnostics(*id, vec![marker.clone()]).await;
+        }
+        drop(state);
+
+        service.inner().index(ids[2]).await;
+
+        let state = service.inner().state().lock().await;
+        let relinted = ids
+            .iter()
+            .map(|id| !state.diagnostics(*id).unwrap().contains(&marker))
+            .collect::<Vec<_>>();
+        assert_eq!(relinted, vec![true, true, true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn rename_reaches_constants_across_included_files() {
+        let (service, _) = load(&[
+            ("main.s", ".include \"consts.inc\"\n.include \"code.inc\"\n"),
+            ("consts.inc", "WIDTH = 32\n"),
+            ("code.inc", "lda #WIDTH\nldx #WIDTH - 1\n"),
+        ])
+        .await;
+
+        let mut edited = service
+            .inner()
+            .rename(rename_params("code.inc", 0, 6, "COLUMNS"))
+            .await
+            .unwrap()
+            .unwrap()
+            .changes
+            .unwrap()
+            .into_iter()
+            .flat_map(|(uri, edits)| {
+                let name = uri.as_str().rsplit('/').next().unwrap().to_string();
+                edits.into_iter().map(move |edit| {
+                    assert_eq!(edit.new_text, "COLUMNS");
+                    (
+                        name.clone(),
+                        edit.range.start.line,
+                        edit.range.start.character,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        edited.sort();
+        assert_eq!(
+            edited,
+            vec![
+                ("code.inc".to_string(), 0, 5),
+                ("code.inc".to_string(), 1, 5),
+                ("consts.inc".to_string(), 0, 0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_cheap_locals_cheap() {
+        let (service, _) = load(&[("main.s", "start:\n@loop: dex\nbne @loop\n")]).await;
+
+        for (line, new_name) in [(1, "again"), (0, "@start")] {
+            let error = service
+                .inner()
+                .rename(rename_params("main.s", line, 0, new_name))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidParams, "{new_name:?}");
+        }
+
+        let edits = service
+            .inner()
+            .rename(rename_params("main.s", 1, 0, "@again"))
+            .await
+            .unwrap()
+            .unwrap()
+            .changes
+            .unwrap()
+            .remove(&uri("main.s"))
+            .unwrap();
+        assert_eq!(edits.len(), 2);
+    }
+
+    #[test]
+    fn the_config_action_is_offered_until_the_config_exists() {
+        let parent = tempfile::tempdir().unwrap();
+        let workspace = parent.path().join("my project");
+        fs::create_dir(&workspace).unwrap();
+        let workspace_uri =
+            Uri::from_str(Url::from_file_path(&workspace).unwrap().as_str()).unwrap();
+        assert!(workspace_uri.as_str().contains("my%20project"));
+
+        let Some(CodeActionOrCommand::CodeAction(action)) =
+            create_config_action(Some(&workspace_uri))
+        else {
+            panic!("expected the config action");
+        };
+        assert_eq!(action.kind, Some(CodeActionKind::SOURCE));
+        let Some(DocumentChanges::Operations(operations)) = action.edit.unwrap().document_changes
+        else {
+            panic!("expected file operations");
+        };
+        let DocumentChangeOperation::Op(ResourceOp::Create(create)) = &operations[0] else {
+            panic!("expected the config to be created first");
+        };
+        assert_eq!(
+            Url::from_str(create.uri.as_str())
+                .unwrap()
+                .to_file_path()
+                .unwrap(),
+            workspace.join("ca65.toml")
+        );
+
+        fs::write(workspace.join("ca65.toml"), "").unwrap();
+        assert!(create_config_action(Some(&workspace_uri)).is_none());
+    }
+
+    #[tokio::test]
+    async fn reloading_the_config_relints_every_file() {
+        let (service, ids) = load(&[("main.s", "phx\n")]).await;
+        service.inner().index(ids[0]).await;
+        let state = service.inner().state();
+        assert_eq!(state.lock().await.diagnostics(ids[0]).unwrap().len(), 1);
+
+        let directory = tempfile::tempdir().unwrap();
+        let config = directory.path().join("ca65.toml");
+        fs::write(&config, "[lsp]\nmachine = \"snes\"\n").unwrap();
+        service.inner().load_config(&config).await.unwrap();
+
+        assert_eq!(state.lock().await.diagnostics(ids[0]).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn enum_members_show_their_computed_values() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".enum Color\nRed\nGreen = 4\nBlue\nCyan = Blue + 2\nMagenta\n.endenum\n",
+        )])
+        .await;
+
+        assert_eq!(
+            inlay_hint_labels(&service, "main.s").await.unwrap(),
+            vec!["= 0", "= 5", "= 7", "= 8", "Color"]
+        );
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_resolve_qualified_names() {
+        let (service, _) = load(&[(
+            "main.s",
+            ".scope Foo\nbar: rts\n.endscope\njmp Foo::bar ; go\nlda #1\n",
+        )])
+        .await;
+
+        let Some(SemanticTokensResult::Tokens(tokens)) = service
+            .inner()
+            .semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier { uri: uri("main.s") },
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected semantic tokens");
+        };
+        let deltas = tokens
+            .data
+            .iter()
+            .map(|token| {
+                (
+                    token.delta_line,
+                    token.delta_start,
+                    token.length,
+                    SEMANTIC_TOKEN_TYPES[token.token_type as usize].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            deltas,
+            vec![
+                (0, 0, 6, SemanticTokenType::MACRO),
+                (0, 7, 3, SemanticTokenType::NAMESPACE),
+                (1, 0, 3, SemanticTokenType::FUNCTION),
+                (0, 5, 3, SemanticTokenType::KEYWORD),
+                (1, 0, 9, SemanticTokenType::MACRO),
+                (1, 0, 3, SemanticTokenType::KEYWORD),
+                (0, 4, 3, SemanticTokenType::NAMESPACE),
+                (0, 5, 3, SemanticTokenType::FUNCTION),
+                (0, 4, 4, SemanticTokenType::COMMENT),
+                (1, 0, 3, SemanticTokenType::KEYWORD),
+                (0, 5, 1, SemanticTokenType::NUMBER),
+            ]
+        );
+    }
+
+    async fn definitions(
+        service: &LspService<Asm>,
+        name: &str,
+        line: u32,
+        character: u32,
+    ) -> Vec<(String, u32)> {
+        match service
+            .inner()
+            .goto_definition(GotoDefinitionParams {
+                text_document_position_params: at(name, line, character),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+        {
+            Some(GotoDefinitionResponse::Link(links)) => links
+                .into_iter()
+                .map(|link| {
+                    (
+                        link.target_uri.as_str().to_string(),
+                        link.target_range.start.line,
+                    )
+                })
+                .collect(),
+            other => panic!("expected definition links, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn cheap_local_labels_resolve_within_their_region() {
+        let (service, _) = load(&[(
+            "main.s",
+            "first:\n@loop: dex\nbne @loop\nsecond:\n@loop: dey\nbne @loop\n",
+        )])
+        .await;
+
+        let main = uri("main.s").as_str().to_string();
+        assert_eq!(
+            definitions(&service, "main.s", 2, 5).await,
+            vec![(main.clone(), 1)]
+        );
+        assert_eq!(definitions(&service, "main.s", 5, 5).await, vec![(main, 4)]);
+    }
+
+    async fn workspace_symbols(service: &LspService<Asm>, query: &str) -> Vec<String> {
+        let Some(WorkspaceSymbolResponse::Nested(symbols)) = service
+            .inner()
+            .symbol(WorkspaceSymbolParams {
+                query: query.to_string(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected workspace symbols");
+        };
+        let mut names = symbols
+            .into_iter()
+            .map(|symbol| symbol.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn workspace_symbols_fuzzy_match_across_files() {
+        let (service, _) = load(&[
+            ("player.s", "player_update: rts\nenemy_update: rts\n"),
+            ("draw.s", ".proc player_draw\nrts\n.endproc\n"),
+        ])
+        .await;
+
+        assert_eq!(
+            workspace_symbols(&service, "player").await,
+            vec!["player_draw", "player_update"]
+        );
+        assert_eq!(
+            workspace_symbols(&service, "PlUp").await,
+            vec!["player_update"]
+        );
+    }
+
+    #[tokio::test]
+    async fn an_empty_workspace_symbol_query_is_bounded() {
+        let source = (0..MAX_WORKSPACE_SYMBOLS + 10)
+            .map(|index| format!("label{index}: rts\n"))
+            .collect::<String>();
+        let (service, _) = load(&[("main.s", &source)]).await;
+
+        assert_eq!(
+            workspace_symbols(&service, "").await.len(),
+            MAX_WORKSPACE_SYMBOLS
+        );
+    }
+
+    #[tokio::test]
+    async fn goto_definition_on_an_include_path_opens_the_file() {
+        let (service, _) = load(&[
+            ("main.s", ".include \"lib.inc\"\n.include \"missing.inc\"\n"),
+            ("lib.inc", "helper: rts\n"),
+        ])
+        .await;
+
+        assert_eq!(
+            definitions(&service, "main.s", 0, 12).await,
+            vec![(uri("lib.inc").as_str().to_string(), 0)]
+        );
+
+        let missing = service
+            .inner()
+            .goto_definition(GotoDefinitionParams {
+                text_document_position_params: at("main.s", 1, 12),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert!(missing.is_none(), "{missing:?}");
+    }
+
+    #[tokio::test]
+    async fn hover_on_a_macro_call_shows_its_signature_and_comment() {
+        let (service, _) = load(&[(
+            "main.s",
+            "; Stores a value at an address\n.macro poke addr, value\nlda #value\nsta addr\n.endmacro\npoke $2000, 1\n",
+        )])
+        .await;
+
+        let hover = service
+            .inner()
+            .hover(HoverParams {
+                text_document_position_params: at("main.s", 5, 1),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let HoverContents::Scalar(MarkedString::String(content)) = hover.contents else {
+            panic!("expected a string, got {:?}", hover.contents);
+        };
+        assert_eq!(
+            content,
+            "```ca65\n.macro poke addr, value\n```\n\nStores a value at an address"
+        );
+    }
+
+    /// The text of each selection range at the position, from the innermost outwards.
+    async fn selections(
+        service: &LspService<Asm>,
+        source: &str,
+        line: u32,
+        character: u32,
+    ) -> Vec<String> {
+        let selections = service
+            .inner()
+            .selection_range(SelectionRangeParams {
+                text_document: TextDocumentIdentifier { uri: uri("main.s") },
+                positions: vec![lsp_types::Position::new(line, character)],
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let file = File::new("main.s", source.to_string());
+        let mut texts = vec![];
+        let mut selection = selections.into_iter().next();
+        while let Some(current) = selection {
+            let start = file
+                .position_to_byte_index(current.range.start.into())
+                .unwrap();
+            let end = file
+                .position_to_byte_index(current.range.end.into())
+                .unwrap();
+            texts.push(source[start..end].to_string());
+            selection = current.parent.map(|parent| *parent);
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn selection_grows_from_an_instruction_to_its_procedure() {
+        let source = ".proc main\nlda #1\nrts\n.endproc\n";
+        let (service, _) = load(&[("main.s", source)]).await;
+
+        assert_eq!(
+            selections(&service, source, 1, 1).await,
+            vec!["lda", "lda #1", ".proc main\nlda #1\nrts\n.endproc", source]
+        );
+    }
+
+    #[tokio::test]
+    async fn selection_grows_from_a_term_to_the_statement() {
+        let source = "VALUE = 2\n.word (VALUE + 1) * 4\n";
+        let (service, _) = load(&[("main.s", source)]).await;
+
+        assert_eq!(
+            selections(&service, source, 1, 8).await,
+            vec![
+                "VALUE",
+                "VALUE + 1",
+                "(VALUE + 1)",
+                "(VALUE + 1) * 4",
+                ".word (VALUE + 1) * 4",
+                source,
+            ]
+        );
     }
 }
\ No newline at end of file
@@ -1,11 +1,20 @@
+use crate::analysis::branches::BranchRangeChecker;
+use crate::analysis::evaluator::ConstantEvaluator;
 use crate::analysis::scope_analyzer::Scope;
 use crate::analysis::symbol_resolver::SymbolResolver;
-use crate::data::files::IndexError;
-use crate::data::symbol::Symbol;
-use codespan::{File, FileId};
+use crate::analysis::visitor::ASTVisitor;
+use crate::data::configuration::LintLevel;
+use crate::data::files::{Files, IndexError};
+use crate::data::symbol::{Symbol, SymbolType, resolve_symbol};
+use crate::documentation::{DOCUMENTATION_COLLECTION, DocumentationKind};
+use codespan::{File, FileId, Span};
 use lazy_static::lazy_static;
-use parser::{Ast, Instructions, ParseError, Token};
-use tower_lsp_server::lsp_types::{Diagnostic, DiagnosticSeverity, Range};
+use parser::{
+    Ast, ImportExport, Instruction, Instructions, MacroInvocation, ParseError, Token,
+    TokenizerError,
+};
+use std::collections::HashSet;
+use tower_lsp_server::lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, Range};
 
 lazy_static! {
     pub static ref INSTRUCTIONS: Instructions = Instructions::load();
@@ -18,11 +27,17 @@ pub struct CacheFile {
     pub id: FileId,
     pub file: File,
     pub tokens: Vec<Token>,
+    pub comments: Vec<Span>,
     pub ast: Ast,
     pub scopes: Vec<Scope>,
     pub includes: Vec<Include>,
+    pub binary_includes: Vec<Token>,
     pub resolved_includes: Vec<ResolvedInclude>,
     pub symbols: Vec<Symbol>,
+    /// The source `tokens` and `comments` were produced from, if that succeeded
+    tokenized_source: Option<String>,
+    /// What the last parse reported, kept so the file's lints can be refreshed without reparsing
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Clone, Debug)]
@@ -60,18 +75,36 @@ impl CacheFile {
             id,
             file,
             tokens: Vec::new(),
+            comments: Vec::new(),
             ast: Ast::new(),
             scopes: vec![],
             includes: vec![],
+            binary_includes: vec![],
             resolved_includes: vec![],
             symbols: vec![],
+            tokenized_source: None,
+            diagnostics: vec![],
         }
     }
 
     pub fn parse(&mut self) -> IndexResult<Vec<ParseError>> {
-        match parser::Tokenizer::new(&self.file.source, &INSTRUCTIONS).parse() {
-            Ok(tokens) => {
+        let tokenized = match self.tokenized_source.take() {
+            Some(previous) => {
+                retokenize(&previous, &self.file.source, &self.tokens, &self.comments)
+            }
+            None => {
+                let mut tokenizer = parser::Tokenizer::new(&self.file.source, &INSTRUCTIONS);
+                tokenizer
+                    .parse()
+                    .map(|tokens| (tokens, tokenizer.comments().to_vec()))
+            }
+        };
+
+        match tokenized {
+            Ok((tokens, comments)) => {
                 self.tokens = tokens;
+                self.comments = comments;
+                self.tokenized_source = Some(self.file.source.clone());
 
                 let (ast, errors) = parser::Parser::new(&self.tokens).parse();
                 self.ast = ast;
@@ -82,14 +115,9 @@ impl CacheFile {
         }
     }
 
-    // TODO: store a diagnostics array for the different stages and concatenate them together
-    pub async fn lint(&mut self) -> Vec<Diagnostic> {
-        self.resolve_identifier_access()
-    }
-
     pub fn resolve_identifier_access(&self) -> Vec<Diagnostic> {
         let mut diagnostics = vec![];
-        let identifiers = SymbolResolver::find_identifiers(self.ast.clone());
+        let identifiers = SymbolResolver::find_identifiers(&self.ast);
 
         for identifier_access in identifiers {
             let range = self
@@ -98,52 +126,180 @@ impl CacheFile {
                 .unwrap()
                 .into();
 
-            if identifier_access.name.starts_with("::") {
-                let m = self
-                    .symbols
-                    .iter()
-                    .find(|Symbol { fqn, .. }| fqn == &identifier_access.name);
-
-                if m.is_none() {
-                    diagnostics.push(Diagnostic {
-                        range,
-                        severity: Some(DiagnosticSeverity::ERROR),
-                        message: format!("Unknown symbol: {}", identifier_access.name),
-                        ..Default::default()
-                    });
-                }
+            if identifier_access.in_macro
+                || self
+                    .find_symbol(&identifier_access.scope, &identifier_access.name)
+                    .is_some()
+            {
                 continue;
             }
 
-            let mut resolved_fqn = None;
+            // For qualified names like `Foo::field`, point out when only the member is wrong
+            let message = match identifier_access.name.rsplit_once("::") {
+                Some((parent, member))
+                    if !parent.is_empty()
+                        && self
+                            .find_symbol(&identifier_access.scope, parent)
+                            .is_some_and(|symbol| matches!(symbol.sym_type, SymbolType::Scope)) =>
+                {
+                    format!("Unknown symbol: {parent} has no member {member}")
+                }
+                _ => format!("Unknown symbol: {}", identifier_access.name),
+            };
+
+            diagnostics.push(Diagnostic {
+                range,
+                severity: Some(DiagnosticSeverity::ERROR),
+                message,
+                ..Default::default()
+            });
+        }
+
+        diagnostics
+    }
+
+    /// Flags `.import`s whose definition `(file, span)` is in none of the `referenced` sets, at `level`.
+    pub fn find_unused_imports(
+        &self,
+        referenced: &[&HashSet<(FileId, Span)>],
+        level: LintLevel,
+    ) -> Vec<Diagnostic> {
+        let Some(severity) = level.severity() else {
+            return vec![];
+        };
+        let mut collector = ImportCollector { imports: vec![] };
+        for statement in self.ast.iter() {
+            collector.visit_statement(statement);
+        }
 
-            for i in (0..=identifier_access.scope.len()).rev() {
-                let scope = &identifier_access.scope[0..i];
-                let target_fqn = [&["".to_owned()], scope, &[identifier_access.name.clone()]]
-                    .concat()
-                    .join("::")
-                    .to_string();
-                let m = self
-                    .symbols
+        collector
+            .imports
+            .into_iter()
+            .filter(|import| {
+                !referenced
                     .iter()
-                    .find(|Symbol { fqn, .. }| fqn == &target_fqn);
+                    .any(|symbols| symbols.contains(&(self.id, import.span)))
+            })
+            .filter_map(|import| {
+                Some(Diagnostic {
+                    range: self.file.byte_span_to_range(import.span).ok()?.into(),
+                    severity: Some(severity),
+                    message: format!("Unused import: {}", import.lexeme),
+                    tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    /// Flags `.feature` and `.macpack` arguments that don't name a known feature or package.
+    pub fn find_unknown_directive_arguments(&self) -> Vec<Diagnostic> {
+        let Some(docs) = DOCUMENTATION_COLLECTION.get() else {
+            return vec![];
+        };
+        let mut collector = DirectiveArgumentCollector { arguments: vec![] };
+        for statement in self.ast.iter() {
+            collector.visit_statement(statement);
+        }
 
-                if m.is_some() {
-                    resolved_fqn = Some(target_fqn);
-                    break;
+        collector
+            .arguments
+            .into_iter()
+            .filter_map(|(kind, name, span)| {
+                let known = docs.get(&kind)?;
+                if known.keys().any(|key| key == name) {
+                    return None;
                 }
-            }
-            if resolved_fqn.is_none() {
-                diagnostics.push(Diagnostic {
-                    range,
-                    severity: Some(DiagnosticSeverity::ERROR),
-                    message: format!("Unknown symbol: {}", identifier_access.name),
+
+                let what = match kind {
+                    DocumentationKind::Feature => "feature",
+                    _ => "macro package",
+                };
+                let mut message = format!("Unknown {what}: {name}");
+                if let Some(suggestion) = closest_match(&name, known.keys()) {
+                    message.push_str(&format!(", did you mean {suggestion}?"));
+                }
+
+                Some(Diagnostic {
+                    range: self.file.byte_span_to_range(span).ok()?.into(),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message,
                     ..Default::default()
-                });
-            }
+                })
+            })
+            .collect()
+    }
+
+    /// Flags instructions the active CPU doesn't have. The CPU is followed through `.include`s
+    /// from each of `roots`, starting from `default_cpu`, so a file starts with whatever CPU is
+    /// active where it's included. An instruction is only flagged when it's unavailable every
+    /// time the file is reached. Mnemonics outside the 6502 set parse as macro invocations, so
+    /// those are only flagged when no macro in `macros` has that name.
+    pub fn find_unavailable_instructions(
+        &self,
+        files: &Files,
+        roots: &[FileId],
+        default_cpu: &str,
+        macros: &HashSet<String>,
+    ) -> Vec<Diagnostic> {
+        let mut visits = vec![];
+        for root in roots {
+            let mut collector = CpuInstructionCollector {
+                files,
+                target: self.id,
+                current: *root,
+                cpu: default_cpu.to_string(),
+                saved: vec![],
+                including: vec![],
+                unavailable: vec![],
+                visits: vec![],
+            };
+            collector.walk(*root);
+            visits.extend(collector.visits);
         }
+        let Some((first, rest)) = visits.split_first() else {
+            return vec![];
+        };
 
-        diagnostics
+        first
+            .iter()
+            .filter(|(_, _, span)| {
+                rest.iter()
+                    .all(|visit| visit.iter().any(|(_, _, other)| other == span))
+            })
+            .filter(|(name, ..)| !macros.contains(name))
+            .filter_map(|(name, cpu, span)| {
+                Some(Diagnostic {
+                    range: self.file.byte_span_to_range(*span).ok()?.into(),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    message: format!("Instruction not available for {cpu}: {name}"),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    /// Flags `beq`-style relative branches whose label is certainly more than a signed byte away.
+    pub fn find_branches_out_of_range(&self) -> Vec<Diagnostic> {
+        let evaluator = ConstantEvaluator::new(&self.ast);
+        BranchRangeChecker::find_out_of_range(&self.ast, &evaluator)
+            .into_iter()
+            .filter_map(|branch| {
+                Some(Diagnostic {
+                    range: self.file.byte_span_to_range(branch.span).ok()?.into(),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message: format!(
+                        "Branch out of range: target is {} bytes away",
+                        branch.distance
+                    ),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn find_symbol(&self, scope: &[String], name: &str) -> Option<&Symbol> {
+        resolve_symbol(&self.symbols, scope, name)
     }
 
     pub fn format_parse_errors(&self, errors: Vec<ParseError>) -> Vec<Diagnostic> {
@@ -158,14 +314,35 @@ impl CacheFile {
                     ));
                 }
                 ParseError::Expected { expected, received } => {
+                    // A token of the right type can still be the wrong word, like `.proc foo: huge`
+                    let received_name = if received.token_type == *expected {
+                        format!("{:?}", received.lexeme)
+                    } else {
+                        format!("{:?}", received.token_type)
+                    };
                     diagnostics.push(Diagnostic::new_simple(
                         self.file.byte_span_to_range(received.span).unwrap().into(),
+                        format!("Expected {:?} but received {}", expected, received_name),
+                    ));
+                }
+                ParseError::MismatchedTerminator { opener, terminator } => {
+                    diagnostics.push(Diagnostic::new_simple(
+                        self.file
+                            .byte_span_to_range(terminator.span)
+                            .unwrap()
+                            .into(),
                         format!(
-                            "Expected {:?} but received {:?}",
-                            expected, received.token_type
+                            "Expected terminator for {} but found {}",
+                            opener.lexeme, terminator.lexeme
                         ),
                     ));
                 }
+                ParseError::UnterminatedBlock { opener } => {
+                    diagnostics.push(Diagnostic::new_simple(
+                        self.file.byte_span_to_range(opener.span).unwrap().into(),
+                        format!("Unterminated `{}` started here", opener.lexeme),
+                    ));
+                }
                 ParseError::EOF => {
                     let pos = self
                         .file
@@ -182,3 +359,352 @@ impl CacheFile {
         diagnostics
     }
 }
+
+// Tokens never continue past the end of a line, so only the lines that differ from the previous
+// source are tokenized again; the tokens before them are kept and the ones after are shifted
+fn retokenize(
+    previous: &str,
+    source: &str,
+    tokens: &[Token],
+    comments: &[Span],
+) -> Result<(Vec<Token>, Vec<Span>), TokenizerError> {
+    let (start, previous_end, end) = changed_lines(previous, source);
+    let mut tokenizer = parser::Tokenizer::new(&source[start..end], &INSTRUCTIONS);
+    let changed = tokenizer.parse().map_err(|err| TokenizerError {
+        offset: err.offset + start,
+        ..err
+    })?;
+
+    // Tokens after the change move by however much the changed lines grew or shrank
+    let shifted = |span: Span| {
+        Span::new(
+            span.start - previous_end + end,
+            span.end - previous_end + end,
+        )
+    };
+    let offset = |span: Span| Span::new(span.start + start, span.end + start);
+
+    let mut spliced = vec![];
+    spliced.extend(
+        tokens
+            .iter()
+            .filter(|token| token.span.start < start)
+            .cloned(),
+    );
+    for token in changed {
+        spliced.push(Token {
+            span: offset(token.span),
+            ..token
+        });
+    }
+    for token in tokens
+        .iter()
+        .filter(|token| token.span.start >= previous_end)
+    {
+        spliced.push(Token {
+            span: shifted(token.span),
+            ..token.clone()
+        });
+    }
+
+    let mut spliced_comments = vec![];
+    spliced_comments.extend(comments.iter().filter(|span| span.start < start));
+    spliced_comments.extend(tokenizer.comments().iter().map(|span| offset(*span)));
+    for span in comments.iter().filter(|span| span.start >= previous_end) {
+        spliced_comments.push(shifted(*span));
+    }
+
+    Ok((spliced, spliced_comments))
+}
+
+// The changed region as whole lines: where it starts, and where it ends in the previous and the
+// new source. Both boundaries sit just after a newline the two sources share, or at an end.
+fn changed_lines(previous: &str, source: &str) -> (usize, usize, usize) {
+    let (previous, source) = (previous.as_bytes(), source.as_bytes());
+    let prefix = previous
+        .iter()
+        .zip(source)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let start = previous[..prefix]
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |newline| newline + 1);
+
+    let suffix = previous
+        .iter()
+        .rev()
+        .zip(source.iter().rev())
+        .take(previous.len().min(source.len()) - start)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix_start = previous.len() - suffix;
+    let previous_end = previous[suffix_start..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map_or(previous.len(), |newline| suffix_start + newline + 1);
+
+    let end = source.len() - (previous.len() - previous_end);
+    (start, previous_end, end)
+}
+
+struct DirectiveArgumentCollector {
+    arguments: Vec<(DocumentationKind, String, Span)>,
+}
+
+impl ASTVisitor for DirectiveArgumentCollector {
+    fn visit_macro_pack(&mut self, pack: &Token, _span: Span) {
+        self.arguments
+            .push((DocumentationKind::Macpack, pack.lexeme.clone(), pack.span));
+    }
+    fn visit_feature(&mut self, name: &Token, _span: Span) {
+        self.arguments
+            .push((DocumentationKind::Feature, name.lexeme.clone(), name.span));
+    }
+}
+
+struct CpuInstructionCollector<'a> {
+    files: &'a Files,
+    /// The file whose instructions are checked
+    target: FileId,
+    current: FileId,
+    cpu: String,
+    /// CPUs saved by `.pushcpu`
+    saved: Vec<String>,
+    including: Vec<FileId>,
+    unavailable: Vec<(String, String, Span)>,
+    /// What was flagged in `target` each time it was reached
+    visits: Vec<Vec<(String, String, Span)>>,
+}
+
+impl CpuInstructionCollector<'_> {
+    fn walk(&mut self, file: FileId) {
+        // A circular include would never finish
+        if self.including.contains(&file) {
+            return;
+        }
+        let files = self.files;
+        self.including.push(file);
+        let parent = std::mem::replace(&mut self.current, file);
+
+        for statement in files.get(file).ast.iter() {
+            self.visit_statement(statement);
+        }
+        if file == self.target {
+            self.visits.push(std::mem::take(&mut self.unavailable));
+        }
+
+        self.current = parent;
+        self.including.pop();
+    }
+
+    fn check(&mut self, mnemonic: &str, span: Span) {
+        if self.current == self.target
+            && INSTRUCTIONS.is_cpu_instruction(mnemonic, &self.cpu) == Some(false)
+        {
+            self.unavailable
+                .push((mnemonic.to_string(), self.cpu.clone(), span));
+        }
+    }
+}
+
+impl ASTVisitor for CpuInstructionCollector<'_> {
+    fn visit_include(&mut self, path: &Token, _span: Span) {
+        let files = self.files;
+        let include = files
+            .get(self.current)
+            .resolved_includes
+            .iter()
+            .find(|include| include.token.span == path.span);
+        if let Some(include) = include {
+            self.walk(include.file);
+        }
+    }
+    fn visit_set_cpu(&mut self, cpu: &str, _span: Span) {
+        self.cpu = cpu.to_string();
+    }
+    fn visit_push_cpu(&mut self, _span: Span) {
+        self.saved.push(self.cpu.clone());
+    }
+    fn visit_pop_cpu(&mut self, _span: Span) {
+        if let Some(cpu) = self.saved.pop() {
+            self.cpu = cpu;
+        }
+    }
+    fn visit_instruction(&mut self, instruction: &Instruction, span: Span) {
+        let mnemonic = Span::new(span.start, span.start + instruction.mnemonic.len());
+        self.check(&instruction.mnemonic, mnemonic);
+    }
+    fn visit_macro_invocation(&mut self, macro_invocation: &MacroInvocation, _span: Span) {
+        let name = &macro_invocation.name;
+        if INSTRUCTIONS.is_any_cpu_instruction(&name.lexeme) {
+            self.check(&name.lexeme, name.span);
+        }
+    }
+}
+
+// Picks the candidate within a couple of edits of the name, if there is one
+fn closest_match<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+struct ImportCollector {
+    imports: Vec<Token>,
+}
+
+impl ASTVisitor for ImportCollector {
+    fn visit_import(&mut self, imports: &[ImportExport], _zero_page: &bool, _span: Span) {
+        self.imports
+            .extend(imports.iter().map(|import| import.name.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::load;
+
+    fn tokenize(source: &str) -> Result<(Vec<Token>, Vec<Span>), (usize, String)> {
+        let mut tokenizer = parser::Tokenizer::new(source, &INSTRUCTIONS);
+        tokenizer
+            .parse()
+            .map(|tokens| (tokens, tokenizer.comments().to_vec()))
+            .map_err(|err| (err.offset, err.kind.to_string()))
+    }
+
+    // Retokenizing after replacing `from` with `to` must give what tokenizing from scratch does
+    fn assert_retokenizes(previous: &str, from: &str, to: &str) {
+        let source = previous.replacen(from, to, 1);
+        let (tokens, comments) = tokenize(previous).unwrap();
+
+        let retokenized = retokenize(previous, &source, &tokens, &comments)
+            .map_err(|err| (err.offset, err.kind.to_string()));
+
+        assert_eq!(retokenized, tokenize(&source), "{source:?}");
+    }
+
+    const SOURCE: &str = "start:\n    lda #$10 ; load\n    sta $2000\n    rts\n";
+
+    #[test]
+    fn retokenizes_an_edit_inside_a_line() {
+        assert_retokenizes(SOURCE, "#$10", "#$20 + 1");
+    }
+
+    #[test]
+    fn retokenizes_an_edit_joining_two_lines() {
+        assert_retokenizes(SOURCE, "; load\n    sta", "sta");
+    }
+
+    #[test]
+    fn retokenizes_an_added_line_continuation() {
+        assert_retokenizes(SOURCE, "sta $2000\n", "sta $2000 + \\\n");
+    }
+
+    #[test]
+    fn retokenizes_an_unterminated_string() {
+        assert_retokenizes(SOURCE, "lda #$10", "lda #\"abc");
+    }
+
+    #[tokio::test]
+    async fn flags_unknown_macro_packages() {
+        let (service, ids) = load(&[("main.s", ".macpack longbranch\n.macpack generik\n")]).await;
+        let state = service.inner().state().lock().await;
+
+        let diagnostics = state.files.get(ids[0]).find_unknown_directive_arguments();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "Unknown macro package: generik, did you mean generic?"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_features_are_flagged_on_their_name() {
+        let (service, ids) = load(&[(
+            "main.s",
+            ".feature at_in_identifiers +\n.feature at_in_identifier -\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        let diagnostics = state.files.get(ids[0]).find_unknown_directive_arguments();
+        assert_eq!(diagnostics.len(), 1);
+        let range = diagnostics[0].range;
+        assert_eq!(
+            (range.start.line, range.start.character, range.end.character),
+            (1, 9, 25)
+        );
+    }
+
+    #[tokio::test]
+    async fn undefined_identifiers_inside_sprintf_are_flagged() {
+        let (service, ids) = load(&[(
+            "main.s",
+            "count = 3\n.byte .sprintf(\"%d %d\", count, missing)\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        let messages = state
+            .files
+            .get(ids[0])
+            .resolve_identifier_access()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["Unknown symbol: missing"]);
+    }
+
+    #[tokio::test]
+    async fn struct_fields_resolve_through_their_struct() {
+        let (service, ids) = load(&[(
+            "main.s",
+            ".struct Point\nxpos .byte\nypos .byte\n.endstruct\nlda #Point::ypos\nlda #.sizeof(Point::xpos)\nlda #Point::zpos\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        let diagnostics = state.files.get(ids[0]).resolve_identifier_access();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 6);
+        assert_eq!(
+            diagnostics[0].message,
+            "Unknown symbol: Point has no member zpos"
+        );
+    }
+
+    #[tokio::test]
+    async fn repeat_counters_are_only_visible_inside_their_loop() {
+        let (service, ids) = load(&[(
+            "main.s",
+            ".repeat 4, i\n.repeat 2, i\n.byte i\n.endrepeat\n.byte i\n.endrepeat\n.byte i\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        let diagnostics = state.files.get(ids[0]).resolve_identifier_access();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 6);
+        assert_eq!(diagnostics[0].message, "Unknown symbol: i");
+    }
+}
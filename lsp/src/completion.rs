@@ -1,13 +1,37 @@
-use crate::analysis::scope_analyzer::ScopeAnalyzer;
+use crate::analysis::scope_analyzer::{REPEAT_SCOPE, ScopeAnalyzer};
+use crate::data::symbol::{Symbol, resolve_symbol};
 use crate::documentation::{COMPLETION_ITEMS_COLLECTION, DocumentationKind};
+use crate::index_engine::IndexEngine;
 use crate::{data::symbol::SymbolType, state::State};
-use codespan::FileId;
 use codespan::Position;
+use codespan::{File, FileId, Span};
 use parser::TokenType;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
 use tower_lsp_server::lsp_types::{
     CompletionItem, CompletionItemKind, CompletionItemLabelDetails, CompletionTextEdit,
-    InsertReplaceEdit, Range,
+    InsertReplaceEdit, Range, TextEdit,
 };
+use url::Url;
+
+/// Identifies the symbol behind a completion item, so `completionItem/resolve` can fill in its
+/// documentation only once the client asks for it.
+#[derive(Serialize, Deserialize)]
+pub struct CompletionData {
+    pub fqn: String,
+    pub file: usize,
+}
+
+impl CompletionData {
+    fn for_symbol(symbol: &Symbol) -> Option<serde_json::Value> {
+        serde_json::to_value(CompletionData {
+            fqn: symbol.fqn.clone(),
+            file: symbol.file_id.get(),
+        })
+        .ok()
+    }
+}
 
 pub trait CompletionProvider {
     fn completions_for(&self, state: &State, id: FileId, position: Position)
@@ -56,45 +80,89 @@ impl CompletionProvider for SymbolCompletionProvider {
         let byte_position = file.file.position_to_byte_index(position).unwrap_or(0);
         let scope = ScopeAnalyzer::search(&file.scopes, byte_position);
 
-        let word_at_position = file.file.get_word_at_position(position).unwrap_or("");
-        let has_namespace = word_at_position.contains(":");
+        let current_scope = scope.join("::");
+        let symbols = &state.units[units[0]].symbols;
+
+        // After `Foo::`, only offer the direct members of `Foo`
+        let Some(source) = file.file.source.get(..byte_position) else {
+            return Vec::new();
+        };
+        let typed_start = source
+            .rfind(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '@' | ':')))
+            .map_or(0, |i| i + 1);
+        if let Some((parent, member)) = source[typed_start..].rsplit_once("::") {
+            let parent_fqn = if parent.is_empty() {
+                Some(String::new())
+            } else {
+                resolve_symbol(symbols, &scope[1..], parent)
+                    .filter(|symbol| matches!(symbol.sym_type, SymbolType::Scope))
+                    .map(|symbol| symbol.fqn.clone())
+            };
+            let Some(parent_fqn) = parent_fqn else {
+                return Vec::new();
+            };
+            let Ok(range) = file
+                .file
+                .byte_span_to_range(Span::new(byte_position - member.len(), byte_position))
+            else {
+                return Vec::new();
+            };
+            let range: Range = range.into();
+
+            return symbols
+                .iter()
+                .filter_map(|symbol| {
+                    let (symbol_parent, name) = symbol.fqn.rsplit_once("::")?;
+                    if symbol_parent != parent_fqn
+                        || matches!(symbol.sym_type, SymbolType::Parameter)
+                    {
+                        return None;
+                    }
+
+                    Some(CompletionItem {
+                        label: name.to_string(),
+                        data: CompletionData::for_symbol(symbol),
+                        kind: Some(symbol_completion_kind(symbol.sym_type)),
+                        text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+                            range,
+                            name.to_string(),
+                        ))),
+                        ..Default::default()
+                    })
+                })
+                .collect();
+        }
 
-        state.units[units[0]]
-            .symbols
+        symbols
             .iter()
             .filter_map(|symbol| {
                 if show_instructions
-                    && matches!(symbol.sym_type, SymbolType::Label | SymbolType::Constant)
+                    && matches!(
+                        symbol.sym_type,
+                        SymbolType::Label | SymbolType::Constant | SymbolType::Parameter
+                    )
                 {
                     None
                 } else if !show_instructions && matches!(symbol.sym_type, SymbolType::Macro) {
                     None
+                } else if (matches!(symbol.sym_type, SymbolType::Parameter)
+                    || symbol.fqn.contains(REPEAT_SCOPE))
+                    && !is_in_scope(&current_scope, &symbol.fqn)
+                {
+                    // Macro parameters and loop counters are only visible inside their body
+                    None
                 } else {
-                    let name = if has_namespace {
-                        symbol.fqn.clone()
-                    } else {
-                        ScopeAnalyzer::remove_denominator(&scope, symbol.fqn.clone())
-                    };
-
+                    let name = ScopeAnalyzer::remove_denominator(&scope, symbol.fqn.clone());
 
                     Some(CompletionItem {
                         label: name,
-                        filter_text: if has_namespace {
-                            Some(symbol.fqn.clone())
-                        } else {
-                            Some(symbol.label.clone())
-                        },
-                        detail: Some(symbol.comment.to_owned()),
+                        filter_text: Some(symbol.label.clone()),
+                        data: CompletionData::for_symbol(symbol),
                         label_details: Some(CompletionItemLabelDetails {
                             detail: None,
                             description: state.files.get_uri_relative(symbol.file_id, id),
                         }),
-                        kind: Some(match symbol.sym_type {
-                            SymbolType::Label => CompletionItemKind::FUNCTION,
-                            SymbolType::Constant => CompletionItemKind::CONSTANT,
-                            SymbolType::Macro => CompletionItemKind::SNIPPET,
-                            SymbolType::Scope => CompletionItemKind::MODULE,
-                        }),
+                        kind: Some(symbol_completion_kind(symbol.sym_type)),
                         ..Default::default()
                     })
                 }
@@ -103,6 +171,37 @@ impl CompletionProvider for SymbolCompletionProvider {
     }
 }
 
+fn symbol_completion_kind(sym_type: SymbolType) -> CompletionItemKind {
+    match sym_type {
+        SymbolType::Label => CompletionItemKind::FUNCTION,
+        SymbolType::Constant | SymbolType::Define => CompletionItemKind::CONSTANT,
+        SymbolType::Macro => CompletionItemKind::SNIPPET,
+        SymbolType::Scope => CompletionItemKind::MODULE,
+        SymbolType::Parameter => CompletionItemKind::VARIABLE,
+    }
+}
+
+fn is_in_scope(current_scope: &str, fqn: &str) -> bool {
+    match fqn.rsplit_once("::") {
+        Some((parent, _)) => {
+            current_scope == parent || current_scope.starts_with(&format!("{parent}::"))
+        }
+        None => true,
+    }
+}
+
+/// The range from the start of the word being typed up to the cursor, worked out from byte
+/// offsets so it stays right whatever the position encoding.
+fn typed_word_range(file: &File, position: Position) -> Option<Range> {
+    let (_, word) = file.get_word_span_at_position(position).ok()?;
+    let offset = file.position_to_byte_index(position).ok()?;
+    let range = file
+        .byte_span_to_range(Span::new(word.start, offset.max(word.start)))
+        .ok()?;
+
+    Some(range.into())
+}
+
 pub struct Ca65DotOperatorCompletionProvider;
 impl CompletionProvider for Ca65DotOperatorCompletionProvider {
     fn completions_for(
@@ -111,22 +210,8 @@ impl CompletionProvider for Ca65DotOperatorCompletionProvider {
         id: FileId,
         position: Position,
     ) -> Vec<CompletionItem> {
-        let curr_word = state
-            .files
-            .get(id)
-            .file
-            .get_word_at_position(position)
-            .expect("Could not get word at position in completion provider");
-
-        let insert_range = Range {
-            start: tower_lsp_server::lsp_types::Position {
-                line: position.line as u32,
-                character: (position.character - curr_word.len()) as u32,
-            },
-            end: tower_lsp_server::lsp_types::Position {
-                line: position.line as u32,
-                character: position.character as u32,
-            },
+        let Some(insert_range) = typed_word_range(&state.files.get(id).file, position) else {
+            return Vec::new();
         };
 
         COMPLETION_ITEMS_COLLECTION
@@ -162,22 +247,8 @@ impl CompletionProvider for Ca65KeywordCompletionProvider {
         id: FileId,
         position: Position,
     ) -> Vec<CompletionItem> {
-        let curr_word = state
-            .files
-            .get(id)
-            .file
-            .get_word_at_position(position)
-            .expect("Could not get word at position in completion provider");
-
-        let insert_range = Range {
-            start: tower_lsp_server::lsp_types::Position {
-                line: position.line as u32,
-                character: (position.character - curr_word.len()) as u32,
-            },
-            end: tower_lsp_server::lsp_types::Position {
-                line: position.line as u32,
-                character: position.character as u32,
-            },
+        let Some(insert_range) = typed_word_range(&state.files.get(id).file, position) else {
+            return Vec::new();
         };
 
         COMPLETION_ITEMS_COLLECTION
@@ -204,6 +275,48 @@ impl CompletionProvider for Ca65KeywordCompletionProvider {
     }
 }
 
+pub struct BlockSnippetCompletionProvider;
+
+impl CompletionProvider for BlockSnippetCompletionProvider {
+    fn completions_for(
+        &self,
+        state: &State,
+        id: FileId,
+        position: Position,
+    ) -> Vec<CompletionItem> {
+        // Blocks can only be opened where a statement starts
+        if !state.files.show_instructions(id, position) {
+            return Vec::new();
+        }
+
+        let Some(insert_range) = typed_word_range(&state.files.get(id).file, position) else {
+            return Vec::new();
+        };
+
+        COMPLETION_ITEMS_COLLECTION
+            .get()
+            .expect("Could not get completion items collection for block snippets")
+            .get(&DocumentationKind::BlockSnippet)
+            .expect("Could not get block snippet completion items")
+            .iter()
+            .map(|item| {
+                let mut new_item = item.clone();
+                new_item.text_edit =
+                    Some(CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                        new_text: item
+                            .insert_text
+                            .as_ref()
+                            .expect("block snippet completion item did not have insert_text")
+                            .clone(),
+                        insert: insert_range,
+                        replace: insert_range,
+                    }));
+                new_item
+            })
+            .collect()
+    }
+}
+
 pub struct MacpackCompletionProvider;
 
 impl CompletionProvider for MacpackCompletionProvider {
@@ -260,3 +373,197 @@ impl CompletionProvider for FeatureCompletionProvider {
         }
     }
 }
+
+/// Completes the path of an `.include` or `.incbin` with the files next to the current one.
+/// Found under the state lock by [`IncludePathCompletion::at`], but the directory is only read by
+/// [`IncludePathCompletion::list`] once the lock has been released.
+pub struct IncludePathCompletion {
+    directory: PathBuf,
+    current_file: PathBuf,
+    binary: bool,
+    insert: Range,
+    replace: Range,
+}
+
+impl IncludePathCompletion {
+    pub fn at(state: &State, id: FileId, position: Position) -> Option<IncludePathCompletion> {
+        let file = &state.files.get(id).file;
+        let offset = file.position_to_byte_index(position).ok()?;
+        let tokens = state.files.line_tokens(id, position);
+
+        let directive = tokens
+            .first()
+            .filter(|tok| tok.token_type == TokenType::Macro)?
+            .lexeme
+            .to_lowercase();
+        let binary = match directive.as_str() {
+            ".include" => false,
+            ".incbin" => true,
+            _ => return None,
+        };
+
+        let path = tokens.iter().find(|tok| {
+            tok.token_type == TokenType::String && tok.span.start < offset && offset <= tok.span.end
+        })?;
+
+        // Only complete while the cursor is between the quotes of the path
+        let terminated = path.lexeme.len() > 1 && path.lexeme.ends_with(&path.lexeme[..1]);
+        let content_end = if terminated {
+            path.span.end - 1
+        } else {
+            path.span.end
+        };
+        if offset > content_end {
+            return None;
+        }
+
+        let current_file = Url::from_str(state.files.get_uri(id).as_str())
+            .ok()?
+            .to_file_path()
+            .ok()?;
+        let directory = current_file.parent()?.to_path_buf();
+
+        let start = file.byte_index_to_position(path.span.start + 1).ok()?;
+        let end = file.byte_index_to_position(content_end).ok()?;
+
+        Some(IncludePathCompletion {
+            directory,
+            current_file,
+            binary,
+            insert: Range::new(start.into(), position.into()),
+            replace: Range::new(start.into(), end.into()),
+        })
+    }
+
+    /// Lists the sibling files the directive can take: sources for `.include`, anything else for
+    /// `.incbin`.
+    pub fn list(&self) -> Vec<CompletionItem> {
+        walkdir::WalkDir::new(&self.directory)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry.file_type().is_file()
+                    && IndexEngine::is_source_file(entry.path()) != self.binary
+                    && entry.path() != self.current_file
+            })
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                CompletionItem {
+                    label: name.clone(),
+                    kind: Some(CompletionItemKind::FILE),
+                    text_edit: Some(CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                        new_text: name,
+                        insert: self.insert,
+                        replace: self.replace,
+                    })),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::load;
+    use codespan::PositionEncoding;
+    use std::fs;
+    use tower_lsp_server::lsp_types;
+
+    fn listed(directory: &std::path::Path, binary: bool) -> Vec<String> {
+        let completion = IncludePathCompletion {
+            directory: directory.to_path_buf(),
+            current_file: directory.join("main.s"),
+            binary,
+            insert: Range::default(),
+            replace: Range::default(),
+        };
+        let mut labels = completion
+            .list()
+            .into_iter()
+            .map(|item| item.label)
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn include_paths_list_sibling_files_for_the_directive() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["main.s", "lib.inc", "tiles.chr", "nested/deep.inc"] {
+            let path = directory.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        assert_eq!(listed(directory.path(), false), vec!["lib.inc"]);
+        assert_eq!(listed(directory.path(), true), vec!["tiles.chr"]);
+    }
+
+    fn edit_ranges(items: &[CompletionItem]) -> Vec<Range> {
+        items
+            .iter()
+            .map(|item| match &item.text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => edit.range,
+                Some(CompletionTextEdit::InsertAndReplace(edit)) => edit.insert,
+                None => panic!("expected a text edit"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn completion_ranges_are_worked_out_from_byte_offsets() {
+        // Lines that don't tokenize are kept out of main.s, so its symbols still get indexed
+        let (service, ids) = load(&[
+            (
+                "main.s",
+                ".scope Foo\nmember = 1\n.endscope\n.include \"typing.inc\"\n",
+            ),
+            ("typing.inc", "lda Foo::é\nlda .é\n"),
+        ])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        let members =
+            SymbolCompletionProvider.completions_for(&state, ids[1], Position::new(0, 10));
+        assert_eq!(
+            edit_ranges(&members),
+            vec![Range::new(
+                lsp_types::Position::new(0, 9),
+                lsp_types::Position::new(0, 10)
+            )]
+        );
+
+        let keywords =
+            Ca65KeywordCompletionProvider.completions_for(&state, ids[1], Position::new(1, 6));
+        assert!(!keywords.is_empty());
+        assert!(edit_ranges(&keywords).iter().all(|range| *range
+            == Range::new(
+                lsp_types::Position::new(1, 4),
+                lsp_types::Position::new(1, 6)
+            )));
+    }
+
+    #[tokio::test]
+    async fn completing_inside_a_character_offers_nothing() {
+        let (service, ids) = load(&[
+            (
+                "main.s",
+                ".scope Foo\nmember = 1\n.endscope\n.include \"typing.inc\"\n",
+            ),
+            ("typing.inc", "lda Foo::é\n"),
+        ])
+        .await;
+        let mut state = service.inner().state().lock().await;
+        state.set_position_encoding(PositionEncoding::Utf8);
+
+        assert!(
+            SymbolCompletionProvider
+                .completions_for(&state, ids[1], Position::new(0, 10))
+                .is_empty()
+        );
+    }
+}
@@ -1,14 +1,17 @@
+use codespan::{PositionEncoding, Span};
 use std::{
     fs::File,
     io::Read,
     path::{Path, PathBuf},
 };
-use tower_lsp_server::lsp_types;
-use tower_lsp_server::lsp_types::{Diagnostic, Position};
+use tower_lsp_server::lsp_types::{Diagnostic, DiagnosticSeverity};
 
 #[derive(serde::Deserialize, Default, Debug)]
 pub struct ToolchainConfig {
     pub cc65: Option<String>,
+    /// Runs ca65 on a file whenever it's saved and reports its errors and warnings
+    #[serde(default)]
+    pub lint_on_save: bool,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -24,11 +27,78 @@ pub struct LSPConfig {
     pub machine: LSPConfigMachine,
 }
 
+#[derive(serde::Deserialize, Debug)]
+#[serde(default)]
+pub struct FormatConfig {
+    pub indent: usize,
+    pub comment_column: usize,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            indent: 8,
+            comment_column: 32,
+        }
+    }
+}
+
+/// How a lint is reported, or `Off` to not report it
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LintLevel {
+    Off,
+    Hint,
+    Info,
+    Warning,
+    Error,
+}
+
+impl LintLevel {
+    pub fn severity(self) -> Option<DiagnosticSeverity> {
+        match self {
+            LintLevel::Off => None,
+            LintLevel::Hint => Some(DiagnosticSeverity::HINT),
+            LintLevel::Info => Some(DiagnosticSeverity::INFORMATION),
+            LintLevel::Warning => Some(DiagnosticSeverity::WARNING),
+            LintLevel::Error => Some(DiagnosticSeverity::ERROR),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LintConfig {
+    pub unused_imports: LintLevel,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            unused_imports: LintLevel::Hint,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct InlayHintConfig {
+    /// Shows what constants used as instruction operands and data work out to. Enum member values
+    /// and struct field offsets are always shown
+    pub constant_values: bool,
+}
+
 #[derive(serde::Deserialize, Debug, Default)]
 pub struct Configuration {
     #[serde(default)]
     pub toolchain: ToolchainConfig,
     pub lsp: Option<LSPConfig>,
+    #[serde(default)]
+    pub format: FormatConfig,
+    #[serde(default)]
+    pub lints: LintConfig,
+    #[serde(default)]
+    pub inlay_hints: InlayHintConfig,
 }
 
 impl Configuration {
@@ -43,10 +113,21 @@ impl Configuration {
             Configuration {
                 toolchain: ToolchainConfig::default(),
                 lsp: None,
+                format: FormatConfig::default(),
+                lints: LintConfig::default(),
+                inlay_hints: InlayHintConfig::default(),
             }
         }
     }
 
+    /// The CPU a file targets until it selects one with `.setcpu`
+    pub fn default_cpu(&self) -> &'static str {
+        match self.lsp.as_ref().map(|lsp| &lsp.machine) {
+            Some(LSPConfigMachine::Snes) => "65816",
+            _ => "6502",
+        }
+    }
+
     pub fn get_ca65_path(&self) -> Option<PathBuf> {
         if let Some(toolchain_path) = self.toolchain.cc65.clone() {
             let compiler = Path::new(toolchain_path.as_str()).join("ca65");
@@ -56,7 +137,7 @@ impl Configuration {
         None
     }
 
-    pub fn load(path: &Path) -> Result<Configuration, Diagnostic> {
+    pub fn load(path: &Path, encoding: PositionEncoding) -> Result<Configuration, Diagnostic> {
         match File::open(path) {
             Ok(mut file) => {
                 let mut contents = String::new();
@@ -68,8 +149,17 @@ impl Configuration {
                         Ok(config)
                     }
                     Err(error) => {
-                        let range =
-                            Self::toml_range_to_lsp_range(contents, error.span().unwrap()).unwrap();
+                        // Goes through a File so the columns use the negotiated encoding
+                        let mut source = codespan::File::new(path.to_string_lossy(), contents);
+                        source.encoding = encoding;
+                        let range = error
+                            .span()
+                            .and_then(|span| {
+                                let span = Span::new(span.start, span.end);
+                                source.byte_span_to_range(span).ok()
+                            })
+                            .map(Into::into)
+                            .unwrap_or_default();
                         eprintln!("Failed to parse config file: {error:?}");
                         Err(Diagnostic::new_simple(range, error.to_string()))
                     }
@@ -78,39 +168,4 @@ impl Configuration {
             Err(_) => Ok(Configuration::default()),
         }
     }
-
-    fn toml_range_to_lsp_range(
-        config: String,
-        range: std::ops::Range<usize>,
-    ) -> Option<lsp_types::Range> {
-        let mut start_pos = None;
-        let mut end_pos = None;
-
-        let mut line = 0;
-        let mut line_start = 0;
-
-        for (idx, c) in config.char_indices() {
-            if idx == range.start {
-                start_pos = Some(Position::new(line, (idx - line_start) as u32));
-            }
-            if idx == range.end {
-                end_pos = Some(Position::new(line, (idx - line_start) as u32));
-                break;
-            }
-
-            if c == '\n' {
-                line += 1;
-                line_start = idx + 1;
-            }
-        }
-
-        if range.end == config.len() && end_pos.is_none() {
-            end_pos = Some(Position::new(line, (range.end - line_start) as u32));
-        }
-
-        Some(lsp_types::Range {
-            start: start_pos?,
-            end: end_pos?,
-        })
-    }
 }
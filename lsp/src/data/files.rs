@@ -6,13 +6,15 @@ use crate::data::indexing_state::IndexingState;
 use crate::data::path::diff_paths;
 use crate::data::symbol::{Symbol, SymbolType};
 use anyhow::anyhow;
-use codespan::{File, FileId, Position};
-use parser::{ParseError, Token, TokenizerError};
+use codespan::{File, FileId, Position, PositionEncoding, Span};
+use parser::{ParseError, Token, TokenizerError, TokenizerErrorKind};
 use path_clean::PathClean;
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tower_lsp_server::lsp_types::{Diagnostic, Range, Uri};
+use tower_lsp_server::lsp_types::{
+    Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, Location, Range, Uri,
+};
 use url::Url;
 
 pub enum IndexError {
@@ -23,6 +25,7 @@ pub enum IndexError {
 pub struct Files {
     files: Vec<CacheFile>,
     pub sources: HashMap<Uri, FileId>,
+    encoding: PositionEncoding,
 }
 
 impl Files {
@@ -30,6 +33,14 @@ impl Files {
         Self {
             files: vec![],
             sources: HashMap::new(),
+            encoding: PositionEncoding::default(),
+        }
+    }
+
+    pub fn set_position_encoding(&mut self, encoding: PositionEncoding) {
+        self.encoding = encoding;
+        for file in self.files.iter_mut() {
+            file.file.encoding = encoding;
         }
     }
 
@@ -47,8 +58,9 @@ impl Files {
 
     pub fn add(&mut self, uri: Uri, contents: String) -> FileId {
         let file_id = FileId::new(self.files.len());
-        self.files
-            .push(CacheFile::new(File::new(uri.as_str(), contents), file_id));
+        let mut file = File::new(uri.as_str(), contents);
+        file.encoding = self.encoding;
+        self.files.push(CacheFile::new(file, file_id));
         file_id
     }
 
@@ -64,6 +76,23 @@ impl Files {
         Uri::from_str(self.get(id).file.name.as_str()).unwrap()
     }
 
+    /// Where an indexed file lives on disk, if its uri is a `file:` uri.
+    pub fn get_path(&self, id: FileId) -> Option<PathBuf> {
+        Url::from_str(self.get_uri(id).as_str())
+            .ok()?
+            .to_file_path()
+            .ok()
+    }
+
+    /// Finds the indexed file stored at `path`.
+    pub fn find_by_path(&self, path: &Path) -> Option<FileId> {
+        let path = path.clean();
+        self.sources.values().copied().find(|id| {
+            self.get_path(*id)
+                .is_some_and(|file_path| file_path == path)
+        })
+    }
+
     pub fn source(&self, id: FileId) -> &String {
         &self.get(id).file.source
     }
@@ -90,21 +119,24 @@ impl Files {
         tokens.is_empty() || tokens[0].span.end >= offset // Makes a naive guess at whether the current line contains an instruction. Doesn't work on lines with labels
     }
 
-    pub fn resolve_import(&self, parent: FileId, path: &str) -> anyhow::Result<Option<FileId>> {
+    fn resolve_relative_path(&self, parent: FileId, path: &str) -> anyhow::Result<PathBuf> {
         let parent_uri = self.get_uri(parent);
 
-        if !path.ends_with(".asm") && !path.ends_with(".s") && !path.ends_with(".inc") {
-            return Ok(None);
-        }
-
-        let parent = Url::from_str(parent_uri.as_str())?
+        Ok(Url::from_str(parent_uri.as_str())?
             .to_file_path()
             .map_err(|_| anyhow!("Failed to create pathbuf"))?
             .parent()
             .ok_or_else(|| anyhow::anyhow!("parent folder not found"))?
             .join(path)
-            .clean();
+            .clean())
+    }
+
+    pub fn resolve_import(&self, parent: FileId, path: &str) -> anyhow::Result<Option<FileId>> {
+        if !path.ends_with(".asm") && !path.ends_with(".s") && !path.ends_with(".inc") {
+            return Ok(None);
+        }
 
+        let parent = self.resolve_relative_path(parent, path)?;
         let parent = convert_uri(Uri::from_str(Url::from_file_path(parent).unwrap().as_ref())?)?;
 
         let id = self
@@ -117,6 +149,44 @@ impl Files {
         Ok(Some(id.ok_or_else(|| anyhow::anyhow!("file not found"))?))
     }
 
+    pub fn resolve_binary_include(&self, parent: FileId, path: &str) -> anyhow::Result<Uri> {
+        let path = self.resolve_relative_path(parent, path)?;
+        if !path.exists() {
+            return Err(anyhow!("file not found"));
+        }
+
+        convert_uri(Uri::from_str(Url::from_file_path(path).unwrap().as_ref())?)
+    }
+
+    /// Where the file `.include`d as `path` from `parent` would be, whether or not it exists.
+    pub fn resolve_include_uri(&self, parent: FileId, path: &str) -> anyhow::Result<Uri> {
+        let path = self.resolve_relative_path(parent, path)?;
+        let url = Url::from_file_path(path).map_err(|_| anyhow!("Failed to create url"))?;
+
+        convert_uri(Uri::from_str(url.as_ref())?)
+    }
+
+    /// Indexed files whose path from `parent` only differs from `path` in case or extension, to
+    /// suggest for an `.include` that doesn't resolve.
+    pub fn find_similar_includes(&self, parent: FileId, path: &str) -> Vec<String> {
+        let stem = |path: &str| {
+            Path::new(path)
+                .with_extension("")
+                .to_string_lossy()
+                .to_lowercase()
+        };
+        let mut candidates = self
+            .sources
+            .values()
+            .filter(|id| **id != parent)
+            .filter_map(|id| self.get_uri_relative(*id, parent))
+            .filter(|candidate| candidate != path && stem(candidate) == stem(path))
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        candidates
+    }
+
     pub fn resolve_import_paths(
         &mut self,
         parent: FileId,
@@ -194,16 +264,49 @@ impl Files {
                     span: scope.get_span(),
                     file_id: file.id,
                     comment: scope.get_description(),
+                    documentation: match &scope {
+                        scope_analyzer::Symbol::Parameter { .. } => None,
+                        _ => leading_comments(&file.file, scope.get_span()),
+                    },
+                    parameters: scope.get_parameters(),
                     sym_type: match &scope {
                         scope_analyzer::Symbol::Macro { .. } => SymbolType::Macro,
                         scope_analyzer::Symbol::Label { .. } => SymbolType::Label,
                         scope_analyzer::Symbol::Constant { .. } => SymbolType::Constant,
-                        scope_analyzer::Symbol::Parameter { .. } => SymbolType::Constant,
+                        scope_analyzer::Symbol::Define { .. } => SymbolType::Define,
+                        scope_analyzer::Symbol::Parameter { .. } => SymbolType::Parameter,
                         scope_analyzer::Symbol::Scope { .. } => SymbolType::Scope,
                     },
                 });
             }
             file.includes = includes;
+            file.binary_includes = analyzer.binary_includes.clone();
+
+            let uri = Uri::from_str(file.file.name.as_str()).unwrap();
+            for redefinition in analyzer.redefinitions.iter() {
+                let (Ok(range), Ok(original_range)) = (
+                    file.file
+                        .byte_span_to_range(redefinition.redefinition.get_span()),
+                    file.file
+                        .byte_span_to_range(redefinition.original.get_span()),
+                ) else {
+                    continue;
+                };
+
+                diagnostics.push(Diagnostic {
+                    range: range.into(),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message: format!(
+                        "Symbol {} is already defined",
+                        redefinition.redefinition.get_name()
+                    ),
+                    related_information: Some(vec![DiagnosticRelatedInformation {
+                        location: Location::new(uri.clone(), original_range.into()),
+                        message: "Previous definition".to_string(),
+                    }]),
+                    ..Default::default()
+                });
+            }
 
             if !is_includes_same(&file.includes, &file.resolved_includes) {
                 let (resolved_imports, import_diagnostics) = self.resolve_import_paths(file_id);
@@ -216,10 +319,16 @@ impl Files {
             let pos = file.file.byte_index_to_position(err.offset).unwrap();
             diagnostics.push(Diagnostic::new_simple(
                 Range::new(pos.into(), pos.into()),
-                "Unexpected character".to_string(),
+                match err.kind {
+                    TokenizerErrorKind::UnexpectedToken => "Unexpected character",
+                    TokenizerErrorKind::UnterminatedString => "Unterminated string",
+                }
+                .to_string(),
             ));
         }
 
+        self.get_mut(file_id).diagnostics = diagnostics.clone();
+
         IndexingState {
             diagnostics,
             includes_changed,
@@ -240,3 +349,24 @@ fn is_includes_same(includes: &[Include], resolved_includes: &[ResolvedInclude])
 
     true
 }
+
+// Joins the run of comment-only lines directly above a definition, without their semicolons
+fn leading_comments(file: &File, span: Span) -> Option<String> {
+    let line = file.byte_index_to_position(span.start).ok()?.line;
+    let mut lines = vec![];
+    for above in (0..line).rev() {
+        let Some(comment) = file
+            .get_line(above)
+            .and_then(|above| file.get_line_source(above))
+            .ok()
+            .and_then(|source| source.trim().strip_prefix(';'))
+        else {
+            break;
+        };
+        let comment = comment.trim_start_matches(';');
+        lines.push(comment.strip_prefix(' ').unwrap_or(comment).trim_end());
+    }
+    lines.reverse();
+
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
@@ -7,8 +7,11 @@ use codespan::{
 pub enum SymbolType {
     Label,
     Constant,
+    /// A `.define`, which is expanded wherever its name appears
+    Define,
     Macro,
     Scope,
+    Parameter,
 }
 
 #[derive(Clone, Debug)]
@@ -18,5 +21,25 @@ pub struct Symbol {
     pub label: String,
     pub span: Span,
     pub comment: String,
+    pub documentation: Option<String>,
     pub sym_type: SymbolType,
+    pub parameters: Vec<String>,
+}
+
+/// Resolves `name` as seen from `scope`, searching from the innermost scope outwards
+pub fn resolve_symbol<'a>(
+    symbols: &'a [Symbol],
+    scope: &[String],
+    name: &str,
+) -> Option<&'a Symbol> {
+    if name.starts_with("::") {
+        return symbols.iter().find(|Symbol { fqn, .. }| fqn == name);
+    }
+
+    (0..=scope.len()).rev().find_map(|i| {
+        let target_fqn = [&["".to_owned()], &scope[0..i], &[name.to_owned()]]
+            .concat()
+            .join("::");
+        symbols.iter().find(|Symbol { fqn, .. }| fqn == &target_fqn)
+    })
 }
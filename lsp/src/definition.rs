@@ -1,38 +1,15 @@
-use crate::analysis::scope_analyzer::ScopeAnalyzer;
+use crate::analysis::scope_analyzer::{REPEAT_SCOPE, ScopeAnalyzer};
+use crate::data::symbol::SymbolType;
 use crate::{data::symbol::Symbol, state::State};
-use codespan::{FileError, FileId, Position, Span};
+use codespan::{FileError, FileId, Position, Span, find_segment_at_pos};
 use std::cmp::Ordering;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Definition;
 
-pub fn find_word_at_pos(line: &str, col: usize) -> Span {
-    let line_ = format!("{line} ");
-    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
-
-    let start = line_
-        .chars()
-        .enumerate()
-        .take(col)
-        .filter(|&(_, c)| !is_ident_char(c))
-        .last()
-        .map(|(i, _)| i + 1)
-        .unwrap_or(0);
-
-    let end = line_
-        .chars()
-        .enumerate()
-        .skip(col)
-        .find(|&(_, c)| !is_ident_char(c))
-        .map(|(i, _)| i)
-        .unwrap_or(col);
-
-    Span::new(start, end)
-}
-
 fn get_sub_identifier(identifier: &str, index: usize, span: Span) -> Span {
-    let index = index - span.start;
-    find_word_at_pos(identifier, index)
+    let (start, end) = find_segment_at_pos(identifier, index.saturating_sub(span.start));
+    Span::new(start, end)
 }
 
 impl Definition {
@@ -55,19 +32,26 @@ impl Definition {
         let current_scopes = ScopeAnalyzer::search(scopes, index);
 
         let new_span = get_sub_identifier(word, index, span);
-        let slice = &word[0..new_span.end];
+        let mut slice = word[0..new_span.end].to_string();
 
         let mut definitions = vec![];
         
         let symbols = &state.units[units[0]].symbols;
 
+        if slice.starts_with('@')
+            && let Some(owner) = find_cheap_label_owner(symbols, id, &current_scopes, index)
+        {
+            slice = format!("{owner}::{slice}");
+        }
+
         if slice.starts_with("::") {
-            if let Some(m) = symbols.iter().find(|Symbol { fqn, .. }| fqn == slice) {
+            if let Some(m) = symbols.iter().find(|Symbol { fqn, .. }| fqn == &slice) {
                 definitions.push(m.clone());
             }
         } else {
-            for (idx, _scope) in current_scopes.iter().rev().enumerate() {
-                let target_fqn = [&current_scopes[0..=idx], &[slice.to_string()]]
+            // Search from the innermost scope outwards so macro parameters shadow outer symbols
+            for idx in (0..current_scopes.len()).rev() {
+                let target_fqn = [&current_scopes[0..=idx], &[slice.clone()]]
                     .concat()
                     .join("::");
                 if let Some(m) = symbols
@@ -93,3 +77,32 @@ impl Definition {
         )))
     }
 }
+
+// Finds the regular label that opens the cheap local label region containing `index`
+fn find_cheap_label_owner(
+    symbols: &[Symbol],
+    id: FileId,
+    current_scopes: &[String],
+    index: usize,
+) -> Option<String> {
+    let scope = current_scopes
+        .iter()
+        .filter(|scope| scope.as_str() != REPEAT_SCOPE)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("::");
+
+    symbols
+        .iter()
+        .filter(|symbol| {
+            symbol.file_id == id
+                && matches!(symbol.sym_type, SymbolType::Label)
+                && symbol.span.start < index
+        })
+        .filter_map(|symbol| {
+            let (parent, name) = symbol.fqn.rsplit_once("::")?;
+            (parent == scope && !name.starts_with('@')).then_some((symbol.span.start, name))
+        })
+        .max_by_key(|(start, _)| *start)
+        .map(|(_, name)| name.to_string())
+}
@@ -25,6 +25,7 @@ pub enum DocumentationKind {
     Instruction,
     Feature,
     Macpack,
+    BlockSnippet,
 }
 pub static DOCUMENTATION_COLLECTION: OnceLock<HashMap<DocumentationKind, MultiKeySingleDoc>> =
     OnceLock::new();
@@ -45,6 +46,14 @@ impl MultiKeySingleDoc {
             None
         }
     }
+
+    /// Every documented keyword, including aliases that share another keyword's documentation.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.keys_to_doc
+            .keys()
+            .chain(self.keys_with_shared_doc.keys())
+            .map(String::as_str)
+    }
 }
 
 pub fn init() {
@@ -91,6 +100,17 @@ fn init_docs() {
     }
 }
 
+// Paired directives that expand into a whole block, ending with the matching terminator
+const BLOCK_SNIPPETS: [(&str, &str, &str); 7] = [
+    (".proc", "${1:name}", ".endproc"),
+    (".macro", "${1:name}", ".endmacro"),
+    (".scope", "${1:name}", ".endscope"),
+    (".struct", "${1:name}", ".endstruct"),
+    (".enum", "${1:name}", ".endenum"),
+    (".repeat", "${1:count}", ".endrepeat"),
+    (".if", "${1:condition}", ".endif"),
+];
+
 pub static COMPLETION_ITEMS_COLLECTION: OnceLock<HashMap<DocumentationKind, Vec<CompletionItem>>> =
     OnceLock::new();
 
@@ -100,7 +120,7 @@ fn init_completion_items() {
         serde_json::from_str::<HashMap<String, String>>(include_str!("../../data/snippets.json"))
             .expect("Could not parse snippets JSON");
 
-    let items: HashMap<DocumentationKind, Vec<CompletionItem>> = DOCUMENTATION_COLLECTION
+    let mut items: HashMap<DocumentationKind, Vec<CompletionItem>> = DOCUMENTATION_COLLECTION
         .get()
         .expect("Could not get documentation collection")
         .into_iter()
@@ -111,6 +131,7 @@ fn init_completion_items() {
             )
         })
         .collect();
+    items.insert(DocumentationKind::BlockSnippet, get_block_snippet_items());
 
     if COMPLETION_ITEMS_COLLECTION.set(items).is_err() {
         eprintln!("Could not set completion items collection");
@@ -175,3 +196,18 @@ fn get_completion_item_vec_from_multi_key_single_doc(
     ]
     .concat()
 }
+
+fn get_block_snippet_items() -> Vec<CompletionItem> {
+    BLOCK_SNIPPETS
+        .iter()
+        .map(|(opener, parameter, terminator)| CompletionItem {
+            filter_text: Some(opener.to_string()),
+            label: opener.to_string(),
+            kind: Some(CompletionItemKind::SNIPPET),
+            detail: Some(format!("{opener} … {terminator}")),
+            insert_text: Some(format!("{opener} {parameter}\n\t$0\n{terminator}")),
+            insert_text_format: Some(InsertTextFormat::SNIPPET),
+            ..Default::default()
+        })
+        .collect()
+}
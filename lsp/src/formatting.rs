@@ -0,0 +1,172 @@
+use crate::cache_file::INSTRUCTIONS;
+use crate::data::configuration::FormatConfig;
+use codespan::Span;
+use parser::{Token, TokenType, Tokenizer};
+
+/// Re-lays out a file from its token stream: labels and constant assignments start in column 0,
+/// every other statement is indented, and trailing comments are aligned to a common column.
+///
+/// Lines whose tokens span several lines, and lines joined by a trailing `\`, are left untouched.
+/// Sources that fail to tokenize are not formatted at all.
+pub fn format_source(source: &str, config: &FormatConfig) -> Option<String> {
+    let mut tokenizer = Tokenizer::new(source, &INSTRUCTIONS);
+    let tokens = tokenizer.parse().ok()?;
+    let mut output = String::with_capacity(source.len());
+    let mut tokens = tokens.iter().peekable();
+    let mut comments = tokenizer.comments().iter().peekable();
+    let mut line_start = 0;
+    let mut continued = false;
+
+    for line in source.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let in_line = |span: &Span| span.start >= line_start && span.start < line_end;
+
+        let mut line_tokens = vec![];
+        while let Some(token) = tokens.next_if(|token| in_line(&token.span)) {
+            if token.token_type != TokenType::EOL {
+                line_tokens.push(token);
+            }
+        }
+        let comment = comments.next_if(|span| in_line(span));
+
+        let spans_lines = line_tokens.iter().any(|token| token.span.end > line_end)
+            || comment.is_some_and(|span| span.end > line_end);
+        // The tokenizer drops a continuation `\` without a token, so it can't be rebuilt
+        let content_end = line_start + line.trim_end_matches(['\r', '\n']).len();
+        let continues = source[..content_end].ends_with('\\')
+            && comment.is_none_or(|span| span.end < content_end);
+        if spans_lines || continued || continues {
+            output.push_str(line);
+        } else {
+            output.push_str(&format_line(source, line, &line_tokens, comment, config));
+            output.push_str(&line[line.trim_end_matches(['\r', '\n']).len()..]);
+        }
+
+        line_start = line_end;
+        continued = continues;
+    }
+
+    Some(output)
+}
+
+fn format_line(
+    source: &str,
+    line: &str,
+    tokens: &[&Token],
+    comment: Option<&Span>,
+    config: &FormatConfig,
+) -> String {
+    let comment = comment.map(|span| &source[span.start..span.end]);
+    let Some(first) = tokens.first() else {
+        // Comment-only lines keep column 0 when they started there, otherwise they are indented
+        return match comment {
+            Some(comment) if line.starts_with(';') => comment.to_string(),
+            Some(comment) => format!("{}{comment}", " ".repeat(config.indent)),
+            None => String::new(),
+        };
+    };
+
+    let mut code = String::new();
+    let mut rest = tokens;
+    let is_label = rest.len() > 1
+        && first.token_type == TokenType::Identifier
+        && rest[1].token_type == TokenType::Colon;
+    let is_assignment = rest.len() > 1
+        && first.token_type == TokenType::Identifier
+        && matches!(rest[1].token_type, TokenType::Equal | TokenType::ConstAssign);
+    let is_unnamed_label = first.token_type == TokenType::Colon;
+
+    if is_label {
+        code.push_str(&source[first.span.start..rest[1].span.end]);
+        rest = &rest[2..];
+    } else if is_unnamed_label {
+        code.push(':');
+        rest = &rest[1..];
+    }
+
+    if is_assignment {
+        code.push_str(&join_tokens(source, rest));
+    } else if let Some((statement, operands)) = rest.split_first() {
+        // Columns count characters, so a label with multibyte characters isn't under-indented
+        let column = code.chars().count();
+        if column >= config.indent {
+            code.push(' ');
+        } else {
+            code.push_str(&" ".repeat(config.indent - column));
+        }
+        code.push_str(&source[statement.span.start..statement.span.end]);
+        if !operands.is_empty() {
+            code.push(' ');
+            code.push_str(&join_tokens(source, operands));
+        }
+    }
+
+    match comment {
+        Some(comment) if code.chars().count() < config.comment_column => {
+            format!("{code:width$}{comment}", width = config.comment_column)
+        }
+        Some(comment) => format!("{code} {comment}"),
+        None => code,
+    }
+}
+
+// Keeps tokens that were written together joined, and collapses any gap between them to one space
+fn join_tokens(source: &str, tokens: &[&Token]) -> String {
+    let mut joined = String::new();
+    let mut previous_end = None;
+    for token in tokens {
+        if previous_end.is_some_and(|end| end < token.span.start) {
+            joined.push(' ');
+        }
+        joined.push_str(&source[token.span.start..token.span.end]);
+        previous_end = Some(token.span.end);
+    }
+
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_messy_source() {
+        let source = "start:   lda   #1   ; load\n  FOO = 2\n    .byte 1,2\n: bne :-\n;top\n";
+        let expected = concat!(
+            "start:  lda #1                  ; load\n",
+            "FOO = 2\n",
+            "        .byte 1,2\n",
+            ":       bne :-\n",
+            ";top\n",
+        );
+
+        let formatted = format_source(source, &FormatConfig::default()).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(
+            format_source(&formatted, &FormatConfig::default()).unwrap(),
+            formatted
+        );
+    }
+
+    #[test]
+    fn leaves_continued_lines_unchanged() {
+        let source = "  .byte 1, \\\n      2\nlabel:  nop ; done \\\n";
+        let expected = "  .byte 1, \\\n      2\nlabel:  nop                     ; done \\\n";
+
+        assert_eq!(
+            format_source(source, &FormatConfig::default()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn aligns_comments_by_characters_rather_than_bytes() {
+        let source = ".byte \"été\" ; x\n";
+        let expected = "        .byte \"été\"             ; x\n";
+
+        assert_eq!(
+            format_source(source, &FormatConfig::default()).unwrap(),
+            expected
+        );
+    }
+}
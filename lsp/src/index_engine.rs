@@ -1,22 +1,36 @@
+use crate::analysis::calls::CallCollector;
+use crate::analysis::scope_analyzer::{REPEAT_SCOPE, ScopeAnalyzer};
+use crate::analysis::symbol_resolver::SymbolResolver;
 use crate::data::convert_uri::convert_uri;
 use crate::data::files::Files;
-use crate::data::symbol::Symbol;
+use crate::data::symbol::{Symbol, SymbolType, resolve_symbol};
 use crate::state::State;
-use codespan::FileId;
+use codespan::{FileId, Span};
 use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tower_lsp_server::Client;
 use tower_lsp_server::lsp_types::request::WorkDoneProgressCreate;
 use tower_lsp_server::lsp_types::{
-    Diagnostic, InlayHintWorkspaceClientCapabilities, ProgressToken, Uri,
+    Diagnostic, DiagnosticSeverity, InlayHintWorkspaceClientCapabilities, ProgressToken, Uri,
     WorkDoneProgressCreateParams, WorkspaceClientCapabilities,
 };
 use uuid::Uuid;
 
+/// The definitions `(file, span)` referenced from each unit, keyed by the unit's file.
+pub type ReferencedSymbols = HashMap<FileId, HashSet<(FileId, Span)>>;
+
+/// A `jsr`/`jmp` or macro invocation at `span`, made from the body of `caller`.
+pub struct Call {
+    pub caller: Symbol,
+    pub target: Symbol,
+    pub file: FileId,
+    pub span: Span,
+}
+
 pub struct IndexEngine {
     pub state: Arc<Mutex<State>>,
 }
@@ -54,15 +68,12 @@ impl IndexEngine {
                 continue;
             }
 
-            if let Some("s" | "asm" | "inc" | "incs") =
-                file.path().extension().and_then(OsStr::to_str)
-            {
+            if IndexEngine::is_source_file(file.path()) {
                 sources.push(file);
             }
         }
 
         let mut state = data.state.lock().await;
-        let mut diagnostics = HashMap::new();
         let mut parsed_files = vec![];
 
         for (idx, file) in sources.iter().enumerate() {
@@ -76,8 +87,7 @@ impl IndexEngine {
             let uri = Uri::from_str(url::Url::from_file_path(file).unwrap().as_str()).unwrap();
             let contents = std::fs::read_to_string(file).unwrap();
             let id = state.get_or_insert_source(convert_uri(uri).unwrap(), contents);
-            let file = state.files.index(id).await;
-            diagnostics.insert(id, file.diagnostics);
+            state.files.index(id).await;
             parsed_files.push(id);
         }
 
@@ -94,14 +104,21 @@ impl IndexEngine {
             state.client.inlay_hint_refresh().await.unwrap();
         }
 
-        for id in parsed_files.iter() {
+        IndexEngine::index_units(&mut state, &parsed_files).await;
+
+        progress.finish().await;
+    }
+
+    /// Makes a unit of every `.s` file in `files`, which are already indexed, then lints them all.
+    pub async fn index_units(state: &mut State, files: &[FileId]) {
+        for id in files.iter() {
             let uri = state.files.get_uri(*id);
             let path = PathBuf::from_str(uri.path().as_str()).unwrap();
             if let Some(ext) = path.extension()
                 && ext.to_str() == Some("s")
             {
                 let (deps, dep_diagnostics) = IndexEngine::calculate_deps(&mut state.files, *id);
-                diagnostics.insert(*id, dep_diagnostics);
+                state.files.get_mut(*id).diagnostics.extend(dep_diagnostics);
                 state.units.insert(*id, deps);
             }
         }
@@ -112,19 +129,19 @@ impl IndexEngine {
             state.units[unit].symbols = symbols;
         }
 
-        for id in parsed_files.iter() {
-            state
-                .publish_diagnostics(
-                    *id,
-                    diagnostics
-                        .get(id)
-                        .and_then(|d| Some(d.clone()))
-                        .unwrap_or_default(),
-                )
-                .await;
+        let referenced = IndexEngine::find_referenced_symbols(state, state.units.0.keys().copied());
+        for id in files.iter() {
+            let mut diagnostics = state.files.get(*id).diagnostics.clone();
+            diagnostics.extend(IndexEngine::lint(state, *id, &referenced));
+            state.publish_diagnostics(*id, diagnostics).await;
         }
+    }
 
-        progress.finish().await;
+    pub fn is_source_file(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(OsStr::to_str),
+            Some("s" | "asm" | "inc" | "incs")
+        )
     }
 
     pub async fn invalidate(state: &mut State, file: FileId) -> Vec<Diagnostic> {
@@ -133,8 +150,6 @@ impl IndexEngine {
         let (resolved_imports, import_diagnostics) = state.files.resolve_import_paths(file);
         diagnostics.extend(import_diagnostics);
 
-        diagnostics.extend(state.files.get_mut(file).lint().await);
-
         let file = state.files.get_mut(file);
         if resolved_imports.iter().ne(&file.resolved_includes) {
             file.resolved_includes = resolved_imports;
@@ -143,13 +158,212 @@ impl IndexEngine {
         diagnostics
     }
 
+    /// The diagnostics for `file` that depend on the symbols of its units rather than only on its
+    /// own source, so they need refreshing whenever another file in those units changes.
+    /// `referenced` must hold every unit containing `file`.
+    pub fn lint(state: &State, file: FileId, referenced: &ReferencedSymbols) -> Vec<Diagnostic> {
+        let mut diagnostics = IndexEngine::find_unused_imports(state, file, referenced);
+        diagnostics.extend(state.files.get(file).find_unknown_directive_arguments());
+        diagnostics.extend(IndexEngine::find_unavailable_instructions(state, file));
+        diagnostics.extend(state.files.get(file).find_branches_out_of_range());
+        diagnostics.extend(IndexEngine::find_include_cycles(state, file));
+        diagnostics
+    }
+
+    /// Flags each `.include` in `file` that leads back to `file`, listing the files on the way.
+    pub fn find_include_cycles(state: &State, file: FileId) -> Vec<Diagnostic> {
+        let cache_file = state.files.get(file);
+        cache_file
+            .resolved_includes
+            .iter()
+            .filter_map(|include| {
+                let cycle = IndexEngine::find_include_path(
+                    &state.files,
+                    include.file,
+                    file,
+                    &mut HashSet::new(),
+                )?;
+                let chain = std::iter::once(file)
+                    .chain(cycle)
+                    .map(|id| {
+                        state
+                            .files
+                            .get_uri_relative(id, file)
+                            .unwrap_or_else(|| state.files.get_uri(id).as_str().to_string())
+                    })
+                    .collect::<Vec<_>>();
+
+                Some(Diagnostic {
+                    range: cache_file
+                        .file
+                        .byte_span_to_range(include.token.span)
+                        .ok()?
+                        .into(),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    message: format!("Circular include: {}", chain.join(" -> ")),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    // The files from `from` to `to` through resolved includes, both ends included
+    fn find_include_path(
+        files: &Files,
+        from: FileId,
+        to: FileId,
+        visited: &mut HashSet<FileId>,
+    ) -> Option<Vec<FileId>> {
+        if from == to {
+            return Some(vec![to]);
+        }
+        if !visited.insert(from) {
+            return None;
+        }
+
+        files
+            .get(from)
+            .resolved_includes
+            .iter()
+            .find_map(|include| {
+                let mut path = IndexEngine::find_include_path(files, include.file, to, visited)?;
+                path.insert(0, from);
+                Some(path)
+            })
+    }
+
+    /// Resolves the identifiers of every file in each of `units` once, so that linting the files of
+    /// a unit doesn't have to walk the whole unit again for each one.
+    pub fn find_referenced_symbols(
+        state: &State,
+        units: impl IntoIterator<Item = FileId>,
+    ) -> ReferencedSymbols {
+        let mut referenced = HashMap::new();
+        for unit in units {
+            if referenced.contains_key(&unit) {
+                continue;
+            }
+            let symbols = &state.units[unit].symbols;
+            let mut unit_referenced = HashSet::new();
+            for id in std::iter::once(unit).chain(state.units[unit].deps.iter().copied()) {
+                for identifier in SymbolResolver::find_identifiers(&state.files.get(id).ast) {
+                    if let Some(symbol) =
+                        resolve_symbol(symbols, &identifier.scope, &identifier.name)
+                    {
+                        unit_referenced.insert((symbol.file_id, symbol.span));
+                    }
+                }
+            }
+            referenced.insert(unit, unit_referenced);
+        }
+
+        referenced
+    }
+
+    pub fn find_unused_imports(
+        state: &State,
+        file: FileId,
+        referenced: &ReferencedSymbols,
+    ) -> Vec<Diagnostic> {
+        let units = state.units.find_related(file);
+        // Without a unit, references from other files can't be seen yet
+        if units.is_empty() {
+            return vec![];
+        }
+
+        let referenced = units
+            .iter()
+            .filter_map(|unit| referenced.get(unit))
+            .collect::<Vec<_>>();
+        state
+            .files
+            .get(file)
+            .find_unused_imports(&referenced, state.lints.unused_imports)
+    }
+
+    pub fn find_unavailable_instructions(state: &State, file: FileId) -> Vec<Diagnostic> {
+        // Another CPU's mnemonic is a fine macro name, wherever in the unit the macro is defined
+        let macros = state
+            .units
+            .find_related(file)
+            .into_iter()
+            .flat_map(|unit| state.units[unit].symbols.iter())
+            .chain(state.files.get(file).symbols.iter())
+            .filter(|symbol| matches!(symbol.sym_type, SymbolType::Macro | SymbolType::Define))
+            .filter_map(|symbol| symbol.fqn.rsplit("::").next())
+            .map(str::to_string)
+            .collect::<HashSet<_>>();
+
+        // Files outside any unit, like headers nothing includes yet, start from the default CPU
+        let mut roots = state.units.find_related(file);
+        if roots.is_empty() {
+            roots.push(file);
+        }
+
+        state.files.get(file).find_unavailable_instructions(
+            &state.files,
+            &roots,
+            &state.default_cpu,
+            &macros,
+        )
+    }
+
+    /// Collects every `(file, span)` that refers to `target` across the units containing `file`.
+    pub fn find_references(state: &State, file: FileId, target: &Symbol) -> Vec<(FileId, Span)> {
+        let mut references = HashSet::new();
+        for unit in state.units.find_related(file) {
+            let symbols = &state.units[unit].symbols;
+            for id in std::iter::once(unit).chain(state.units[unit].deps.iter().copied()) {
+                for identifier in SymbolResolver::find_identifiers(&state.files.get(id).ast) {
+                    let resolved = resolve_symbol(symbols, &identifier.scope, &identifier.name);
+                    if resolved.is_some_and(|symbol| {
+                        symbol.file_id == target.file_id && symbol.span == target.span
+                    }) {
+                        references.insert((id, identifier.span));
+                    }
+                }
+            }
+        }
+
+        references.into_iter().collect()
+    }
+
+    pub fn find_calls(state: &State, file: FileId) -> Vec<Call> {
+        let mut seen = HashSet::new();
+        let mut calls = vec![];
+        for unit in state.units.find_related(file) {
+            let symbols = &state.units[unit].symbols;
+            for id in std::iter::once(unit).chain(state.units[unit].deps.iter().copied()) {
+                let cache_file = state.files.get(id);
+                for call in CallCollector::find_calls(&cache_file.ast) {
+                    if !seen.insert((id, call.span)) {
+                        continue;
+                    }
+                    let scope = ScopeAnalyzer::search(&cache_file.scopes, call.span.start);
+                    let (Some(target), Some(caller)) = (
+                        resolve_symbol(symbols, &scope[1..], &call.name),
+                        find_caller(symbols, id, &scope, call.span.start),
+                    ) else {
+                        continue;
+                    };
+                    calls.push(Call {
+                        caller: caller.clone(),
+                        target: target.clone(),
+                        file: id,
+                        span: call.span,
+                    });
+                }
+            }
+        }
+
+        calls
+    }
+
     pub fn calculate_deps(files: &mut Files, file: FileId) -> (Vec<FileId>, Vec<Diagnostic>) {
         let mut deps = HashSet::new();
         let mut diagnostics = vec![];
+        // Cycles are reported by `find_include_cycles` when the files involved are linted
         IndexEngine::flatten_dependencies(files, file, &mut deps, &mut diagnostics);
-        if deps.contains(&file) {
-            eprintln!("Circular dependency");
-        }
 
         (deps.into_iter().collect(), diagnostics)
     }
@@ -175,7 +389,7 @@ impl IndexEngine {
     pub fn get_symbol_tree(files: &mut Files, file_id: FileId) -> Vec<Symbol> {
         let mut stack = vec!["".to_owned()];
         let mut symbols = Vec::new();
-        Self::get_symbols_for_file(files, file_id, &mut symbols, &mut stack);
+        Self::get_symbols_for_file(files, file_id, &mut symbols, &mut stack, &mut vec![]);
 
         symbols
     }
@@ -185,12 +399,18 @@ impl IndexEngine {
         file_id: FileId,
         symbols: &mut Vec<Symbol>,
         stack: &mut Vec<String>,
+        including: &mut Vec<FileId>,
     ) {
         let file = &files.get(file_id);
         let resolved_includes = file.resolved_includes.clone();
         let file_symbols = file.symbols.clone();
 
+        including.push(file_id);
         for include in resolved_includes {
+            // A circular include would never finish, so the file including itself is skipped
+            if including.contains(&include.file) {
+                continue;
+            }
             let backup = stack.clone();
             stack.extend_from_slice(
                 &include.scope[1..]
@@ -198,9 +418,10 @@ impl IndexEngine {
                     .map(|s| s.name.to_owned())
                     .collect::<Vec<_>>(),
             );
-            Self::get_symbols_for_file(files, include.file, symbols, stack);
+            Self::get_symbols_for_file(files, include.file, symbols, stack, including);
             *stack = backup;
         }
+        including.pop();
 
         for symbol in file_symbols {
             let mut symbol = symbol.clone();
@@ -212,3 +433,220 @@ impl IndexEngine {
         }
     }
 }
+
+// A call belongs to the innermost proc or macro around it, or to a label declared before it in
+// that same scope, whichever comes last
+fn find_caller<'a>(
+    symbols: &'a [Symbol],
+    file: FileId,
+    scope: &[String],
+    offset: usize,
+) -> Option<&'a Symbol> {
+    let scope_fqn = scope
+        .iter()
+        .filter(|name| name.as_str() != REPEAT_SCOPE)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("::");
+
+    symbols
+        .iter()
+        .filter(|symbol| symbol.file_id == file && symbol.span.start < offset)
+        .filter(|symbol| {
+            symbol.fqn == scope_fqn
+                || matches!(symbol.sym_type, SymbolType::Label)
+                    && symbol
+                        .fqn
+                        .rsplit_once("::")
+                        .is_some_and(|(parent, name)| parent == scope_fqn && !name.starts_with('@'))
+        })
+        .max_by_key(|symbol| symbol.span.start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::configuration::LintLevel;
+    use crate::test_support::load;
+
+    fn unused_imports(state: &State, file: FileId) -> Vec<String> {
+        let referenced =
+            IndexEngine::find_referenced_symbols(state, state.units.find_related(file));
+        IndexEngine::find_unused_imports(state, file, &referenced)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn imports_used_anywhere_in_the_unit_are_not_flagged() {
+        let (service, ids) = load(&[
+            ("main.s", ".include \"defs.inc\"\njsr used\n"),
+            ("defs.inc", ".import used, unused\n"),
+        ])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        assert_eq!(
+            unused_imports(&state, ids[1]),
+            vec!["Unused import: unused"]
+        );
+        assert!(unused_imports(&state, ids[0]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn imports_used_in_a_macro_body_are_not_flagged() {
+        let (service, ids) = load(&[(
+            "main.s",
+            ".import from_macro, unused\n.macro call\n    jsr from_macro\n.endmacro\n",
+        )])
+        .await;
+        let state = service.inner().state().lock().await;
+
+        assert_eq!(
+            unused_imports(&state, ids[0]),
+            vec!["Unused import: unused"]
+        );
+    }
+
+    async fn unavailable_instructions(source: &str) -> Vec<String> {
+        let (service, ids) = load(&[("main.s", source)]).await;
+        let state = service.inner().state().lock().await;
+        IndexEngine::find_unavailable_instructions(&state, ids[0])
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn instructions_are_checked_against_the_active_cpu() {
+        assert_eq!(
+            unavailable_instructions(".p02\nloop: bra loop\n").await,
+            vec!["Instruction not available for 6502: bra"]
+        );
+        assert!(
+            unavailable_instructions(".pc02\nloop: bra loop\n")
+                .await
+                .is_empty()
+        );
+        assert!(
+            unavailable_instructions(".p816\nloop: bra loop\nstz $10\nxba\n")
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn macros_named_like_other_cpus_instructions_are_not_flagged() {
+        assert!(
+            unavailable_instructions(".macro xba\n.endmacro\n.p02\nxba\n")
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            unavailable_instructions(".p02\nxba\n").await,
+            vec!["Instruction not available for 6502: xba"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unused_imports_are_reported_at_the_configured_level() {
+        let (service, ids) = load(&[("main.s", ".import used, unused\njsr used\n")]).await;
+        let mut state = service.inner().state().lock().await;
+        let severities = |state: &State| {
+            let referenced =
+                IndexEngine::find_referenced_symbols(state, state.units.find_related(ids[0]));
+            IndexEngine::find_unused_imports(state, ids[0], &referenced)
+                .into_iter()
+                .map(|diagnostic| (diagnostic.message, diagnostic.severity))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            severities(&state),
+            vec![(
+                "Unused import: unused".to_string(),
+                Some(DiagnosticSeverity::HINT)
+            )]
+        );
+
+        state.lints.unused_imports = LintLevel::Warning;
+        assert_eq!(
+            severities(&state),
+            vec![(
+                "Unused import: unused".to_string(),
+                Some(DiagnosticSeverity::WARNING)
+            )]
+        );
+
+        state.lints.unused_imports = LintLevel::Off;
+        assert!(severities(&state).is_empty());
+    }
+
+    #[tokio::test]
+    async fn circular_includes_are_flagged_in_every_file_of_the_cycle() {
+        let (service, ids) =
+            load(&[("a.s", ".include \"b.s\"\n"), ("b.s", ".include \"a.s\"\n")]).await;
+        let state = service.inner().state().lock().await;
+        let cycles = |file: FileId| {
+            IndexEngine::find_include_cycles(&state, file)
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(cycles(ids[0]), vec!["Circular include: a.s -> b.s -> a.s"]);
+        assert_eq!(cycles(ids[1]), vec!["Circular include: b.s -> a.s -> b.s"]);
+    }
+
+    async fn unavailable_in(files: &[(&str, &str)]) -> Vec<Vec<(u32, String)>> {
+        let (service, ids) = load(files).await;
+        let state = service.inner().state().lock().await;
+        ids.iter()
+            .map(|id| {
+                IndexEngine::find_unavailable_instructions(&state, *id)
+                    .into_iter()
+                    .map(|diagnostic| (diagnostic.range.start.line, diagnostic.message))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn the_cpu_is_followed_across_includes() {
+        let header_sets_the_cpu = unavailable_in(&[
+            ("main.s", ".include \"cpu.inc\"\nphx\nloop: bra loop\n"),
+            ("cpu.inc", ".setcpu \"65C02\"\n"),
+        ])
+        .await;
+        assert_eq!(header_sets_the_cpu, vec![vec![], vec![]]);
+
+        let includer_sets_the_cpu = unavailable_in(&[
+            ("main.s", ".setcpu \"65C02\"\n.include \"routines.inc\"\n"),
+            ("routines.inc", "save: phx\nrts\n"),
+        ])
+        .await;
+        assert_eq!(includer_sets_the_cpu, vec![vec![], vec![]]);
+
+        let nothing_sets_the_cpu = unavailable_in(&[
+            ("main.s", ".include \"routines.inc\"\n"),
+            ("routines.inc", "save: phx\nrts\n"),
+        ])
+        .await;
+        assert_eq!(
+            nothing_sets_the_cpu,
+            vec![
+                vec![],
+                vec![(0, "Instruction not available for 6502: phx".to_string())]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pushcpu_and_popcpu_restore_the_cpu() {
+        assert_eq!(
+            unavailable_instructions(".p02\n.pushcpu\n.pc02\nphx\n.popcpu\nplx\n").await,
+            vec!["Instruction not available for 6502: plx"]
+        );
+    }
+}
@@ -6,8 +6,11 @@ mod data;
 mod definition;
 mod documentation;
 mod error;
+mod formatting;
 mod index_engine;
 mod state;
+#[cfg(test)]
+mod test_support;
 
 use asm_server::Asm;
 use data::instructions;
@@ -1,5 +1,7 @@
+use crate::data::configuration::LintConfig;
 use crate::{data::files::Files, data::units::Units};
-use codespan::FileId;
+use codespan::{FileId, PositionEncoding};
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Mutex;
 use lazy_static::lazy_static;
@@ -21,6 +23,13 @@ pub struct State {
     pub client: Client,
     pub client_capabilities: ClientCapabilities,
     pub units: Units,
+    pub position_encoding: PositionEncoding,
+    /// The CPU assumed before a file's first `.setcpu`
+    pub default_cpu: String,
+    pub lints: LintConfig,
+    diagnostics: HashMap<FileId, Vec<Diagnostic>>,
+    toolchain_diagnostics: HashMap<FileId, Vec<Diagnostic>>,
+    versions: HashMap<FileId, i32>,
 }
 
 lazy_static! {
@@ -35,8 +44,19 @@ impl State {
             client,
             client_capabilities: ClientCapabilities::default(),
             units: Units::default(),
+            position_encoding: PositionEncoding::default(),
+            default_cpu: "6502".to_string(),
+            lints: LintConfig::default(),
+            diagnostics: HashMap::new(),
+            toolchain_diagnostics: HashMap::new(),
+            versions: HashMap::new(),
         }
     }
+
+    pub fn set_position_encoding(&mut self, encoding: PositionEncoding) {
+        self.position_encoding = encoding;
+        self.files.set_position_encoding(encoding);
+    }
     pub fn get_or_insert_source(&mut self, uri: Uri, text: String) -> FileId {
         if let Some(id) = self.files.sources.get(&uri) {
             *id
@@ -67,10 +87,53 @@ impl State {
             }
         }
         self.files.update(id, source);
+        self.versions.insert(id, document.version);
         id
     }
 
+    /// The version of the last edit the client sent for a file, if it has been edited at all.
+    pub fn version(&self, id: FileId) -> Option<i32> {
+        self.versions.get(&id).copied()
+    }
+
     pub async fn publish_diagnostics(&mut self, id: FileId, diagnostics: Vec<Diagnostic>) {
+        self.diagnostics.insert(id, diagnostics);
+        self.send_diagnostics(id).await;
+    }
+
+    /// Replaces the diagnostics reported by ca65 for a file, keeping the server's own alongside them.
+    pub async fn publish_toolchain_diagnostics(
+        &mut self,
+        id: FileId,
+        diagnostics: Vec<Diagnostic>,
+    ) {
+        self.toolchain_diagnostics.insert(id, diagnostics);
+        self.send_diagnostics(id).await;
+    }
+
+    #[cfg(test)]
+    pub fn diagnostics(&self, id: FileId) -> Option<&Vec<Diagnostic>> {
+        self.diagnostics.get(&id)
+    }
+
+    #[cfg(test)]
+    pub fn toolchain_diagnostics(&self, id: FileId) -> Option<&Vec<Diagnostic>> {
+        self.toolchain_diagnostics.get(&id)
+    }
+
+    /// Forgets what ca65 reported for a file once it's edited, since its line numbers no longer match.
+    /// The next [`State::publish_diagnostics`] for the file sends the change to the client.
+    pub fn clear_toolchain_diagnostics(&mut self, id: FileId) {
+        self.toolchain_diagnostics.remove(&id);
+    }
+
+    async fn send_diagnostics(&self, id: FileId) {
+        let diagnostics = [&self.diagnostics, &self.toolchain_diagnostics]
+            .into_iter()
+            .filter_map(|diagnostics| diagnostics.get(&id))
+            .flatten()
+            .cloned()
+            .collect();
         self.client
             .publish_diagnostics(
                 Uri::from_str(self.files.get(id).file.name.as_str()).unwrap(),
@@ -0,0 +1,73 @@
+use crate::asm_server::Asm;
+use crate::data::instructions;
+use crate::documentation;
+use crate::index_engine::IndexEngine;
+use codespan::FileId;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Once;
+use tower_lsp_server::LspService;
+use tower_lsp_server::lsp_types::Uri;
+use url::Url;
+
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        instructions::init_instruction_map();
+        documentation::init();
+    });
+}
+
+/// The uri of `name` in the workspace used by [`load`].
+pub fn uri(name: &str) -> Uri {
+    Uri::from_str(&format!("file:///project/{name}")).unwrap()
+}
+
+/// Starts a server with `files` indexed as if they were found when crawling `/project`. The client
+/// isn't connected to anything, so whatever the server sends it is dropped.
+pub async fn load(files: &[(&str, &str)]) -> (LspService<Asm>, Vec<FileId>) {
+    load_uris(
+        files
+            .iter()
+            .map(|(name, source)| (uri(name), source.to_string())),
+    )
+    .await
+}
+
+/// Like [`load`], but writes `files` into `directory` first, for features that run tools on them.
+pub async fn load_on_disk(
+    directory: &Path,
+    files: &[(&str, &str)],
+) -> (LspService<Asm>, Vec<FileId>) {
+    let mut uris = vec![];
+    for (name, source) in files {
+        let path = directory.join(name);
+        fs::write(&path, source).unwrap();
+        uris.push((
+            Uri::from_str(Url::from_file_path(&path).unwrap().as_str()).unwrap(),
+            source.to_string(),
+        ));
+    }
+    load_uris(uris).await
+}
+
+async fn load_uris(
+    files: impl IntoIterator<Item = (Uri, String)>,
+) -> (LspService<Asm>, Vec<FileId>) {
+    init();
+    let (service, _) = LspService::new(Asm::new);
+
+    let mut state = service.inner().state().lock().await;
+    let ids = files
+        .into_iter()
+        .map(|(uri, source)| state.get_or_insert_source(uri, source))
+        .collect::<Vec<_>>();
+    for id in ids.iter() {
+        state.files.index(*id).await;
+    }
+    IndexEngine::index_units(&mut state, &ids).await;
+    drop(state);
+
+    (service, ids)
+}
@@ -18,6 +18,11 @@ impl Token {
             span,
         }
     }
+
+    /// Cheap local labels (`@loop`) are only visible between two regular labels
+    pub fn is_cheap_local(&self) -> bool {
+        self.lexeme.starts_with('@')
+    }
 }
 
 impl Display for Token {
@@ -49,4 +49,7 @@ pub enum TokenType {
     WordOp,
     LeftBracket,
     RightBracket,
+    Ellipsis,
+    Blank,
+    Const,
 }
\ No newline at end of file
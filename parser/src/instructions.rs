@@ -1,22 +1,102 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// A CPU that `.setcpu` can select, with the mnemonics it adds to the CPU it extends.
+struct Cpu {
+    name: &'static str,
+    base: Option<&'static str>,
+    mnemonics: HashSet<String>,
+}
 
 pub struct Instructions {
     instructions: HashMap<String, String>,
+    cpus: Vec<Cpu>,
+}
+
+fn parse_table(raw: &str) -> HashMap<String, String> {
+    let lines: Vec<_> = raw.lines().collect();
+    let mut instructions = HashMap::new();
+    for group in lines.chunks(2) {
+        instructions.insert(group[0].to_string().to_lowercase(), group[1].to_string());
+    }
+    instructions
 }
 
 impl Instructions {
     pub fn load() -> Instructions {
-        let instructions_raw = include_str!("../../lsp/instructions/6502.txt");
-        let lines: Vec<_> = instructions_raw.lines().collect();
-        let mut instructions = HashMap::new();
-        for group in lines.chunks(2) {
-            instructions.insert(group[0].to_string().to_lowercase(), group[1].to_string());
+        let instructions = parse_table(include_str!("../../lsp/instructions/6502.txt"));
+        let extensions = [
+            (
+                "6502X",
+                "6502",
+                include_str!("../../lsp/instructions/6502x.txt"),
+            ),
+            (
+                "65SC02",
+                "6502",
+                include_str!("../../lsp/instructions/65sc02.txt"),
+            ),
+            (
+                "65C02",
+                "65SC02",
+                include_str!("../../lsp/instructions/65c02.txt"),
+            ),
+            (
+                "65816",
+                "65SC02",
+                include_str!("../../lsp/instructions/65816.txt"),
+            ),
+        ];
+
+        let mut cpus = vec![Cpu {
+            name: "6502",
+            base: None,
+            mnemonics: instructions.keys().cloned().collect(),
+        }];
+        for (name, base, raw) in extensions {
+            cpus.push(Cpu {
+                name,
+                base: Some(base),
+                mnemonics: parse_table(raw).into_keys().collect(),
+            });
         }
-        Instructions { instructions }
+
+        Instructions { instructions, cpus }
     }
 
+    /// Whether the tokenizer should treat `mnemonic` as an instruction. Only the 6502 set is
+    /// recognized, so mnemonics from other CPUs stay free to be used as macro names.
     pub fn is_instruction(&self, mnemonic: String) -> bool {
         self.instructions
             .contains_key(mnemonic.to_lowercase().as_str())
     }
+
+    /// Whether `mnemonic` is an instruction on any of the known CPUs.
+    pub fn is_any_cpu_instruction(&self, mnemonic: &str) -> bool {
+        let mnemonic = mnemonic.to_lowercase();
+        self.cpus
+            .iter()
+            .any(|cpu| cpu.mnemonics.contains(&mnemonic))
+    }
+
+    /// Whether `mnemonic` is available on `cpu` (as named by `.setcpu`), or `None` when there is no
+    /// table for that CPU.
+    pub fn is_cpu_instruction(&self, mnemonic: &str, cpu: &str) -> Option<bool> {
+        let mnemonic = mnemonic.to_lowercase();
+        let mut current = self.find_cpu(cpu)?;
+        loop {
+            if current.mnemonics.contains(&mnemonic) {
+                return Some(true);
+            }
+            match current.base {
+                Some(base) => current = self.find_cpu(base)?,
+                None => return Some(false),
+            }
+        }
+    }
+
+    fn find_cpu(&self, name: &str) -> Option<&Cpu> {
+        self.cpus
+            .iter()
+            .find(|cpu| cpu.name.eq_ignore_ascii_case(name))
+    }
 }
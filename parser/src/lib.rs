@@ -1,10 +1,12 @@
 pub mod data;
 pub mod instructions;
+pub mod number;
 pub mod parser;
 pub mod stream;
 pub mod tokenizer;
 
 pub use data::*;
 pub use instructions::*;
+pub use number::*;
 pub use parser::*;
 pub use tokenizer::*;
@@ -1,23 +1,23 @@
 use parser::{Instructions, ParseError, Tokenizer, TokenizerError};
 
 fn main() {
-    let mut args = std::env::args();
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "test.s".to_string());
     let instructions = Instructions::load();
 
-    // if args.len() < 2 {
-    //     eprintln!("Usage: parser <file>");
-    //     std::process::exit(1);
-    // }
-
-    let buf = std::fs::read_to_string("test.s").unwrap();
-    let cs_file = codespan::File::new("test", buf);
+    let buf = std::fs::read_to_string(&path).unwrap();
+    let cs_file = codespan::File::new(&path, buf);
 
     let mut tokenizer = Tokenizer::new(&cs_file.source, &instructions);
     match tokenizer.parse() {
         Ok(tokens) => {
             let mut parser = parser::Parser::new(&tokens);
-            let ast = parser.parse();
+            let (ast, errors) = parser.parse();
             println!("{:#?}", ast);
+            for error in errors {
+                print_parse_error(&cs_file, error);
+            }
         }
         Err(e) => {
             print_error(&cs_file, e);
@@ -44,6 +44,14 @@ fn print_parse_error(file: &codespan::File, error: ParseError) {
             println!("Unexpected token {:?}", token);
             print_error_offset(file, token.span.start);
         }
+        ParseError::MismatchedTerminator { opener, terminator } => {
+            println!("{} block closed with {}", opener.lexeme, terminator.lexeme);
+            print_error_offset(file, terminator.span.start);
+        }
+        ParseError::UnterminatedBlock { opener } => {
+            println!("Unterminated {} block", opener.lexeme);
+            print_error_offset(file, opener.span.start);
+        }
     }
 }
 
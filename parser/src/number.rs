@@ -0,0 +1,83 @@
+/// Returns the radix of a numeric literal along with its digits, without the radix prefix.
+pub fn split_radix(lexeme: &str) -> (u32, &str) {
+    if let Some(digits) = lexeme
+        .strip_prefix('$')
+        .or_else(|| lexeme.strip_prefix("0x"))
+        .or_else(|| lexeme.strip_prefix("0X"))
+    {
+        (16, digits)
+    } else if let Some(digits) = lexeme
+        .strip_prefix('%')
+        .or_else(|| lexeme.strip_prefix("0b"))
+        .or_else(|| lexeme.strip_prefix("0B"))
+    {
+        (2, digits)
+    } else {
+        (10, lexeme)
+    }
+}
+
+pub fn is_digit(c: char, radix: u32) -> bool {
+    c == '_' || c.is_digit(radix)
+}
+
+/// Parses a numeric literal (`$ff`, `0xff`, `%1010`, `0b1010`, `255`) or a character constant (`'A'`, `'\n'`).
+pub fn parse_number(lexeme: &str) -> Option<u32> {
+    if let Some(inner) = lexeme
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+    {
+        // The lexeme is as written, so escapes are decoded the way the tokenizer reads them
+        let c = match inner.strip_prefix('\\') {
+            Some("\\") => '\\',
+            Some("\"") => '"',
+            Some("'") => '\'',
+            Some("n") => '\n',
+            Some("t") => '\t',
+            Some("0") => '\0',
+            Some(_) => return None,
+            None => {
+                let mut chars = inner.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return None,
+                }
+            }
+        };
+        return Some(c as u32);
+    }
+
+    let (radix, digits) = split_radix(lexeme);
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| is_digit(c, radix)) {
+        return None;
+    }
+
+    u32::from_str_radix(&digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_character_constants() {
+        assert_eq!(parse_number("'A'"), Some(0x41));
+        assert_eq!(parse_number(r"'\''"), Some(0x27));
+        assert_eq!(parse_number(r"'\\'"), Some(0x5c));
+        assert_eq!(parse_number(r"'\n'"), Some(0x0a));
+        assert_eq!(parse_number(r"'\0'"), Some(0));
+        assert_eq!(parse_number("'AB'"), None);
+    }
+
+    #[test]
+    fn evaluates_escaped_character_constant_tokens() {
+        let instructions = crate::Instructions::load();
+        let tokens = crate::Tokenizer::new(r"'\''", &instructions)
+            .parse()
+            .unwrap();
+
+        assert_eq!(tokens[0].token_type, crate::TokenType::Number);
+        assert_eq!(parse_number(&tokens[0].lexeme), Some(0x27));
+    }
+}
@@ -44,6 +44,13 @@ pub enum ParseError {
         expected: TokenType,
         received: Token,
     },
+    MismatchedTerminator {
+        opener: Token,
+        terminator: Token,
+    },
+    UnterminatedBlock {
+        opener: Token,
+    },
     EOF,
 }
 
@@ -56,6 +63,19 @@ impl Display for ParseError {
 
 type Result<T> = std::result::Result<T, ParseError>;
 
+const BLOCK_TERMINATORS: &[&str] = &[
+    ".endproc",
+    ".endscope",
+    ".endrepeat",
+    ".endrep",
+    ".endmacro",
+    ".endmac",
+    ".endenum",
+    ".endstruct",
+    ".endunion",
+    ".endif",
+];
+
 pub struct TokenStream<'a> {
     tokens: &'a Vec<Token>,
     position: usize,
@@ -84,6 +104,11 @@ impl<'a> TokenStream<'a> {
         None
     }
 
+    // The token `offset` places after the next one, without consuming anything
+    pub fn peek_ahead(&self, offset: usize) -> Option<Token> {
+        self.tokens.get(self.position + offset).cloned()
+    }
+
     pub fn previous(&self) -> Result<Token> {
         if self.position > 0 {
             Ok(self.tokens[self.position - 1].clone())
@@ -102,6 +127,8 @@ pub struct ConstantAssign {
     pub name: Token,
     pub value: Expression,
     pub span: Span,
+    /// Assigned with `.set`, which may be repeated to change the value
+    pub reassignable: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -112,6 +139,7 @@ pub enum ExpressionKind {
     UnnamedLabelReference(i8),
     Group(Box<Expression>),
     MemoryAccess(Box<Expression>),
+    IndexedIndirect(Box<Expression>, Token),
     UnaryPositive(Box<Expression>),
     Math(TokenType, Box<Expression>, Box<Expression>),
     Not(Box<Expression>),
@@ -130,6 +158,8 @@ pub enum ExpressionKind {
     Call(String, Vec<Expression>),
     WordOp(Token, Box<Expression>),
     PseudoFunction(Token, Vec<Expression>),
+    Blank(Box<Expression>),
+    Const(Box<Expression>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -157,6 +187,7 @@ pub enum IfKind {
     NoParams,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct IfStatement {
     pub kind: IfKind,
     pub if_body: Vec<Statement>,
@@ -171,7 +202,7 @@ pub enum StatementKind {
     Label(Token),
     UnnamedLabel,
     Instruction(Instruction),
-    Procedure(Token, bool, Vec<Statement>),
+    Procedure(Token, AddressSize, Vec<Statement>),
     Enum(Option<Token>, Vec<EnumMember>),
     Macro,
     SetCPU(String),
@@ -180,13 +211,13 @@ pub enum StatementKind {
     Reserve(Expression, Option<Expression>),
 
     MacroInvocation(MacroInvocation),
-    MacroPack(String),
-    Feature(String),
+    MacroPack(Token),
+    Feature(Token),
     Scope(Option<Token>, Vec<Statement>),
-    IncludeBinary(Token, Option<Token>, Option<Token>),
-    MacroDefinition(Token, Vec<Token>, Vec<Statement>),
-    Data(Vec<Expression>),
-    Org(String),
+    IncludeBinary(Token, Option<Expression>, Option<Expression>),
+    MacroDefinition(Token, Vec<Token>, bool, Vec<Statement>),
+    Data(DataKind, Vec<Expression>),
+    Org(Expression),
     Repeat(Expression, Option<Token>, Vec<Statement>),
     Global {
         identifiers: Vec<Token>,
@@ -197,25 +228,89 @@ pub enum StatementKind {
         zero_page: bool,
     },
     Ascii(Token),
-    If(IfKind, Vec<Statement>),
+    If(IfStatement),
     Struct(Token, Vec<StructMember>),
     Import {
         imports: Vec<ImportExport>,
         zero_page: bool,
     },
     Define(Token, Option<Vec<Token>>, Expression),
+    PushSeg,
+    PopSeg,
+    PushCpu,
+    PopCpu,
+    ConDes {
+        name: Token,
+        kind: ConDesKind,
+        priority: Option<Expression>,
+    },
+    Undefine(Token),
+    DelMacro(Token),
+    ExitMacro,
+    Local(Vec<Token>),
+    CpuState(CpuState),
+    Assert {
+        condition: Expression,
+        action: Token,
+        message: Option<Token>,
+    },
+    Charmap {
+        index: Expression,
+        code: Expression,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Segment {
     Literal(String),
-    Identifier(Token),
+    Identifier(Token, Option<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConDesKind {
+    Constructor,
+    Destructor,
+    Interruptor,
+    Other(Expression),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSize {
+    Near,
+    Far,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuState {
+    AccumulatorWidth(u8),
+    IndexWidth(u8),
+    Smart(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataKind {
+    Byte,
+    Word,
+    Dword,
+    Dbyt,
+    LoBytes,
+    HiBytes,
+    Addr,
+    FarAddr,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum StructMember {
     Struct(Statement),
-    Field(Token), // TODO: Add data type
+    Field {
+        name: Token,
+        /// The storage directive, such as `.byte`, `.res` or `.tag`
+        data_type: Token,
+        /// The struct embedded with `.tag`
+        tag: Option<Token>,
+        /// The multiplier after the directive, or the byte count of `.res`
+        count: Option<Expression>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -248,32 +343,39 @@ pub struct MacroInvocation {
 
 pub struct Parser<'a> {
     tokens: TokenStream<'a>,
+    macro_depth: usize,
+    // Whether `a`, `x`, `y` and `s` name registers, which they only do in operands
+    in_operands: bool,
+    errors: Vec<ParseError>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(tokens: &'a Vec<Token>) -> Self {
         Self {
             tokens: TokenStream::new(tokens),
+            macro_depth: 0,
+            in_operands: false,
+            errors: vec![],
         }
     }
 
     pub fn parse(&mut self) -> (Ast, Vec<ParseError>) {
         let mut lines = vec![];
-        let mut errors = vec![];
 
         while !self.tokens.at_end() {
+            let line_start = self.tokens.position;
             let result = self.parse_line();
             match result {
                 Ok(Some(operation)) => lines.push(operation),
                 Ok(None) => (),
                 Err(e) => {
-                    errors.push(e);
-                    self.error_recovery();
+                    self.error_recovery(&e, line_start);
+                    self.errors.push(e);
                 }
             }
         }
 
-        (lines, errors)
+        (lines, std::mem::take(&mut self.errors))
     }
 
     fn parse_line(&mut self) -> Result<Option<Statement>> {
@@ -347,8 +449,7 @@ impl<'a> Parser<'a> {
                     }))
                 }
                 ".macpack" => {
-                    self.consume_token(TokenType::Identifier)?;
-                    let pack = self.last().lexeme;
+                    let pack = self.consume_token(TokenType::Identifier)?;
                     let end = self.mark_end();
                     self.consume_newline()?;
                     Ok(Some(Statement {
@@ -357,8 +458,9 @@ impl<'a> Parser<'a> {
                     }))
                 }
                 ".feature" => {
-                    self.consume_token(TokenType::Identifier)?;
-                    let feature = self.last().lexeme;
+                    let feature = self.consume_token(TokenType::Identifier)?;
+                    // `-` turns the feature off, and `+` spells out turning it on
+                    match_token!(self.tokens, TokenType::Plus | TokenType::Minus);
                     let end = self.mark_end();
                     self.consume_newline()?;
                     Ok(Some(Statement {
@@ -382,10 +484,10 @@ impl<'a> Parser<'a> {
                     let mut bin_offset = None;
                     let mut bin_end = None;
                     if match_token!(self.tokens, TokenType::Comma) {
-                        bin_offset = Some(self.consume_token(TokenType::Number)?);
+                        bin_offset = Some(self.parse_expression()?);
                     }
                     if match_token!(self.tokens, TokenType::Comma) {
-                        bin_end = Some(self.consume_token(TokenType::Number)?);
+                        bin_end = Some(self.parse_expression()?);
                     }
                     let end = self.mark_end();
                     self.consume_newline()?;
@@ -396,7 +498,7 @@ impl<'a> Parser<'a> {
                 }
                 ".setcpu" => {
                     self.consume_token(TokenType::String)?;
-                    let cpu = self.last().lexeme;
+                    let cpu = self.last().lexeme.trim_matches('"').to_string();
                     let end = self.mark_end();
                     self.consume_newline()?;
 
@@ -406,12 +508,12 @@ impl<'a> Parser<'a> {
                     }))
                 }
                 ".org" => {
-                    let address = self.consume_token(TokenType::Number)?;
+                    let address = self.parse_expression()?;
                     let end = self.mark_end();
                     self.consume_newline()?;
 
                     Ok(Some(Statement {
-                        kind: StatementKind::Org(address.lexeme.clone()),
+                        kind: StatementKind::Org(address),
                         span: Span::new(start, end),
                     }))
                 }
@@ -425,11 +527,122 @@ impl<'a> Parser<'a> {
                     }
                     // self.consume_token(TokenType::String)?;
                     let segment = self.last();
+                    // Optional address size, e.g. `.segment "ZP": zeropage`
+                    let attribute = if match_token!(self.tokens, TokenType::Colon) {
+                        Some(self.consume_token(TokenType::Identifier)?)
+                    } else {
+                        None
+                    };
                     let end = self.mark_end();
                     self.consume_newline()?;
 
                     Ok(Some(Statement {
-                        kind: StatementKind::Segment(Segment::Identifier(segment)),
+                        kind: StatementKind::Segment(Segment::Identifier(segment, attribute)),
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".pushseg" => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::PushSeg,
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".popseg" => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::PopSeg,
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".pushcpu" => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::PushCpu,
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".popcpu" => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::PopCpu,
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".condes" | ".constructor" | ".destructor" | ".interruptor" => {
+                    Ok(Some(self.parse_condes()?))
+                }
+                ".assert" => {
+                    let condition = self.parse_expression()?;
+                    self.consume_token(TokenType::Comma)?;
+                    let action = self.consume_token(TokenType::Identifier)?;
+                    if !matches!(
+                        action.lexeme.to_lowercase().as_str(),
+                        "error" | "warning" | "lderror" | "ldwarning"
+                    ) {
+                        return Err(ParseError::UnexpectedToken(action));
+                    }
+                    let message = if match_token!(self.tokens, TokenType::Comma) {
+                        Some(self.consume_token(TokenType::String)?)
+                    } else {
+                        None
+                    };
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::Assert {
+                            condition,
+                            action,
+                            message,
+                        },
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".undefine" | ".undef" => {
+                    let ident = self.consume_token(TokenType::Identifier)?;
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::Undefine(ident),
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".delmacro" | ".delmac" => {
+                    let ident = self.consume_token(TokenType::Identifier)?;
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::DelMacro(ident),
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".exitmacro" | ".exitmac" if self.macro_depth > 0 => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::ExitMacro,
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".local" => {
+                    let identifiers = self.parse_parameters_tokens()?;
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::Local(identifiers),
                         span: Span::new(start, end),
                     }))
                 }
@@ -438,18 +651,28 @@ impl<'a> Parser<'a> {
                 ".proc" => {
                     self.consume_token(TokenType::Identifier)?;
                     let ident = self.last();
-                    let far = if match_token!(self.tokens, TokenType::Colon) {
-                        self.consume_token(TokenType::Identifier)?;
-                        self.last().lexeme == "far"
+                    let address_size = if match_token!(self.tokens, TokenType::Colon) {
+                        let attribute = self.consume_token(TokenType::Identifier)?;
+                        match attribute.lexeme.to_lowercase().as_str() {
+                            "near" => AddressSize::Near,
+                            "far" => AddressSize::Far,
+                            _ => {
+                                return Err(ParseError::Expected {
+                                    expected: TokenType::Identifier,
+                                    received: attribute,
+                                });
+                            }
+                        }
                     } else {
-                        false
+                        AddressSize::Near
                     };
 
                     self.consume_newline()?;
-                    let commands: Vec<Statement> = self.parse_statement_block(&[".endproc"])?;
+                    let commands: Vec<Statement> =
+                        self.parse_statement_block(&mac, &[".endproc"])?;
                     let end = self.mark_end();
                     return Ok(Some(Statement {
-                        kind: StatementKind::Procedure(ident, far, commands),
+                        kind: StatementKind::Procedure(ident, address_size, commands),
                         span: Span::new(start, end),
                     }));
                 }
@@ -460,7 +683,7 @@ impl<'a> Parser<'a> {
                         None
                     };
                     self.consume_newline()?;
-                    let commands = self.parse_statement_block(&[".endscope"])?;
+                    let commands = self.parse_statement_block(&mac, &[".endscope"])?;
                     let end = self.mark_end();
                     return Ok(Some(Statement {
                         kind: StatementKind::Scope(ident, commands),
@@ -476,7 +699,7 @@ impl<'a> Parser<'a> {
                         None
                     };
                     self.consume_newline()?;
-                    let commands = self.parse_statement_block(&[".endrepeat", ".endrep"])?;
+                    let commands = self.parse_statement_block(&mac, &[".endrepeat", ".endrep"])?;
                     let end = self.mark_end();
                     return Ok(Some(Statement {
                         kind: StatementKind::Repeat(max, iter, commands),
@@ -520,14 +743,25 @@ impl<'a> Parser<'a> {
                         span: Span::new(start, end),
                     }))
                 }
-                ".db" | ".dw" | ".byte" | ".word" | ".dword" | ".lobytes" => {
+                ".db" | ".dw" | ".byte" | ".word" | ".dword" | ".dbyt" | ".lobytes"
+                | ".hibytes" | ".addr" | ".faraddr" => {
+                    let kind = match macro_matcher.as_str() {
+                        ".db" | ".byte" => DataKind::Byte,
+                        ".dw" | ".word" => DataKind::Word,
+                        ".dword" => DataKind::Dword,
+                        ".dbyt" => DataKind::Dbyt,
+                        ".lobytes" => DataKind::LoBytes,
+                        ".hibytes" => DataKind::HiBytes,
+                        ".addr" => DataKind::Addr,
+                        ".faraddr" => DataKind::FarAddr,
+                        _ => unreachable!("data directives do not match the data arm"),
+                    };
                     let parameters = self.parse_parameters()?;
                     let end = self.mark_end();
                     self.consume_newline()?;
 
-                    // TODO: Add kind
                     Ok(Some(Statement {
-                        kind: StatementKind::Data(parameters),
+                        kind: StatementKind::Data(kind, parameters),
                         span: Span::new(start, end),
                     }))
                 }
@@ -547,13 +781,73 @@ impl<'a> Parser<'a> {
                 | ".ifnref" | ".ifp02" | ".ifp4510" | ".ifp816" | ".ifpC02" => {
                     Ok(Some(self.parse_if()?))
                 }
-                ".smart" | ".autoimport" => {
-                    if match_token!(self.tokens, TokenType::Plus | TokenType::Minus) {}
+                ".a8" | ".a16" | ".i8" | ".i16" => {
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    let state = match macro_matcher.as_str() {
+                        ".a8" => CpuState::AccumulatorWidth(8),
+                        ".a16" => CpuState::AccumulatorWidth(16),
+                        ".i8" => CpuState::IndexWidth(8),
+                        ".i16" => CpuState::IndexWidth(16),
+                        _ => unreachable!("register width directives do not match the width arm"),
+                    };
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::CpuState(state),
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".smart" => {
+                    let enabled = !match_token!(self.tokens, TokenType::Minus);
+                    if enabled {
+                        match_token!(self.tokens, TokenType::Plus);
+                    }
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::CpuState(CpuState::Smart(enabled)),
+                        span: Span::new(start, end),
+                    }))
+                }
+                ".autoimport" => {
+                    match_token!(self.tokens, TokenType::Plus | TokenType::Minus);
                     Ok(None)
                 }
+                ".charmap" => {
+                    let index = self.parse_expression()?;
+                    self.consume_token(TokenType::Comma)?;
+                    let code = self.parse_expression()?;
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::Charmap { index, code },
+                        span: Span::new(start, end),
+                    }))
+                }
+                // Shorthands for `.setcpu`
+                ".p02" | ".p02x" | ".psc02" | ".pc02" | ".p816" | ".p4510" | ".pdtv" => {
+                    let cpu = match macro_matcher.as_str() {
+                        ".p02" => "6502",
+                        ".p02x" => "6502X",
+                        ".psc02" => "65SC02",
+                        ".pc02" => "65C02",
+                        ".p816" => "65816",
+                        ".p4510" => "4510",
+                        _ => "6502DTV",
+                    };
+                    let end = self.mark_end();
+                    self.consume_newline()?;
+
+                    Ok(Some(Statement {
+                        kind: StatementKind::SetCPU(cpu.to_string()),
+                        span: Span::new(start, end),
+                    }))
+                }
                 // Ignored for now
-                ".local" | ".index" | ".mem" | ".align" | ".addr" | ".charmap" | ".assert"
-                | ".p816" | ".i8" | ".i16" | ".a8" | ".a16" | ".error" => {
+                ".index" | ".mem" | ".align" | ".error" => {
                     self.parse_parameters()?;
                     Ok(None)
                 }
@@ -579,7 +873,9 @@ impl<'a> Parser<'a> {
         };
         self.consume_newline()?;
 
-        let mut commands: Vec<Statement> = vec![];
+        let mut if_body: Vec<Statement> = vec![];
+        let mut else_ifs: Vec<(Expression, Vec<Statement>)> = vec![];
+        let mut else_body: Option<Vec<Statement>> = None;
 
         while !self.tokens.at_end() {
             if check_token!(self.tokens, TokenType::Macro) {
@@ -587,18 +883,27 @@ impl<'a> Parser<'a> {
                 match tok_lexeme.as_str() {
                     ".elseif" => {
                         self.tokens.advance();
-                        self.parse_expression()?;
+                        let condition = self.parse_expression()?;
                         self.consume_newline()?;
+                        else_ifs.push((condition, vec![]));
+                        continue;
                     }
                     ".else" => {
                         self.tokens.advance();
                         self.consume_newline()?;
+                        else_body = Some(vec![]);
+                        continue;
                     }
                     ".endif" => {
                         self.tokens.advance();
                         let end = self.mark_end();
                         return Ok(Statement {
-                            kind: StatementKind::If(if_kind, commands),
+                            kind: StatementKind::If(IfStatement {
+                                kind: if_kind,
+                                if_body,
+                                else_body,
+                                else_ifs: (!else_ifs.is_empty()).then_some(else_ifs),
+                            }),
                             span: Span::new(start, end),
                         });
                     }
@@ -606,40 +911,95 @@ impl<'a> Parser<'a> {
                 }
             }
             if let Some(line) = self.parse_line()? {
-                commands.push(line);
+                // Statements belong to whichever branch was opened last
+                if let Some(body) = else_body.as_mut() {
+                    body.push(line);
+                } else if let Some((_, body)) = else_ifs.last_mut() {
+                    body.push(line);
+                } else {
+                    if_body.push(line);
+                }
             }
         }
 
-        Err(ParseError::Expected {
-            received: self.peek()?,
-            expected: TokenType::Macro,
+        Err(ParseError::UnterminatedBlock { opener: if_token })
+    }
+
+    fn parse_condes(&mut self) -> Result<Statement> {
+        let start = self.mark_start();
+        let directive = self.last().lexeme.to_lowercase();
+        let name = self.consume_token(TokenType::Identifier)?;
+        let kind = match directive.as_str() {
+            ".constructor" => ConDesKind::Constructor,
+            ".destructor" => ConDesKind::Destructor,
+            ".interruptor" => ConDesKind::Interruptor,
+            _ => {
+                self.consume_token(TokenType::Comma)?;
+                let kind = self.parse_expression()?;
+                match &kind.kind {
+                    ExpressionKind::Identifier(ident) => match ident.to_lowercase().as_str() {
+                        "constructor" => ConDesKind::Constructor,
+                        "destructor" => ConDesKind::Destructor,
+                        "interruptor" => ConDesKind::Interruptor,
+                        _ => ConDesKind::Other(kind),
+                    },
+                    _ => ConDesKind::Other(kind),
+                }
+            }
+        };
+        let priority = if match_token!(self.tokens, TokenType::Comma) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        let end = self.mark_end();
+        self.consume_newline()?;
+
+        Ok(Statement {
+            kind: StatementKind::ConDes {
+                name,
+                kind,
+                priority,
+            },
+            span: Span::new(start, end),
         })
     }
 
     fn parse_macro_def(&mut self) -> Result<Statement> {
+        let opener = self.last();
         let start = self.mark_start();
         self.consume_token(TokenType::Identifier)?;
         let ident = self.last();
         let mut parameters = vec![];
+        let mut variadic = false;
 
         while match_token!(self.tokens, TokenType::Identifier) {
             parameters.push(self.last());
+            // A trailing `...` collects the remaining arguments into the last parameter
+            if match_token!(self.tokens, TokenType::Ellipsis) {
+                variadic = true;
+                break;
+            }
             if self.consume_token(TokenType::Comma).is_err() {
                 break;
             }
         }
         self.consume_newline()?;
 
-        let commands = self.parse_statement_block(&[".endmacro"])?;
+        self.macro_depth += 1;
+        let commands = self.parse_statement_block(&opener, &[".endmacro", ".endmac"]);
+        self.macro_depth -= 1;
+        let commands = commands?;
         let end = self.mark_end();
         Ok(Statement {
-            kind: StatementKind::MacroDefinition(ident, parameters, commands),
+            kind: StatementKind::MacroDefinition(ident, parameters, variadic, commands),
             span: Span::new(start, end),
         })
     }
 
     fn parse_enum(&mut self) -> Result<Statement> {
         let start = self.mark_start();
+        let opener = self.last();
 
         // enums can either be named or unnamed
         let ident: Option<Token> = if check_token!(self.tokens, TokenType::Identifier) {
@@ -674,15 +1034,12 @@ impl<'a> Parser<'a> {
             self.consume_newline()?;
         }
 
-        // never encountered ".endenum"
-        Err(ParseError::Expected {
-            received: self.peek()?,
-            expected: TokenType::Macro,
-        })
+        Err(ParseError::UnterminatedBlock { opener })
     }
 
     fn parse_struct(&mut self) -> Result<Statement> {
         let start = self.mark_start();
+        let opener = self.last();
         let ident = self.consume_token(TokenType::Identifier)?;
 
         self.consume_newline()?;
@@ -713,19 +1070,28 @@ impl<'a> Parser<'a> {
                 }
             } else {
                 let ident = self.consume_token(TokenType::Identifier)?;
-                let _data_type = self.consume_token(TokenType::Macro)?; // TODO: add data type
-                match_token!(self.tokens, TokenType::Identifier);
-                match_token!(self.tokens, TokenType::Number);
-                members.push(StructMember::Field(ident));
+                let data_type = self.consume_token(TokenType::Macro)?;
+                let tag = if data_type.lexeme.eq_ignore_ascii_case(".tag") {
+                    Some(self.consume_token(TokenType::Identifier)?)
+                } else {
+                    None
+                };
+                let count = if self.tokens.at_end() || check_token!(self.tokens, TokenType::EOL) {
+                    None
+                } else {
+                    Some(self.parse_expression()?)
+                };
+                members.push(StructMember::Field {
+                    name: ident,
+                    data_type,
+                    tag,
+                    count,
+                });
                 self.consume_newline()?;
             }
         }
 
-        // never encountered ".endenum"
-        Err(ParseError::Expected {
-            received: self.peek()?,
-            expected: TokenType::Macro,
-        })
+        Err(ParseError::UnterminatedBlock { opener })
     }
 
     fn parse_define(&mut self) -> Result<Statement> {
@@ -752,46 +1118,68 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_assignment(&mut self) -> Result<Statement> {
-        if let Some(token) = self.tokens.peek() {
-            if match_token!(self.tokens, TokenType::Identifier) {
-                let start = self.last().span.start;
-                if match_token!(self.tokens, TokenType::Equal) {
-                    let value = self.parse_expression()?;
-                    let end = self.tokens.previous()?.span.end;
-                    let operation = StatementKind::ConstantAssign(ConstantAssign {
-                        name: token,
-                        value,
-                        span: Span::new(start, end),
-                    });
+        if let Some(token) = self.tokens.peek()
+            && match_token!(self.tokens, TokenType::Identifier)
+        {
+            let start = self.last().span.start;
+            if match_token!(self.tokens, TokenType::Equal) {
+                let value = self.parse_expression()?;
+                let end = self.tokens.previous()?.span.end;
+                let operation = StatementKind::ConstantAssign(ConstantAssign {
+                    name: token,
+                    value,
+                    span: Span::new(start, end),
+                    reassignable: false,
+                });
 
-                    self.consume_newline()?;
+                self.consume_newline()?;
 
-                    return Ok(Statement {
-                        kind: operation,
-                        span: Span::new(start, end),
-                    });
-                }
-                if match_token!(self.tokens, TokenType::ConstAssign) {
-                    let value = self.parse_expression()?;
-                    let end = self.tokens.previous()?.span.end;
-                    let operation = StatementKind::ConstantAssign(ConstantAssign {
-                        name: token,
-                        value,
-                        span: Span::new(start, end),
-                    });
+                return Ok(Statement {
+                    kind: operation,
+                    span: Span::new(start, end),
+                });
+            }
+            if match_token!(self.tokens, TokenType::ConstAssign) {
+                let value = self.parse_expression()?;
+                let end = self.tokens.previous()?.span.end;
+                let operation = StatementKind::ConstantAssign(ConstantAssign {
+                    name: token,
+                    value,
+                    span: Span::new(start, end),
+                    reassignable: false,
+                });
 
-                    self.consume_newline()?;
+                self.consume_newline()?;
 
-                    return Ok(Statement {
-                        kind: operation,
-                        span: Span::new(start, end),
-                    });
-                }
-                if check_token!(self.tokens, TokenType::Colon) {
-                    return self.parse_label();
-                }
-                return self.parse_macro_invocation();
+                return Ok(Statement {
+                    kind: operation,
+                    span: Span::new(start, end),
+                });
+            }
+            if check_token!(self.tokens, TokenType::Macro)
+                && self.peek()?.lexeme.eq_ignore_ascii_case(".set")
+            {
+                self.tokens.advance();
+                let value = self.parse_expression()?;
+                let end = self.tokens.previous()?.span.end;
+                let operation = StatementKind::ConstantAssign(ConstantAssign {
+                    name: token,
+                    value,
+                    span: Span::new(start, end),
+                    reassignable: true,
+                });
+
+                self.consume_newline()?;
+
+                return Ok(Statement {
+                    kind: operation,
+                    span: Span::new(start, end),
+                });
+            }
+            if check_token!(self.tokens, TokenType::Colon) {
+                return self.parse_label();
             }
+            return self.parse_macro_invocation();
         }
         self.parse_instruction()
     }
@@ -800,7 +1188,7 @@ impl<'a> Parser<'a> {
         if match_token!(self.tokens, TokenType::Instruction) {
             let mnemonic = self.last().lexeme;
             let start = self.mark_start();
-            let parameters = self.parse_parameters()?;
+            let parameters = self.with_registers(Self::parse_parameters)?;
             let end = self.mark_end();
 
             self.consume_newline()?;
@@ -844,7 +1232,7 @@ impl<'a> Parser<'a> {
     fn parse_macro_invocation(&mut self) -> Result<Statement> {
         let start = self.mark_start();
         let name = self.tokens.previous()?;
-        let parameters = self.parse_macro_parameters()?;
+        let parameters = self.with_registers(Self::parse_macro_parameters)?;
         let end = self.mark_end();
         Ok(Statement {
             kind: StatementKind::MacroInvocation(MacroInvocation { name, parameters }),
@@ -852,6 +1240,13 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn with_registers<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.in_operands = true;
+        let result = parse(self);
+        self.in_operands = false;
+        result
+    }
+
     fn parse_expression(&mut self) -> Result<Expression> {
         self.parse_expr0()
     }
@@ -930,10 +1325,11 @@ impl<'a> Parser<'a> {
                 | TokenType::LessThanEq
                 | TokenType::GreaterThanEq
         ) {
+            let operator = self.last().token_type;
             let right = self.parse_simple_expression()?;
             root = Expression {
                 kind: ExpressionKind::Comparison(
-                    self.tokens.previous()?.token_type,
+                    operator,
                     Box::from(root.clone()),
                     Box::from(right.clone()),
                 ),
@@ -980,11 +1376,12 @@ impl<'a> Parser<'a> {
                 | TokenType::ShiftLeft
                 | TokenType::ShiftRight
         ) {
+            let operator = self.last().token_type;
             let right = self.parse_factor()?;
 
             root = Expression {
                 kind: ExpressionKind::Term(
-                    self.tokens.previous()?.token_type,
+                    operator,
                     Box::from(root.clone()),
                     Box::from(right.clone()),
                 ),
@@ -996,7 +1393,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_factor(&mut self) -> Result<Expression> {
-        let start = self.mark_start();
+        let start = self.peek_start();
         if match_token!(self.tokens, TokenType::Hash) {
             let right = self.parse_factor()?;
             let end = self.mark_end();
@@ -1086,6 +1483,28 @@ impl<'a> Parser<'a> {
                 span: Span::new(start, end),
             });
         }
+        if match_token!(self.tokens, TokenType::Blank) {
+            self.consume_token(TokenType::LeftParen)?;
+            let tokens = self.parse_token_list(TokenType::RightParen)?;
+            self.consume_token(TokenType::RightParen)?;
+            let end = self.mark_end();
+
+            return Ok(Expression {
+                kind: ExpressionKind::Blank(Box::new(tokens)),
+                span: Span::new(start, end),
+            });
+        }
+        if match_token!(self.tokens, TokenType::Const) {
+            self.consume_token(TokenType::LeftParen)?;
+            let expr = self.parse_expression()?;
+            self.consume_token(TokenType::RightParen)?;
+            let end = self.mark_end();
+
+            return Ok(Expression {
+                kind: ExpressionKind::Const(Box::new(expr)),
+                span: Span::new(start, end),
+            });
+        }
         if match_token!(self.tokens, TokenType::Extract) {
             return self.parse_extract();
         }
@@ -1102,7 +1521,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_primary(&mut self) -> Result<Expression> {
-        let start = self.mark_start();
+        let start = self.peek_start();
         if match_token!(self.tokens, TokenType::LeftBrace) {
             // let start = self.mark_start();
             let ident = self.parse_identifier()?;
@@ -1144,14 +1563,32 @@ impl<'a> Parser<'a> {
         if match_token!(self.tokens, TokenType::LeftParen) {
             let start = self.mark_start();
             let expr = self.parse_expression()?;
-            // TODO: this needs to be handled. Used for instructions like JSR (label, x)
-            while match_token!(self.tokens, TokenType::Comma) {
-                self.parse_expression()?;
-            }
+            // Indexed indirect addressing like `jsr (label,x)` or `lda (3,s),y`
+            let is_indexed = check_token!(self.tokens, TokenType::Comma)
+                && matches!(
+                    (self.tokens.peek_ahead(1), self.tokens.peek_ahead(2)),
+                    (
+                        Some(Token {
+                            token_type: TokenType::Identifier,
+                            ..
+                        }),
+                        Some(Token {
+                            token_type: TokenType::RightParen,
+                            ..
+                        })
+                    )
+                );
+            let kind = if is_indexed {
+                self.tokens.advance();
+                let register = self.consume_token(TokenType::Identifier)?;
+                ExpressionKind::IndexedIndirect(Box::from(expr), register)
+            } else {
+                ExpressionKind::Group(Box::from(expr))
+            };
             self.consume_token(TokenType::RightParen)?;
             let end = self.mark_end();
             return Ok(Expression {
-                kind: ExpressionKind::Group(Box::from(expr)),
+                kind,
                 span: Span::new(start, end),
             });
         }
@@ -1174,14 +1611,16 @@ impl<'a> Parser<'a> {
             let next = self.tokens.peek().unwrap();
 
             return match next.lexeme.as_str() {
-                ".addrsize" | ".bank" | ".bankbyte" | ".blank" | ".cap" | ".capability"
-                | ".concat" | ".const" | ".def" | ".defined" | ".definedmacro" | ".hibyte"
-                | ".hiword" | ".ident" | ".ismnem" | ".ismnemonic" | ".max" | ".min" | ".ref"
-                | ".referenced" | ".sizeof" | ".sprintf" | ".strat" | ".string" | ".strlen" | "tcount" => {
-                    self.parse_pseudo_function()
+                ".addrsize" | ".bank" | ".bankbyte" | ".cap" | ".capability" | ".concat"
+                | ".def" | ".defined" | ".hibyte" | ".hiword" | ".ident" | ".max" | ".min"
+                | ".ref" | ".referenced" | ".sizeof" | ".sprintf" | ".strat" | ".string"
+                | ".strlen" => self.parse_pseudo_function(),
+                // These take raw tokens (mnemonics, possibly undefined macro names), not symbols
+                ".definedmacro" | ".ismnem" | ".ismnemonic" | ".tcount" => {
+                    self.parse_token_list_function()
                 }
-                ".asize" | ".isize" => {
-                    let start = self.mark_start();
+                ".asize" | ".isize" | ".cpu" | ".paramcount" | ".version" | ".time" => {
+                    let start = self.peek_start();
                     let macro_name = self.consume_token(TokenType::Macro)?.lexeme;
                     let end = self.mark_end();
                     Ok(Expression {
@@ -1196,7 +1635,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_pseudo_function(&mut self) -> Result<Expression> {
-        let start = self.mark_start();
+        let start = self.peek_start();
         let macro_name = self.consume_token(TokenType::Macro)?;
         self.consume_token(TokenType::LeftParen)?;
         let mut args = vec![];
@@ -1213,6 +1652,20 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn parse_token_list_function(&mut self) -> Result<Expression> {
+        let start = self.peek_start();
+        let macro_name = self.consume_token(TokenType::Macro)?;
+        self.consume_token(TokenType::LeftParen)?;
+        let tokens = self.parse_token_list(TokenType::RightParen)?;
+        self.consume_token(TokenType::RightParen)?;
+        let end = self.mark_end();
+
+        Ok(Expression {
+            kind: ExpressionKind::PseudoFunction(macro_name, vec![tokens]),
+            span: Span::new(start, end),
+        })
+    }
+
     fn parse_macro_parameters(&mut self) -> Result<Vec<MacroParameter>> {
         let mut parameters = vec![];
         if !check_token!(self.tokens, TokenType::EOL) {
@@ -1311,7 +1764,9 @@ impl<'a> Parser<'a> {
         {
             // TODO: Handle addressing modes?
             self.parse_expression()
-        } else if matches!(token_string.to_lowercase().as_str(), "y" | "x" | "a" | "s") {
+        } else if self.in_operands
+            && matches!(token_string.to_lowercase().as_str(), "y" | "x" | "a" | "s")
+        {
             // TODO: Available registers should rely on target processor
             // Reserved registers
             Ok(Expression {
@@ -1335,7 +1790,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_unnamed_label_reference(&mut self) -> Result<Expression> {
-        let start = self.mark_start();
+        let start = self.peek_start();
 
         self.consume_token(TokenType::UnnamedLabelReference)?;
 
@@ -1355,24 +1810,47 @@ impl<'a> Parser<'a> {
     }
 
     #[inline]
-    fn parse_statement_block(&mut self, macro_end: &[&str]) -> Result<Vec<Statement>> {
+    fn parse_statement_block(
+        &mut self,
+        opener: &Token,
+        macro_end: &[&str],
+    ) -> Result<Vec<Statement>> {
         let mut commands: Vec<Statement> = vec![];
         while !self.tokens.at_end() {
             if check_token!(self.tokens, TokenType::Macro) {
-                let m = self.peek()?.lexeme;
-                if macro_end.contains(&m.as_str()) {
+                let m = self.peek()?;
+                let lexeme = m.lexeme.to_lowercase();
+                if macro_end.contains(&lexeme.as_str()) {
                     self.tokens.advance();
                     return Ok(commands);
                 }
+                if BLOCK_TERMINATORS.contains(&lexeme.as_str()) {
+                    return Err(ParseError::MismatchedTerminator {
+                        opener: opener.clone(),
+                        terminator: m,
+                    });
+                }
             }
-            if let Some(line) = self.parse_line()? {
-                commands.push(line);
+            let line_start = self.tokens.position;
+            match self.parse_line() {
+                Ok(Some(line)) => commands.push(line),
+                Ok(None) => {}
+                // A broken block can't be recovered from here, so it's left to the enclosing block
+                Err(
+                    err @ (ParseError::MismatchedTerminator { .. }
+                    | ParseError::UnterminatedBlock { .. }
+                    | ParseError::EOF),
+                ) => return Err(err),
+                // Anything else only costs the statement it's in, keeping the block intact
+                Err(err) => {
+                    self.error_recovery(&err, line_start);
+                    self.errors.push(err);
+                }
             }
         }
 
-        Err(ParseError::Expected {
-            received: self.peek()?,
-            expected: TokenType::Macro,
+        Err(ParseError::UnterminatedBlock {
+            opener: opener.clone(),
         })
     }
 
@@ -1456,6 +1934,14 @@ impl<'a> Parser<'a> {
         self.tokens.previous().unwrap().span.start
     }
 
+    // The start of the token about to be consumed, for spans that begin at it
+    #[inline]
+    fn peek_start(&self) -> usize {
+        self.tokens
+            .peek()
+            .map_or_else(|| self.mark_start(), |token| token.span.start)
+    }
+
     #[inline]
     fn mark_end(&self) -> usize {
         self.mark_start() + self.tokens.previous().unwrap().lexeme.len()
@@ -1486,13 +1972,23 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn error_recovery(&mut self) {
+    // Skips the rest of a broken statement. A stray or missing token resumes at the next
+    // instruction or directive on the line, anything else at the end of the line.
+    fn error_recovery(&mut self, error: &ParseError, line_start: usize) {
+        let resume_mid_line = matches!(
+            error,
+            ParseError::Expected { .. } | ParseError::UnexpectedToken(_)
+        );
         loop {
             if self.tokens.at_end() {
                 break;
             }
             if let Some(token) = self.tokens.peek() {
-                if token.token_type == TokenType::EOL {
+                if token.token_type == TokenType::EOL
+                    || (resume_mid_line
+                        && self.tokens.position > line_start
+                        && self.starts_statement(&token))
+                {
                     break;
                 } else {
                     self.tokens.advance();
@@ -1502,4 +1998,706 @@ impl<'a> Parser<'a> {
             }
         }
     }
+
+    fn starts_statement(&self, token: &Token) -> bool {
+        match token.token_type {
+            TokenType::Instruction => true,
+            // Pseudo functions and variables like `.sizeof(Point)` or `.cpu` are expressions
+            TokenType::Macro => {
+                !matches!(
+                    token.lexeme.to_lowercase().as_str(),
+                    ".asize" | ".isize" | ".cpu" | ".paramcount" | ".version" | ".time"
+                ) && !self
+                    .tokens
+                    .peek_ahead(1)
+                    .is_some_and(|next| next.token_type == TokenType::LeftParen)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instructions, Tokenizer};
+
+    fn parse(source: &str) -> Ast {
+        let instructions = Instructions::load();
+        let tokens = Tokenizer::new(source, &instructions).parse().unwrap();
+        let (ast, errors) = Parser::new(&tokens).parse();
+        assert!(errors.is_empty(), "{errors:?}");
+        ast
+    }
+
+    fn operands(source: &str) -> Vec<ExpressionKind> {
+        match &parse(source)[0].kind {
+            StatementKind::Instruction(instruction) => instruction
+                .parameters
+                .iter()
+                .map(|parameter| parameter.kind.clone())
+                .collect(),
+            other => panic!("expected an instruction, got {other:?}"),
+        }
+    }
+
+    fn assigned_value(source: &str) -> ExpressionKind {
+        match &parse(source)[0].kind {
+            StatementKind::ConstantAssign(assign) => assign.value.kind.clone(),
+            other => panic!("expected an assignment, got {other:?}"),
+        }
+    }
+
+    fn if_condition(source: &str) -> ExpressionKind {
+        match &parse(source)[0].kind {
+            StatementKind::If(IfStatement {
+                kind: IfKind::WithExpression(condition),
+                ..
+            }) => condition.kind.clone(),
+            other => panic!("expected an .if with a condition, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_indexed_indirect_operands() {
+        match &operands("lda ($10,x)\n")[..] {
+            [ExpressionKind::IndexedIndirect(address, register)] => {
+                assert_eq!(address.kind, ExpressionKind::Literal("$10".to_string()));
+                assert_eq!(register.lexeme, "x");
+            }
+            other => panic!("expected an indexed indirect operand, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_parenthesised_lists_without_a_register() {
+        let instructions = Instructions::load();
+        for source in ["lda (value, 2)\n", "lda (value, x + 1)\n"] {
+            let tokens = Tokenizer::new(source, &instructions).parse().unwrap();
+            let (_, errors) = Parser::new(&tokens).parse();
+            match &errors[..] {
+                [
+                    ParseError::Expected {
+                        expected: TokenType::RightParen,
+                        received,
+                    },
+                ] => assert_eq!(received.token_type, TokenType::Comma),
+                other => panic!("expected a missing parenthesis for {source:?}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_constructors_with_a_priority() {
+        match &parse(".constructor init, 7\n")[0].kind {
+            StatementKind::ConDes {
+                name,
+                kind: ConDesKind::Constructor,
+                priority: Some(priority),
+            } => {
+                assert_eq!(name.lexeme, "init");
+                assert_eq!(priority.kind, ExpressionKind::Literal("7".to_string()));
+            }
+            other => panic!("expected a constructor, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_undefine_and_delmacro() {
+        let ast = parse(".define FOO 1\n.undefine FOO\n.delmacro push\n");
+
+        assert!(matches!(&ast[1].kind, StatementKind::Undefine(name) if name.lexeme == "FOO"));
+        assert!(matches!(&ast[2].kind, StatementKind::DelMacro(name) if name.lexeme == "push"));
+    }
+
+    #[test]
+    fn parses_variadic_macro_parameters() {
+        match &parse(".macro poke addr, vals...\nlda vals\n.endmacro\n")[0].kind {
+            StatementKind::MacroDefinition(name, parameters, variadic, _) => {
+                assert_eq!(name.lexeme, "poke");
+                let names = parameters
+                    .iter()
+                    .map(|parameter| parameter.lexeme.as_str())
+                    .collect::<Vec<_>>();
+                assert_eq!(names, ["addr", "vals"]);
+                assert!(variadic);
+            }
+            other => panic!("expected a macro definition, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_org_addresses_as_expressions() {
+        let addresses = parse(".org *+2\n.org LOAD_ADDR\n")
+            .into_iter()
+            .map(|statement| match statement.kind {
+                StatementKind::Org(address) => address.kind,
+                other => panic!("expected an .org statement, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        match &addresses[..] {
+            [
+                ExpressionKind::SimpleExpression(operator, left, right),
+                ExpressionKind::Identifier(name),
+            ] => {
+                assert_eq!(operator.token_type, TokenType::Plus);
+                assert_eq!(left.kind, ExpressionKind::Literal("*".to_string()));
+                assert_eq!(right.kind, ExpressionKind::Literal("2".to_string()));
+                assert_eq!(name, "LOAD_ADDR");
+            }
+            other => panic!("expected two expression addresses, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_symbolic_incbin_offsets_and_lengths() {
+        match &parse(".incbin \"tiles.chr\", TILE_OFFSET, TILE_COUNT * 16\n")[0].kind {
+            StatementKind::IncludeBinary(path, Some(offset), Some(length)) => {
+                assert_eq!(path.lexeme, "\"tiles.chr\"");
+                assert_eq!(
+                    offset.kind,
+                    ExpressionKind::Identifier("TILE_OFFSET".to_string())
+                );
+                assert!(
+                    matches!(length.kind, ExpressionKind::Term(TokenType::Multiply, _, _)),
+                    "{length:?}"
+                );
+            }
+            other => panic!("expected an .incbin with an offset and length, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_dbyt_data() {
+        match &parse(".dbyt $1234, label\n")[0].kind {
+            StatementKind::Data(DataKind::Dbyt, values) => {
+                let values = values
+                    .iter()
+                    .map(|value| value.kind.clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    values,
+                    [
+                        ExpressionKind::Literal("$1234".to_string()),
+                        ExpressionKind::Identifier("label".to_string()),
+                    ]
+                );
+            }
+            other => panic!("expected a .dbyt statement, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn preserves_the_data_kind_of_each_directive() {
+        let cases = [
+            (".db", DataKind::Byte),
+            (".byte", DataKind::Byte),
+            (".dw", DataKind::Word),
+            (".word", DataKind::Word),
+            (".dword", DataKind::Dword),
+            (".dbyt", DataKind::Dbyt),
+            (".lobytes", DataKind::LoBytes),
+            (".hibytes", DataKind::HiBytes),
+            (".addr", DataKind::Addr),
+            (".faraddr", DataKind::FarAddr),
+        ];
+        for (directive, expected) in cases {
+            match &parse(&format!("{directive} 1\n"))[0].kind {
+                StatementKind::Data(kind, _) => assert_eq!(*kind, expected, "{directive}"),
+                other => panic!("expected a data statement for {directive}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_segment_address_size_attributes() {
+        let segments = parse(".segment \"BSS\": zeropage\n.segment \"CODE\"\n")
+            .into_iter()
+            .map(|statement| match statement.kind {
+                StatementKind::Segment(Segment::Identifier(name, attribute)) => {
+                    (name.lexeme, attribute.map(|attribute| attribute.lexeme))
+                }
+                other => panic!("expected a .segment statement, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            [
+                ("\"BSS\"".to_string(), Some("zeropage".to_string())),
+                ("\"CODE\"".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn features_can_be_switched_on_and_off() {
+        let ast = parse(
+            ".feature at_in_identifiers +\n.feature c_comments -\n.feature ubiquitous_idents\n",
+        );
+        let features = ast
+            .iter()
+            .map(|statement| match &statement.kind {
+                StatementKind::Feature(name) => name.lexeme.as_str(),
+                kind => panic!("expected a feature, got {kind:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            features,
+            vec!["at_in_identifiers", "c_comments", "ubiquitous_idents"]
+        );
+    }
+
+    #[test]
+    fn procedures_take_near_or_far() {
+        let address_sizes =
+            parse(".proc a\n.endproc\n.proc b: near\n.endproc\n.proc c: far\n.endproc\n")
+                .iter()
+                .map(|statement| match &statement.kind {
+                    StatementKind::Procedure(_, address_size, _) => *address_size,
+                    kind => panic!("expected a procedure, got {kind:?}"),
+                })
+                .collect::<Vec<_>>();
+        assert_eq!(
+            address_sizes,
+            vec![AddressSize::Near, AddressSize::Near, AddressSize::Far]
+        );
+
+        let instructions = Instructions::load();
+        let tokens = Tokenizer::new(".proc d: huge\n.endproc\n", &instructions)
+            .parse()
+            .unwrap();
+        let (_, errors) = Parser::new(&tokens).parse();
+        match &errors[..] {
+            [ParseError::Expected { received, .. }, ..] => assert_eq!(received.lexeme, "huge"),
+            other => panic!("expected the attribute to be rejected, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_reservations_with_symbolic_counts_and_fills() {
+        let ast = parse(".res 16\n.res WIDTH*2, $00\n");
+
+        match &ast[0].kind {
+            StatementKind::Reserve(amount, None) => {
+                assert_eq!(amount.kind, ExpressionKind::Literal("16".to_string()))
+            }
+            other => panic!("expected a .res without a fill, got {other:?}"),
+        }
+        match &ast[1].kind {
+            StatementKind::Reserve(amount, Some(fill)) => {
+                assert!(
+                    matches!(amount.kind, ExpressionKind::Term(TokenType::Multiply, _, _)),
+                    "{amount:?}"
+                );
+                assert_eq!(fill.kind, ExpressionKind::Literal("$00".to_string()));
+            }
+            other => panic!("expected a .res with a fill, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn errors_inside_a_block_only_cost_their_own_line() {
+        let instructions = Instructions::load();
+        let tokens = Tokenizer::new(
+            ".proc main\nlda (value, 2)\nnext: ldx #1\n.endproc\nafter: rts\n",
+            &instructions,
+        )
+        .parse()
+        .unwrap();
+        let (ast, errors) = Parser::new(&tokens).parse();
+
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        match &ast[..] {
+            [
+                Statement {
+                    kind: StatementKind::Procedure(name, _, body),
+                    ..
+                },
+                Statement {
+                    kind: StatementKind::Label(after),
+                    ..
+                },
+                ..,
+            ] => {
+                assert_eq!(name.lexeme, "main");
+                assert!(
+                    matches!(&body[..], [Statement { kind: StatementKind::Label(next), .. }, ..] if next.lexeme == "next"),
+                    "{body:?}"
+                );
+                assert_eq!(after.lexeme, "after");
+            }
+            other => panic!("expected the procedure to survive the error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn recovers_at_the_next_statement_on_the_line() {
+        let instructions = Instructions::load();
+        let tokens = Tokenizer::new("lda #1 ldx #2\nldy .sizeof(, .byte 3\n", &instructions)
+            .parse()
+            .unwrap();
+        let (ast, errors) = Parser::new(&tokens).parse();
+
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        match &ast[..] {
+            [
+                Statement {
+                    kind: StatementKind::Instruction(instruction),
+                    ..
+                },
+                Statement {
+                    kind: StatementKind::Data(DataKind::Byte, values),
+                    ..
+                },
+            ] => {
+                assert_eq!(instruction.mnemonic, "ldx");
+                assert_eq!(values.len(), 1);
+            }
+            other => panic!("expected the trailing statements to be parsed, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn struct_fields_can_embed_another_struct() {
+        match &parse(".struct Sprite\npos .tag Point\nflags .byte\n.endstruct\n")[0].kind {
+            StatementKind::Struct(name, members) => {
+                assert_eq!(name.lexeme, "Sprite");
+                match &members[..] {
+                    [
+                        StructMember::Field {
+                            name,
+                            data_type,
+                            tag: Some(tag),
+                            count: None,
+                        },
+                        StructMember::Field { tag: None, .. },
+                    ] => {
+                        assert_eq!(name.lexeme, "pos");
+                        assert_eq!(data_type.lexeme, ".tag");
+                        assert_eq!(tag.lexeme, "Point");
+                    }
+                    other => panic!("expected a .tag field and a .byte field, got {other:?}"),
+                }
+            }
+            other => panic!("expected a struct, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_every_condes_spelling() {
+        let cases = [
+            (".constructor init\n", ConDesKind::Constructor, None),
+            (".constructor init, 7\n", ConDesKind::Constructor, Some("7")),
+            (".destructor init\n", ConDesKind::Destructor, None),
+            (".destructor init, 3\n", ConDesKind::Destructor, Some("3")),
+            (".interruptor init\n", ConDesKind::Interruptor, None),
+            (
+                ".interruptor init, 20\n",
+                ConDesKind::Interruptor,
+                Some("20"),
+            ),
+            (".condes init, constructor\n", ConDesKind::Constructor, None),
+            (
+                ".condes init, interruptor, 5\n",
+                ConDesKind::Interruptor,
+                Some("5"),
+            ),
+        ];
+        for (source, expected, expected_priority) in cases {
+            match &parse(source)[0].kind {
+                StatementKind::ConDes {
+                    name,
+                    kind,
+                    priority,
+                } => {
+                    assert_eq!(name.lexeme, "init", "{source}");
+                    assert_eq!(*kind, expected, "{source}");
+                    assert_eq!(
+                        priority.as_ref().map(|priority| priority.kind.clone()),
+                        expected_priority
+                            .map(|priority| ExpressionKind::Literal(priority.to_string())),
+                        "{source}"
+                    );
+                }
+                other => panic!("expected a condes statement for {source:?}, got {other:?}"),
+            }
+        }
+
+        match &parse(".condes init, 2\n")[0].kind {
+            StatementKind::ConDes {
+                kind: ConDesKind::Other(kind),
+                ..
+            } => assert_eq!(kind.kind, ExpressionKind::Literal("2".to_string())),
+            other => panic!("expected a numbered condes type, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn exitmacro_is_only_accepted_inside_a_macro() {
+        match &parse(".macro clear cond\n.if cond\n.exitmacro\n.endif\nlda #0\n.endmacro\n")[0].kind
+        {
+            StatementKind::MacroDefinition(_, _, _, body) => match &body[..] {
+                [
+                    Statement {
+                        kind: StatementKind::If(if_statement),
+                        ..
+                    },
+                    Statement {
+                        kind: StatementKind::Instruction(_),
+                        ..
+                    },
+                ] => assert!(matches!(
+                    &if_statement.if_body[..],
+                    [Statement {
+                        kind: StatementKind::ExitMacro,
+                        ..
+                    }]
+                )),
+                other => panic!("expected an .if and an instruction, got {other:?}"),
+            },
+            other => panic!("expected a macro definition, got {other:?}"),
+        }
+
+        let instructions = Instructions::load();
+        let tokens = Tokenizer::new(".exitmacro\n", &instructions)
+            .parse()
+            .unwrap();
+        let (_, errors) = Parser::new(&tokens).parse();
+        assert_eq!(errors.len(), 1, "{errors:?}");
+    }
+
+    #[test]
+    fn parses_strings_in_byte_lists() {
+        match &parse(".byte \"Hi\", 13, 10\n")[0].kind {
+            StatementKind::Data(DataKind::Byte, values) => {
+                let values = values
+                    .iter()
+                    .map(|value| value.kind.clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    values,
+                    [
+                        ExpressionKind::String("\"Hi\"".to_string()),
+                        ExpressionKind::Literal("13".to_string()),
+                        ExpressionKind::Literal("10".to_string()),
+                    ]
+                );
+            }
+            other => panic!("expected a .byte statement, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_byte_operators() {
+        for directive in [".lobyte", ".hibyte", ".bankbyte"] {
+            match assigned_value(&format!("VALUE = {directive}(addr)\n")) {
+                ExpressionKind::WordOp(operator, operand) => {
+                    assert_eq!(operator.lexeme, directive);
+                    assert_eq!(operand.kind, ExpressionKind::Identifier("addr".to_string()));
+                }
+                other => panic!("expected a byte operator for {directive}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_blank_and_const_in_if_conditions() {
+        let conditions = parse(".if .blank(arg)\n.endif\n.if .const(SIZE * 2)\n.endif\n")
+            .into_iter()
+            .map(|statement| match statement.kind {
+                StatementKind::If(IfStatement {
+                    kind: IfKind::WithExpression(condition),
+                    ..
+                }) => condition.kind,
+                other => panic!("expected an .if with a condition, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+
+        match &conditions[..] {
+            [
+                ExpressionKind::Blank(tokens),
+                ExpressionKind::Const(expression),
+            ] => {
+                assert!(
+                    matches!(&tokens.kind, ExpressionKind::TokenList(tokens) if tokens.len() == 1),
+                    "{tokens:?}"
+                );
+                assert!(
+                    matches!(
+                        expression.kind,
+                        ExpressionKind::Term(TokenType::Multiply, _, _)
+                    ),
+                    "{expression:?}"
+                );
+            }
+            other => panic!("expected .blank and .const conditions, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_string_builtins() {
+        let cases = [
+            ("NAME = .concat(\"a\", PREFIX, \"b\")\n", ".concat", 3),
+            ("NAME = .string(VALUE)\n", ".string", 1),
+            ("LEN = .strlen(\"abc\")\n", ".strlen", 1),
+            ("CHAR = .strat(TEXT, 1)\n", ".strat", 2),
+        ];
+        for (source, expected, arguments) in cases {
+            match assigned_value(source) {
+                ExpressionKind::PseudoFunction(name, args) => {
+                    assert_eq!(name.lexeme, expected);
+                    assert_eq!(args.len(), arguments, "{source}");
+                }
+                other => panic!("expected {expected} to be a pseudo function, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_symbol_and_token_builtins_in_if_conditions() {
+        for (condition, expected) in [
+            (".definedmacro(foo)", ".definedmacro"),
+            (".ismnemonic(lda)", ".ismnemonic"),
+            (".tcount(a b c)", ".tcount"),
+            (".referenced(foo)", ".referenced"),
+            (".ref(foo)", ".ref"),
+        ] {
+            match if_condition(&format!(".if {condition}\n.endif\n")) {
+                ExpressionKind::PseudoFunction(name, args) => {
+                    assert_eq!(name.lexeme, expected);
+                    assert_eq!(args.len(), 1, "{condition}");
+                }
+                other => panic!("expected {condition} to be a pseudo function, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_assembler_variables_as_literals() {
+        match if_condition(".if .cpu & CPU_ISET_65816\n.endif\n") {
+            ExpressionKind::Term(TokenType::BitwiseAnd, left, right) => {
+                assert_eq!(left.kind, ExpressionKind::Literal(".cpu".to_string()));
+                assert_eq!(
+                    right.kind,
+                    ExpressionKind::Identifier("CPU_ISET_65816".to_string())
+                );
+            }
+            other => panic!("expected .cpu to be masked, got {other:?}"),
+        }
+        for variable in [".paramcount", ".version", ".time"] {
+            match if_condition(&format!(".if {variable} > 1\n.endif\n")) {
+                ExpressionKind::Comparison(TokenType::GreaterThan, left, _) => {
+                    assert_eq!(left.kind, ExpressionKind::Literal(variable.to_string()))
+                }
+                other => panic!("expected {variable} to be compared, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn continued_lines_form_one_statement() {
+        for source in [
+            ".byte $01, $02, \\\n      $03, $04\nrts\n",
+            ".byte $01, $02, \\\r\n      $03, $04\r\nrts\r\n",
+        ] {
+            let ast = parse(source);
+            assert_eq!(ast.len(), 2, "{source:?}");
+            match &ast[0].kind {
+                StatementKind::Data(DataKind::Byte, values) => {
+                    assert_eq!(values.len(), 4, "{source:?}")
+                }
+                other => panic!("expected a .byte statement, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_register_width_directives() {
+        let states = parse(".a8\n.a16\n.i16\n.i8\n.smart\n.smart -\n")
+            .into_iter()
+            .map(|statement| match statement.kind {
+                StatementKind::CpuState(state) => state,
+                other => panic!("expected a CPU state directive, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                CpuState::AccumulatorWidth(8),
+                CpuState::AccumulatorWidth(16),
+                CpuState::IndexWidth(16),
+                CpuState::IndexWidth(8),
+                CpuState::Smart(true),
+                CpuState::Smart(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn character_constants_are_numbers() {
+        assert_eq!(
+            operands("lda #'A'\n"),
+            [ExpressionKind::Immediate(Box::new(Expression {
+                kind: ExpressionKind::Literal("'A'".to_string()),
+                span: Span::new(5, 8),
+            }))]
+        );
+
+        match &parse(".byte 'A'+$80\n")[0].kind {
+            StatementKind::Data(DataKind::Byte, values) => match &values[..] {
+                [
+                    Expression {
+                        kind: ExpressionKind::SimpleExpression(operator, left, right),
+                        ..
+                    },
+                ] => {
+                    assert_eq!(operator.token_type, TokenType::Plus);
+                    assert_eq!(left.kind, ExpressionKind::Literal("'A'".to_string()));
+                    assert_eq!(right.kind, ExpressionKind::Literal("$80".to_string()));
+                }
+                other => panic!("expected a sum, got {other:?}"),
+            },
+            other => panic!("expected a .byte statement, got {other:?}"),
+        }
+        assert_eq!(crate::number::parse_number("'A'"), Some(0x41));
+    }
+
+    #[test]
+    fn parses_assert_with_an_action_and_message() {
+        match &parse(".assert * = $8000, error, \"bad org\"\n.assert SIZE < 256, warning\n")[..] {
+            [
+                Statement {
+                    kind:
+                        StatementKind::Assert {
+                            condition,
+                            action,
+                            message: Some(message),
+                        },
+                    ..
+                },
+                Statement {
+                    kind:
+                        StatementKind::Assert {
+                            action: warning,
+                            message: None,
+                            ..
+                        },
+                    ..
+                },
+            ] => {
+                assert!(
+                    matches!(
+                        &condition.kind,
+                        ExpressionKind::Comparison(TokenType::Equal, left, _)
+                            if left.kind == ExpressionKind::Literal("*".to_string())
+                    ),
+                    "{condition:?}"
+                );
+                assert_eq!(action.lexeme, "error");
+                assert_eq!(message.lexeme, "\"bad org\"");
+                assert_eq!(warning.lexeme, "warning");
+            }
+            other => panic!("expected two .assert statements, got {other:?}"),
+        }
+    }
 }
@@ -15,33 +15,22 @@ impl Stream {
     }
 
     pub fn peek(&self) -> Option<char> {
-        if self.position >= self.input.len() {
-            None
-        } else {
-            Some(self.input.as_bytes()[self.position] as char)
-        }
+        self.input.get(self.position..)?.chars().next()
     }
 
     pub fn peek_next(&self) -> Option<char> {
-        if self.position + 1 >= self.input.len() {
-            None
-        } else {
-            Some(self.input.as_bytes()[self.position + 1] as char)
-        }
+        self.input.get(self.position..)?.chars().nth(1)
     }
 
     pub fn at_end(&self) -> bool {
         self.position >= self.input.len()
     }
 
+    // Positions are byte offsets, so step over the whole UTF-8 sequence
     pub fn advance(&mut self) -> Option<char> {
-        if self.at_end() {
-            None
-        } else {
-            let c = self.input.as_bytes()[self.position] as char;
-            self.position += 1;
-            Some(c)
-        }
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
     }
 
     pub fn match_char(&mut self, expected: char) -> bool {
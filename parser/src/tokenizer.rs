@@ -1,4 +1,5 @@
 use crate::instructions::Instructions;
+use crate::number::{is_digit, split_radix};
 use crate::stream::Stream;
 use crate::{Token, TokenType};
 use codespan::Span;
@@ -7,6 +8,7 @@ use std::fmt::{Display, Formatter};
 #[derive(Debug)]
 pub enum TokenizerErrorKind {
     UnexpectedToken,
+    UnterminatedString,
 }
 
 impl Display for TokenizerErrorKind {
@@ -33,6 +35,7 @@ pub struct Tokenizer<'a> {
     input: Stream,
     start: usize,
     instructions: &'a Instructions,
+    comments: Vec<Span>,
 }
 
 impl<'a> Tokenizer<'a> {
@@ -41,9 +44,15 @@ impl<'a> Tokenizer<'a> {
             input: Stream::new(input.to_string()),
             start: 0,
             instructions,
+            comments: vec![],
         }
     }
 
+    /// Spans of the `;` comments skipped while tokenizing.
+    pub fn comments(&self) -> &[Span] {
+        &self.comments
+    }
+
     pub fn parse(&mut self) -> Result<Vec<Token>> {
         let mut result = vec![];
         while !self.input.at_end() {
@@ -62,8 +71,12 @@ impl<'a> Tokenizer<'a> {
                 self.comment();
                 Ok(None)
             }
-            Some('.') => {
+            Some('.') if self.input.peek() == Some('.') && self.input.peek_next() == Some('.') => {
                 self.input.advance();
+                self.input.advance();
+                Ok(Some(self.make_token(TokenType::Ellipsis)))
+            }
+            Some('.') => {
                 self.identifier();
                 Ok(Some(match self.get_lexeme().to_lowercase().as_str() {
                     ".bitor" => self.make_token(TokenType::BitwiseOr),
@@ -83,11 +96,23 @@ impl<'a> Tokenizer<'a> {
                     }
                     ".match" | ".xmatch" => self.make_token(TokenType::Match),
                     ".left" | ".mid" | ".right" => self.make_token(TokenType::Extract),
+                    ".blank" => self.make_token(TokenType::Blank),
+                    ".const" => self.make_token(TokenType::Const),
                     _ => self.make_token(TokenType::Macro),
                 }))
             }
-            Some('"' | '\'') => {
-                self.string(c.unwrap());
+            Some('\'') => {
+                let string = self.string('\'')?;
+                // A single quoted character is a character constant, e.g. `'A'`
+                let token_type = if string.chars().count() == 1 {
+                    TokenType::Number
+                } else {
+                    TokenType::String
+                };
+                Ok(Some(self.make_token(token_type)))
+            }
+            Some('"') => {
+                self.string('"')?;
                 Ok(Some(self.make_token(TokenType::String)))
             }
             Some('(') => Ok(Some(self.make_token(TokenType::LeftParen))),
@@ -107,7 +132,6 @@ impl<'a> Tokenizer<'a> {
                 ))
             }
             Some('@') => {
-                self.input.advance();
                 self.identifier();
                 Ok(Some(self.make_token(TokenType::Identifier)))
             }
@@ -125,7 +149,7 @@ impl<'a> Tokenizer<'a> {
                         '+' | '-' | '>' | '<' => {
                             loop {
                                 let c = self.input.peek();
-                                if c.is_some_and(|c| c.is_whitespace()) {
+                                if c.is_none_or(|c| c.is_whitespace()) {
                                     break;
                                 } else if c.is_some_and(|c| c != char_following_colon) {
                                     return Err(TokenizerError {
@@ -144,18 +168,28 @@ impl<'a> Tokenizer<'a> {
                     return Ok(Some(self.make_token(TokenType::Colon)));
                 }
             }
+            Some('0') if self.radix_prefix(&['x', 'X'], 16) => {
+                self.hex_number();
+                self.number_token()
+            }
+            Some('0') if self.radix_prefix(&['b', 'B'], 2) => {
+                self.bin_number();
+                self.number_token()
+            }
             Some('0'..='9') => {
                 self.number();
-                Ok(Some(self.make_token(TokenType::Number)))
+                self.number_token()
             }
             Some('$') => {
                 self.hex_number();
-                Ok(Some(self.make_token(TokenType::Number)))
+                self.number_token()
             }
-            Some('%') => {
+            // `%` only starts a binary number when a binary digit follows, otherwise it is modulo
+            Some('%') if matches!(self.input.peek(), Some('0' | '1')) => {
                 self.bin_number();
-                Ok(Some(self.make_token(TokenType::Number)))
+                self.number_token()
             }
+            Some('%') => Ok(Some(self.make_token(TokenType::Mod))),
             Some('|') => Ok(Some(if self.input.peek() == Some('|') {
                 self.input.advance();
                 self.make_token(TokenType::Or)
@@ -199,6 +233,7 @@ impl<'a> Tokenizer<'a> {
             Some('/') => Ok(Some(self.make_token(TokenType::Divide))),
             Some('~') => Ok(Some(self.make_token(TokenType::BitwiseNot))),
             Some('^') => Ok(Some(self.make_token(TokenType::Caret))),
+            Some('\\') if self.line_continuation() => Ok(None),
             Some('\n') => Ok(Some(self.make_token(TokenType::EOL))),
             None => Ok(Some(self.make_token(TokenType::EOF))),
             Some(' ' | '\t' | '\r') => Ok(None),
@@ -222,53 +257,103 @@ impl<'a> Tokenizer<'a> {
     }
 
     fn number(&mut self) {
-        while self
-            .input
-            .peek()
-            .is_some_and(|c| c.is_numeric() || c == '_')
+        while self.input.peek().is_some_and(|c| is_digit(c, 10)) {
+            self.input.advance();
+        }
+    }
+
+    // C-style `0x`/`0b` prefixes, only when a digit of that radix follows
+    fn radix_prefix(&mut self, prefix: &[char], radix: u32) -> bool {
+        if self.input.peek().is_some_and(|c| prefix.contains(&c))
+            && self.input.peek_next().is_some_and(|c| is_digit(c, radix))
         {
             self.input.advance();
+            true
+        } else {
+            false
         }
     }
 
     fn hex_number(&mut self) {
-        while !self.input.at_end()
-            && self
-                .input
-                .peek()
-                .is_some_and(|c| c.is_ascii_hexdigit() || c == '_')
-        {
+        while !self.input.at_end() && self.input.peek().is_some_and(|c| is_digit(c, 16)) {
             self.input.advance();
         }
     }
 
     fn bin_number(&mut self) {
-        while !self.input.at_end()
-            && self
-                .input
-                .peek()
-                .is_some_and(|c| matches!(c, '0' | '1' | '_'))
-        {
+        while !self.input.at_end() && self.input.peek().is_some_and(|c| is_digit(c, 2)) {
             self.input.advance();
         }
     }
 
+    // A backslash at the end of a line joins it with the next one
+    fn line_continuation(&mut self) -> bool {
+        if self.input.peek() == Some('\r') && self.input.peek_next() == Some('\n') {
+            self.input.advance();
+        }
+        if self.input.peek() == Some('\n') {
+            self.input.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    // `_` may only separate digits, e.g. `%1010_0000`
+    fn number_token(&self) -> Result<Option<Token>> {
+        let lexeme = self.get_lexeme();
+        let (_, digits) = split_radix(&lexeme);
+        if digits.starts_with('_') || digits.ends_with('_') {
+            return Err(TokenizerError {
+                kind: TokenizerErrorKind::UnexpectedToken,
+                offset: self.start,
+            });
+        }
+
+        Ok(Some(self.make_token(TokenType::Number)))
+    }
+
     fn comment(&mut self) {
         while !self.input.at_end() && self.input.peek().unwrap() != '\n' {
             self.input.advance();
         }
+        self.comments.push(Span::new(self.start, self.input.pos()));
     }
 
-    fn string(&mut self, variant: char) -> String {
-        while !self.input.at_end() {
-            if self.input.advance() == Some(variant) {
+    // Returns the contents between the quotes with escape sequences applied
+    fn string(&mut self, variant: char) -> Result<String> {
+        let mut contents = String::new();
+        while let Some(c) = self.input.peek() {
+            if c == '\n' {
                 break;
             }
+            self.input.advance();
+            if c == variant {
+                return Ok(contents);
+            }
+            if c == '\\' {
+                match self.input.peek() {
+                    Some(escaped @ ('\\' | '"' | '\'')) => contents.push(escaped),
+                    Some('n') => contents.push('\n'),
+                    Some('t') => contents.push('\t'),
+                    Some('0') => contents.push('\0'),
+                    Some('\n') | None => continue,
+                    // Unknown escapes are kept as written
+                    Some(other) => {
+                        contents.push('\\');
+                        contents.push(other);
+                    }
+                }
+                self.input.advance();
+            } else {
+                contents.push(c);
+            }
         }
 
-        let string = String::from_utf8(self.input[self.start..self.input.pos()].to_vec()).unwrap();
-
-        string
+        Err(TokenizerError {
+            kind: TokenizerErrorKind::UnterminatedString,
+            offset: self.start,
+        })
     }
 
     fn make_token(&self, token_type: TokenType) -> Token {
@@ -286,3 +371,119 @@ impl<'a> Tokenizer<'a> {
         String::from_utf8(self.input[self.start..self.input.pos()].to_vec()).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenize(source: &str) -> Result<Vec<Token>> {
+        let instructions = Instructions::load();
+        Tokenizer::new(source, &instructions).parse()
+    }
+
+    fn lexemes(source: &str) -> Vec<(TokenType, String)> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .filter(|token| token.token_type != TokenType::EOL)
+            .map(|token| (token.token_type, token.lexeme))
+            .collect()
+    }
+
+    #[test]
+    fn tokenizes_past_multibyte_comments() {
+        let source = "; café\nlda #1\n";
+        assert_eq!(
+            lexemes(source),
+            [
+                (TokenType::Instruction, "lda".to_string()),
+                (TokenType::Hash, "#".to_string()),
+                (TokenType::Number, "1".to_string()),
+            ]
+        );
+
+        let tokens = tokenize(source).unwrap();
+        let lda = tokens.iter().find(|token| token.lexeme == "lda").unwrap();
+        assert_eq!(&source[lda.span.start..lda.span.end], "lda");
+    }
+
+    #[test]
+    fn tokenizes_c_style_radix_prefixes() {
+        assert_eq!(
+            lexemes("lda #0x1F\n.byte 0b1010_0000\n"),
+            [
+                (TokenType::Instruction, "lda".to_string()),
+                (TokenType::Hash, "#".to_string()),
+                (TokenType::Number, "0x1F".to_string()),
+                (TokenType::Macro, ".byte".to_string()),
+                (TokenType::Number, "0b1010_0000".to_string()),
+            ]
+        );
+        assert_eq!(crate::number::parse_number("0x1F"), Some(0x1f));
+        assert_eq!(crate::number::parse_number("0b1010_0000"), Some(0xa0));
+    }
+
+    #[test]
+    fn accepts_digit_separators_between_digits() {
+        for (source, value) in [("%1010_0000", 0xa0), ("$12_34", 0x1234), ("1_000", 1000)] {
+            let tokens = lexemes(source);
+            assert_eq!(tokens, [(TokenType::Number, source.to_string())]);
+            assert_eq!(crate::number::parse_number(source), Some(value), "{source}");
+        }
+    }
+
+    #[test]
+    fn rejects_digit_separators_at_the_edges() {
+        for source in ["$_12", "$12_", "%1010_", "1000_", "0x1F_"] {
+            let error = tokenize(source).expect_err(source);
+            assert!(
+                matches!(error.kind, TokenizerErrorKind::UnexpectedToken),
+                "{source}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_escaped_quotes_inside_strings() {
+        assert_eq!(
+            lexemes(".byte \"say \\\"hi\\\"\", 0\n"),
+            [
+                (TokenType::Macro, ".byte".to_string()),
+                (TokenType::String, r#""say \"hi\"""#.to_string()),
+                (TokenType::Comma, ",".to_string()),
+                (TokenType::Number, "0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_strings() {
+        let error = tokenize(".byte \"abc\nnop\n").unwrap_err();
+        assert!(matches!(error.kind, TokenizerErrorKind::UnterminatedString));
+        assert_eq!(error.offset, 6);
+    }
+
+    #[test]
+    fn percent_is_modulo_unless_a_binary_digit_follows() {
+        assert_eq!(
+            lexemes("a%b\n"),
+            [
+                (TokenType::Identifier, "a".to_string()),
+                (TokenType::Mod, "%".to_string()),
+                (TokenType::Identifier, "b".to_string()),
+            ]
+        );
+        assert_eq!(
+            lexemes("%1010\n"),
+            [(TokenType::Number, "%1010".to_string())]
+        );
+        assert_eq!(
+            lexemes("lda #%11110000\n"),
+            [
+                (TokenType::Instruction, "lda".to_string()),
+                (TokenType::Hash, "#".to_string()),
+                (TokenType::Number, "%11110000".to_string()),
+            ]
+        );
+    }
+}